@@ -0,0 +1,694 @@
+use std::rc::Rc;
+
+use geometry::Point;
+use geometry::bounds::{Bounds, Bounds3f};
+use geometry::interaction::SurfaceInteraction;
+use geometry::point::Point3f;
+use geometry::ray::Ray_;
+use geometry::vector::Vector3f;
+use primitives::Primitive;
+
+use super::hlbvh::hlbvh_build;
+use super::sbvh::sbvh_build;
+use super::stats::{NoStats, TraversalStats};
+
+pub const N_BUCKETS: usize = 12;
+
+// Named as in the literature
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum SplitMethod {
+    SAH,
+    HLBVH,
+    /// SAH with spatial splits where object splits overlap by more than the
+    /// given fraction of the root surface area.
+    SBVH(f32),
+    Middle,
+    EqualCounts,
+}
+
+#[derive(Debug, Clone)]
+pub struct BVHPrimitiveInfo {
+    pub primitive_number: usize,
+    pub bounds: Bounds3f,
+    pub centroid: Point3f,
+}
+
+impl BVHPrimitiveInfo {
+    pub fn new(primitive_number: usize, bounds: Bounds3f) -> BVHPrimitiveInfo {
+        BVHPrimitiveInfo {
+            primitive_number,
+            bounds,
+            centroid: (bounds[0] + bounds[1]) * 0.5,
+        }
+    }
+}
+
+pub struct BVHBuildNode {
+    pub bounds: Bounds3f,
+    pub children: [Option<Box<BVHBuildNode>>; 2],
+    pub split_axis: u8,
+    pub first_prim_offset: usize,
+    pub n_primitives: usize,
+}
+
+impl BVHBuildNode {
+    pub fn leaf(first: usize, n: usize, bounds: Bounds3f) -> BVHBuildNode {
+        BVHBuildNode {
+            bounds,
+            children: [None, None],
+            split_axis: 0,
+            first_prim_offset: first,
+            n_primitives: n,
+        }
+    }
+
+    pub fn interior(axis: u8, c0: Box<BVHBuildNode>, c1: Box<BVHBuildNode>) -> BVHBuildNode {
+        BVHBuildNode {
+            bounds: Bounds::bounds_union(&c0.bounds, c1.bounds),
+            children: [Some(c0), Some(c1)],
+            split_axis: axis,
+            first_prim_offset: 0,
+            n_primitives: 0,
+        }
+    }
+}
+
+/// Builds leaves of at most `max_prims` over the primitives `start..end` by
+/// halving the range, for primitives no split plane separates, such as many
+/// instances at one spot. `leaf` makes the leaf of a subrange. The returned
+/// node is not counted in `total_nodes`, the nodes below it are.
+pub fn equal_count_leaves<F: FnMut(usize, usize) -> Box<BVHBuildNode>>(
+    start: usize,
+    end: usize,
+    max_prims: usize,
+    axis: u8,
+    total_nodes: &mut usize,
+    leaf: &mut F,
+) -> Box<BVHBuildNode> {
+    if end - start <= max_prims {
+        return leaf(start, end);
+    }
+    let mid = start + (end - start) / 2;
+    *total_nodes += 2;
+    let c0 = equal_count_leaves(start, mid, max_prims, axis, total_nodes, leaf);
+    let c1 = equal_count_leaves(mid, end, max_prims, axis, total_nodes, leaf);
+    Box::new(BVHBuildNode::interior(axis, c0, c1))
+}
+
+/// Node of the flattened tree. The first child of an interior node directly
+/// follows it, `offset` holds the second child for interior nodes and the
+/// first primitive for leaves.
+#[derive(Debug, Clone)]
+pub struct LinearBVHNode {
+    pub bounds: Bounds3f,
+    pub offset: u32,
+    pub n_primitives: u16,
+    pub axis: u8,
+}
+
+/// Bounding volume hierarchy over `primitives`. Traversals are reported to
+/// `S`, which by default discards them.
+pub struct BVHAccel<S = NoStats> {
+    max_prims_in_node: usize,
+    split_method: SplitMethod,
+    primitives: Vec<Rc<dyn Primitive>>,
+    /// Input index of each entry of `primitives`.
+    primitive_order: Vec<usize>,
+    /// Number of primitives given to the last build, which spatial splits
+    /// can make differ from `primitives.len()`.
+    n_input_primitives: usize,
+    nodes: Vec<LinearBVHNode>,
+    build_cost: f32,
+    stats: S,
+}
+
+/// Moves all elements matching `pred` to the front and returns their count.
+pub fn partition<T, F: FnMut(&T) -> bool>(v: &mut [T], mut pred: F) -> usize {
+    let mut first = 0;
+    for i in 0..v.len() {
+        if pred(&v[i]) {
+            v.swap(first, i);
+            first += 1;
+        }
+    }
+    first
+}
+
+pub fn union_bounds<'a, I: Iterator<Item = &'a Bounds3f>>(mut iter: I) -> Bounds3f {
+    let first = *iter.next().expect("union of empty bounds");
+    iter.fold(first, |b, other| Bounds::bounds_union(&b, *other))
+}
+
+pub fn centroid_bounds(info: &[BVHPrimitiveInfo]) -> Bounds3f {
+    let mut b = Bounds3f::from(&info[0].centroid);
+    for pi in info.iter().skip(1) {
+        b = Bounds::point_union(&b, &pi.centroid);
+    }
+    b
+}
+
+/// Index of the SAH bucket `centroid` falls into along `dim`.
+pub fn bucket_index(centroid_bounds: &Bounds3f, centroid: &Point3f, dim: u8, n_buckets: usize) -> usize {
+    let b = (n_buckets as f32 * centroid_bounds.offset(centroid)[dim]) as usize;
+    b.min(n_buckets - 1)
+}
+
+/// Evaluates the binned SAH along `dim` and returns the cheapest bucket to
+/// split after together with its relative cost.
+pub fn find_sah_split(info: &[BVHPrimitiveInfo], bounds: &Bounds3f, centroid_bounds: &Bounds3f, dim: u8) -> (usize, f32) {
+    let mut counts = [0usize; N_BUCKETS];
+    let mut bucket_bounds: [Option<Bounds3f>; N_BUCKETS] = [None; N_BUCKETS];
+    for pi in info {
+        let b = bucket_index(centroid_bounds, &pi.centroid, dim, N_BUCKETS);
+        counts[b] += 1;
+        bucket_bounds[b] = Some(match bucket_bounds[b] {
+            Some(bb) => Bounds::bounds_union(&bb, pi.bounds),
+            None => pi.bounds,
+        });
+    }
+
+    // Sweep from both sides to get the cost of splitting after each bucket
+    let mut cost = [0f32; N_BUCKETS - 1];
+    let mut count_below = 0;
+    let mut bounds_below: Option<Bounds3f> = None;
+    let mut area_below = [0f32; N_BUCKETS - 1];
+    let mut counts_below = [0usize; N_BUCKETS - 1];
+    for i in 0..N_BUCKETS - 1 {
+        count_below += counts[i];
+        if let Some(bb) = bucket_bounds[i] {
+            bounds_below = Some(match bounds_below {
+                Some(b) => Bounds::bounds_union(&b, bb),
+                None => bb,
+            });
+        }
+        counts_below[i] = count_below;
+        area_below[i] = bounds_below.map_or(0., |b| b.surface_area());
+    }
+    let mut count_above = 0;
+    let mut bounds_above: Option<Bounds3f> = None;
+    for i in (1..N_BUCKETS).rev() {
+        count_above += counts[i];
+        if let Some(bb) = bucket_bounds[i] {
+            bounds_above = Some(match bounds_above {
+                Some(b) => Bounds::bounds_union(&b, bb),
+                None => bb,
+            });
+        }
+        let area_above = bounds_above.map_or(0., |b| b.surface_area());
+        cost[i - 1] = 0.125
+            + (counts_below[i - 1] as f32 * area_below[i - 1] + count_above as f32 * area_above)
+                / bounds.surface_area();
+    }
+
+    let mut min_bucket = 0;
+    for i in 1..N_BUCKETS - 1 {
+        if cost[i] < cost[min_bucket] {
+            min_bucket = i;
+        }
+    }
+    (min_bucket, cost[min_bucket])
+}
+
+impl BVHAccel {
+    pub fn new(primitives: Vec<Rc<dyn Primitive>>, max_prims_in_node: usize, split_method: SplitMethod) -> BVHAccel {
+        BVHAccel::with_stats(primitives, max_prims_in_node, split_method, NoStats)
+    }
+
+    /// Reassembles a tree from previously built nodes and the input index
+    /// of every leaf primitive slot.
+    pub fn from_parts(
+        primitives: &[Rc<dyn Primitive>],
+        max_prims_in_node: usize,
+        split_method: SplitMethod,
+        nodes: Vec<LinearBVHNode>,
+        primitive_order: Vec<usize>,
+    ) -> BVHAccel {
+        let mut accel = BVHAccel {
+            max_prims_in_node: max_prims_in_node.clamp(1, 255),
+            split_method,
+            primitives: primitive_order.iter().map(|&i| primitives[i].clone()).collect(),
+            primitive_order,
+            n_input_primitives: primitives.len(),
+            nodes,
+            build_cost: 0.,
+            stats: NoStats,
+        };
+        accel.build_cost = accel.sah_cost();
+        accel
+    }
+}
+
+impl<S: TraversalStats> BVHAccel<S> {
+    pub fn with_stats(
+        primitives: Vec<Rc<dyn Primitive>>,
+        max_prims_in_node: usize,
+        split_method: SplitMethod,
+        stats: S,
+    ) -> BVHAccel<S> {
+        let mut accel = BVHAccel {
+            max_prims_in_node: max_prims_in_node.clamp(1, 255),
+            split_method,
+            primitives: Vec::new(),
+            primitive_order: Vec::new(),
+            n_input_primitives: 0,
+            nodes: Vec::new(),
+            build_cost: 0.,
+            stats,
+        };
+        accel.build(primitives);
+        accel
+    }
+
+    fn build(&mut self, primitives: Vec<Rc<dyn Primitive>>) {
+        self.nodes.clear();
+        self.n_input_primitives = primitives.len();
+        if primitives.is_empty() {
+            self.primitives = primitives;
+            self.primitive_order.clear();
+            return;
+        }
+
+        let mut primitive_info: Vec<BVHPrimitiveInfo> = primitives
+            .iter()
+            .enumerate()
+            .map(|(i, p)| BVHPrimitiveInfo::new(i, p.world_bound()))
+            .collect();
+        let mut total_nodes = 0;
+        let mut order = Vec::with_capacity(primitives.len());
+        let root = match self.split_method {
+            SplitMethod::HLBVH => {
+                let (root, n_nodes, morton_order) = hlbvh_build(&primitive_info, self.max_prims_in_node);
+                total_nodes = n_nodes;
+                order = morton_order;
+                root
+            }
+            SplitMethod::SBVH(alpha) => {
+                let (root, n_nodes, reference_order) = sbvh_build(&primitive_info, self.max_prims_in_node, alpha);
+                total_nodes = n_nodes;
+                order = reference_order;
+                root
+            }
+            _ => self.recursive_build(&mut primitive_info, &mut total_nodes, &mut order),
+        };
+        self.primitives = order.iter().map(|&i| primitives[i].clone()).collect();
+        self.primitive_order = order;
+        self.nodes = flatten_bvh_tree(&root, total_nodes);
+        self.build_cost = self.sah_cost();
+    }
+
+    fn recursive_build(
+        &self,
+        info: &mut [BVHPrimitiveInfo],
+        total_nodes: &mut usize,
+        order: &mut Vec<usize>,
+    ) -> Box<BVHBuildNode> {
+        *total_nodes += 1;
+        let bounds = union_bounds(info.iter().map(|pi| &pi.bounds));
+        let n_primitives = info.len();
+
+        let make_leaf = |info: &[BVHPrimitiveInfo], order: &mut Vec<usize>| {
+            let first = order.len();
+            order.extend(info.iter().map(|pi| pi.primitive_number));
+            Box::new(BVHBuildNode::leaf(first, info.len(), bounds))
+        };
+        if n_primitives == 1 {
+            return make_leaf(info, order);
+        }
+
+        let cb = centroid_bounds(info);
+        let dim = cb.maximum_extent();
+        if cb[1][dim] == cb[0][dim] {
+            let info = &*info;
+            return equal_count_leaves(0, n_primitives, self.max_prims_in_node, dim, total_nodes, &mut |start, end| {
+                let bounds = union_bounds(info[start..end].iter().map(|pi| &pi.bounds));
+                let first = order.len();
+                order.extend(info[start..end].iter().map(|pi| pi.primitive_number));
+                Box::new(BVHBuildNode::leaf(first, end - start, bounds))
+            });
+        }
+
+        let mut mid = 0;
+        if self.split_method == SplitMethod::Middle {
+            let pmid = (cb[0][dim] + cb[1][dim]) / 2.;
+            mid = partition(info, |pi| pi.centroid[dim] < pmid);
+        }
+        if self.split_method == SplitMethod::SAH && n_primitives > 2 {
+            let (min_bucket, min_cost) = find_sah_split(info, &bounds, &cb, dim);
+            let leaf_cost = n_primitives as f32;
+            if n_primitives > self.max_prims_in_node || min_cost < leaf_cost {
+                mid = partition(info, |pi| bucket_index(&cb, &pi.centroid, dim, N_BUCKETS) <= min_bucket);
+            } else {
+                return make_leaf(info, order);
+            }
+        }
+        if mid == 0 || mid == n_primitives {
+            // Equal counts, also the fallback when the other methods fail to split
+            mid = n_primitives / 2;
+            info.select_nth_unstable_by(mid, |a, b| {
+                a.centroid[dim].partial_cmp(&b.centroid[dim]).unwrap()
+            });
+        }
+
+        let (left, right) = info.split_at_mut(mid);
+        let c0 = self.recursive_build(left, total_nodes, order);
+        let c1 = self.recursive_build(right, total_nodes, order);
+        Box::new(BVHBuildNode::interior(dim, c0, c1))
+    }
+
+    pub fn nodes(&self) -> &[LinearBVHNode] {
+        &self.nodes
+    }
+
+    pub fn primitive_order(&self) -> &[usize] {
+        &self.primitive_order
+    }
+
+    pub fn primitives(&self) -> &[Rc<dyn Primitive>] {
+        &self.primitives
+    }
+
+    pub fn stats(&self) -> &S {
+        &self.stats
+    }
+
+    /// Expected cost of tracing a ray through the tree, measured with the
+    /// same relative costs the SAH builder uses.
+    pub fn sah_cost(&self) -> f32 {
+        let root_area = match self.nodes.first() {
+            Some(root) => root.bounds.surface_area(),
+            None => return 0.,
+        };
+        if root_area == 0. {
+            return self.primitives.len() as f32;
+        }
+        self.nodes
+            .iter()
+            .map(|node| {
+                let cost = if node.n_primitives > 0 {
+                    node.n_primitives as f32
+                } else {
+                    0.125
+                };
+                cost * node.bounds.surface_area() / root_area
+            })
+            .sum()
+    }
+
+    /// Recomputes all node bounds bottom up for primitives that moved without
+    /// changing the tree topology. `primitives` replaces the primitives given
+    /// at construction and has to be in the same order. Primitives referenced
+    /// by several leaves of a spatial split tree get their full bounds in
+    /// each of them.
+    pub fn refit(&mut self, primitives: Vec<Rc<dyn Primitive>>) {
+        assert_eq!(primitives.len(), self.n_input_primitives);
+        self.primitives = self.primitive_order
+            .iter()
+            .map(|&i| primitives[i].clone())
+            .collect();
+
+        // Children are stored after their parents
+        for i in (0..self.nodes.len()).rev() {
+            let (offset, n_primitives) = (self.nodes[i].offset as usize, self.nodes[i].n_primitives as usize);
+            self.nodes[i].bounds = if n_primitives > 0 {
+                let mut b = self.primitives[offset].world_bound();
+                for prim in &self.primitives[offset + 1..offset + n_primitives] {
+                    b = Bounds::bounds_union(&b, prim.world_bound());
+                }
+                b
+            } else {
+                Bounds::bounds_union(&self.nodes[i + 1].bounds, self.nodes[offset].bounds)
+            };
+        }
+    }
+
+    /// Refits the tree and rebuilds it instead once its SAH cost exceeds
+    /// `max_cost_ratio` times the cost right after the last build. Returns
+    /// whether the tree was rebuilt.
+    pub fn refit_or_rebuild(&mut self, primitives: Vec<Rc<dyn Primitive>>, max_cost_ratio: f32) -> bool {
+        assert_eq!(primitives.len(), self.n_input_primitives);
+        self.refit(primitives.clone());
+        if self.sah_cost() <= self.build_cost * max_cost_ratio {
+            return false;
+        }
+        self.build(primitives);
+        true
+    }
+}
+
+/// Lays the build tree out in depth first order.
+pub fn flatten_bvh_tree(root: &BVHBuildNode, total_nodes: usize) -> Vec<LinearBVHNode> {
+    let mut nodes = Vec::with_capacity(total_nodes);
+    flatten_node(root, &mut nodes);
+    nodes
+}
+
+fn flatten_node(node: &BVHBuildNode, nodes: &mut Vec<LinearBVHNode>) -> usize {
+    assert!(node.n_primitives <= u16::MAX as usize, "leaf of {} primitives", node.n_primitives);
+    let offset = nodes.len();
+    nodes.push(LinearBVHNode {
+        bounds: node.bounds,
+        offset: node.first_prim_offset as u32,
+        n_primitives: node.n_primitives as u16,
+        axis: node.split_axis,
+    });
+    if node.n_primitives == 0 {
+        if let [Some(ref c0), Some(ref c1)] = node.children {
+            flatten_node(c0, nodes);
+            let second = flatten_node(c1, nodes);
+            nodes[offset].offset = second as u32;
+        }
+    }
+    offset
+}
+
+pub fn ray_inv_dir(ray: &Ray_) -> (Vector3f, [u8; 3]) {
+    let inv_dir = Vector3f::new(1. / ray.d.x, 1. / ray.d.y, 1. / ray.d.z);
+    let dir_is_neg = [
+        (inv_dir.x < 0.) as u8,
+        (inv_dir.y < 0.) as u8,
+        (inv_dir.z < 0.) as u8,
+    ];
+    (inv_dir, dir_is_neg)
+}
+
+impl<S: TraversalStats> Primitive for BVHAccel<S> {
+    fn world_bound(&self) -> Bounds3f {
+        match self.nodes.first() {
+            Some(node) => node.bounds,
+            None => Bounds3f::from(&Point3f::zero()),
+        }
+    }
+
+    fn intersect(&self, ray: &Ray_) -> Option<SurfaceInteraction> {
+        if self.nodes.is_empty() {
+            return None;
+        }
+        let (inv_dir, dir_is_neg) = ray_inv_dir(ray);
+        let mut isect = None;
+        let mut nodes_to_visit = [0usize; 64];
+        let mut to_visit_offset = 0;
+        let mut current = 0;
+        let mut visited = 0;
+        loop {
+            let node = &self.nodes[current];
+            visited += 1;
+            if node.bounds.intersect_p_inv(ray, &inv_dir, &dir_is_neg) {
+                if node.n_primitives > 0 {
+                    let first = node.offset as usize;
+                    for prim in &self.primitives[first..first + node.n_primitives as usize] {
+                        if let Some(si) = prim.intersect(ray) {
+                            isect = Some(si);
+                        }
+                    }
+                    if to_visit_offset == 0 {
+                        break;
+                    }
+                    to_visit_offset -= 1;
+                    current = nodes_to_visit[to_visit_offset];
+                } else {
+                    // Visit the child on the near side of the split first
+                    if dir_is_neg[node.axis as usize] != 0 {
+                        nodes_to_visit[to_visit_offset] = current + 1;
+                        current = node.offset as usize;
+                    } else {
+                        nodes_to_visit[to_visit_offset] = node.offset as usize;
+                        current += 1;
+                    }
+                    to_visit_offset += 1;
+                }
+            } else {
+                if to_visit_offset == 0 {
+                    break;
+                }
+                to_visit_offset -= 1;
+                current = nodes_to_visit[to_visit_offset];
+            }
+        }
+        self.stats.record_traversal(visited);
+        isect
+    }
+
+    fn intersect_p(&self, ray: &Ray_) -> bool {
+        if self.nodes.is_empty() {
+            return false;
+        }
+        let (inv_dir, dir_is_neg) = ray_inv_dir(ray);
+        let mut nodes_to_visit = [0usize; 64];
+        let mut to_visit_offset = 0;
+        let mut current = 0;
+        let mut visited = 0;
+        loop {
+            let node = &self.nodes[current];
+            visited += 1;
+            if node.bounds.intersect_p_inv(ray, &inv_dir, &dir_is_neg) {
+                if node.n_primitives > 0 {
+                    let first = node.offset as usize;
+                    for prim in &self.primitives[first..first + node.n_primitives as usize] {
+                        if prim.intersect_p(ray) {
+                            self.stats.record_traversal(visited);
+                            return true;
+                        }
+                    }
+                    if to_visit_offset == 0 {
+                        break;
+                    }
+                    to_visit_offset -= 1;
+                    current = nodes_to_visit[to_visit_offset];
+                } else {
+                    if dir_is_neg[node.axis as usize] != 0 {
+                        nodes_to_visit[to_visit_offset] = current + 1;
+                        current = node.offset as usize;
+                    } else {
+                        nodes_to_visit[to_visit_offset] = node.offset as usize;
+                        current += 1;
+                    }
+                    to_visit_offset += 1;
+                }
+            } else {
+                if to_visit_offset == 0 {
+                    break;
+                }
+                to_visit_offset -= 1;
+                current = nodes_to_visit[to_visit_offset];
+            }
+        }
+        self.stats.record_traversal(visited);
+        false
+    }
+}
+
+#[test]
+fn bvh_matches_brute_force() {
+    use super::test_util::{brute_force_hit, closest_hit, random_rays, random_triangles};
+
+    let prims = random_triangles(200, 5);
+
+    for &method in &[
+        SplitMethod::SAH,
+        SplitMethod::HLBVH,
+        SplitMethod::Middle,
+        SplitMethod::EqualCounts,
+    ] {
+        let bvh = BVHAccel::new(prims.clone(), 4, method);
+        for (o, d) in random_rays(300, 6) {
+            let brute = brute_force_hit(&prims, &o, &d);
+            assert_eq!(closest_hit(&bvh, &o, &d), brute);
+            let shadow_ray = Ray_::new(&o, &d, f32::INFINITY, 0., None);
+            assert_eq!(bvh.intersect_p(&shadow_ray), brute.is_some());
+        }
+    }
+}
+
+#[test]
+fn coincident_primitives_are_split_by_count() {
+    use super::test_util::{brute_force_hit, closest_hit, lcg, triangle_primitives};
+
+    // Triangles whose bounds are all centered on the origin, so no split
+    // plane separates their centroids
+    let mut rand = lcg(17);
+    let mut p = Vec::new();
+    for i in 0..3000 {
+        let s = 1. + i as f32 * 1e-3;
+        let z = (rand() * 2. - 1.) * s;
+        p.push(Point3f::new(-s, -s, -s));
+        p.push(Point3f::new(s, s, s));
+        p.push(Point3f::new(s, -s, z));
+    }
+    let prims = triangle_primitives(p);
+
+    for &method in &[
+        SplitMethod::SAH,
+        SplitMethod::HLBVH,
+        SplitMethod::SBVH(1e-5),
+        SplitMethod::Middle,
+        SplitMethod::EqualCounts,
+    ] {
+        let bvh = BVHAccel::new(prims.clone(), 4, method);
+        let leaves = bvh.nodes().iter().filter(|n| n.n_primitives > 0);
+        assert!(leaves.clone().all(|n| n.n_primitives <= 4), "{:?}", method);
+        assert!(leaves.map(|n| n.n_primitives as usize).sum::<usize>() >= 3000);
+        for _ in 0..50 {
+            let o = Point3f::new(rand() * 20. - 10., rand() * 20. - 10., 20.);
+            let target = Point3f::new(rand() * 2. - 1., rand() * 2. - 1., rand() * 2. - 1.);
+            let d = target - o;
+            // The triangles all meet along the diagonal, where the nearest
+            // one is a tie up to rounding
+            match (closest_hit(&bvh, &o, &d), brute_force_hit(&prims, &o, &d)) {
+                (Some(t), Some(t_brute)) => assert!((t - t_brute).abs() < 1e-5, "{:?}", method),
+                (t, t_brute) => assert_eq!(t, t_brute, "{:?}", method),
+            }
+        }
+    }
+}
+
+#[test]
+fn refit_follows_deforming_mesh() {
+    use super::test_util::{brute_force_hit, closest_hit, lcg, random_rays, random_triangle_points,
+                           triangle_primitives as to_prims};
+
+    let p = random_triangle_points(150, 2., &mut lcg(23));
+    let check = |bvh: &BVHAccel, prims: &[Rc<dyn Primitive>], seed: u32| {
+        for (o, d) in random_rays(200, seed) {
+            assert_eq!(closest_hit(bvh, &o, &d), brute_force_hit(prims, &o, &d));
+        }
+    };
+
+    for &method in [SplitMethod::SAH, SplitMethod::SBVH(0.)].iter() {
+        let mut bvh = BVHAccel::new(to_prims(p.clone()), 2, method);
+        if let SplitMethod::SBVH(_) = method {
+            // Refit has to give duplicated references their full bounds
+            assert!(bvh.primitives().len() > p.len() / 3);
+        }
+        let n_nodes = bvh.nodes().len();
+
+        // Small wobble keeps the topology and barely changes the cost
+        let wobbled: Vec<Point3f> = p
+            .iter()
+            .map(|v| *v + Vector3f::new(0.3, -0.2, 0.1) * (v.x * 0.7).sin())
+            .collect();
+        let prims = to_prims(wobbled);
+        assert!(!bvh.refit_or_rebuild(prims.clone(), 1.5));
+        assert_eq!(bvh.nodes().len(), n_nodes);
+        check(&bvh, &prims, 24);
+
+        // Mirroring every other triangle scatters the leaves and forces a rebuild
+        let scattered: Vec<Point3f> = p
+            .iter()
+            .enumerate()
+            .map(|(i, v)| if (i / 3) % 2 == 0 { Point3f::new(10. - v.x, 10. - v.y, v.z) } else { *v })
+            .collect();
+        let prims = to_prims(scattered);
+        assert!(bvh.refit_or_rebuild(prims.clone(), 1.5));
+        check(&bvh, &prims, 25);
+    }
+}
+
+#[test]
+#[should_panic]
+fn refit_rejects_different_primitive_count() {
+    use super::test_util::random_triangles;
+
+    let mut bvh = BVHAccel::new(random_triangles(20, 27), 2, SplitMethod::SBVH(1e-5));
+    bvh.refit(random_triangles(21, 27));
+}
@@ -0,0 +1,307 @@
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::rc::Rc;
+
+use geometry::bounds::Bounds3f;
+use geometry::point::Point3f;
+use primitives::Primitive;
+
+use super::bvh::{BVHAccel, LinearBVHNode, SplitMethod};
+use super::stats::TraversalStats;
+
+const MAGIC: &[u8; 4] = b"PBVH";
+/// Bump whenever the node layout or the file layout changes.
+const VERSION: u32 = 1;
+/// Interior levels the traversal stack of `BVHAccel` has room for.
+const MAX_TRAVERSAL_DEPTH: usize = 64;
+
+/// 64 bit FNV-1a, stable across platforms and compiler versions unlike the
+/// standard library hasher.
+struct Fnv1a(u64);
+
+impl Fnv1a {
+    fn new() -> Fnv1a {
+        Fnv1a(0xcbf2_9ce4_8422_2325)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.0 ^= b as u64;
+            self.0 = self.0.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+    }
+
+    fn write_u32(&mut self, v: u32) {
+        self.write(&v.to_le_bytes());
+    }
+}
+
+/// Hash identifying a build: the bounds of every primitive in input order
+/// together with the build parameters.
+pub fn geometry_hash(primitives: &[Rc<dyn Primitive>], max_prims_in_node: usize, split_method: SplitMethod) -> u64 {
+    let mut hasher = Fnv1a::new();
+    hasher.write_u32(primitives.len() as u32);
+    hasher.write_u32(max_prims_in_node as u32);
+    let (tag, param) = match split_method {
+        SplitMethod::SAH => (0, 0.),
+        SplitMethod::HLBVH => (1, 0.),
+        SplitMethod::SBVH(alpha) => (2, alpha),
+        SplitMethod::Middle => (3, 0.),
+        SplitMethod::EqualCounts => (4, 0.),
+    };
+    hasher.write_u32(tag);
+    hasher.write_u32(f32::to_bits(param));
+    for prim in primitives {
+        let b = prim.world_bound();
+        for i in 0..2u8 {
+            for axis in 0..3u8 {
+                hasher.write_u32(b[i][axis].to_bits());
+            }
+        }
+    }
+    hasher.0
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+fn read_u32<R: Read>(r: &mut R) -> io::Result<u32> {
+    let mut buf = [0u8; 4];
+    r.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_u64<R: Read>(r: &mut R) -> io::Result<u64> {
+    let mut buf = [0u8; 8];
+    r.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+fn read_f32<R: Read>(r: &mut R) -> io::Result<f32> {
+    read_u32(r).map(f32::from_bits)
+}
+
+/// Writes the flattened nodes and primitive ordering of `bvh`. The layout is
+/// little endian: magic, version, geometry hash, slot and node counts, the
+/// input index of every primitive slot and 32 bytes per node.
+pub fn write_cache<S: TraversalStats>(bvh: &BVHAccel<S>, hash: u64, path: &Path) -> io::Result<()> {
+    let mut w = BufWriter::new(File::create(path)?);
+    w.write_all(MAGIC)?;
+    w.write_all(&VERSION.to_le_bytes())?;
+    w.write_all(&hash.to_le_bytes())?;
+    w.write_all(&(bvh.primitive_order().len() as u64).to_le_bytes())?;
+    w.write_all(&(bvh.nodes().len() as u64).to_le_bytes())?;
+    for &i in bvh.primitive_order() {
+        w.write_all(&(i as u32).to_le_bytes())?;
+    }
+    for node in bvh.nodes() {
+        for i in 0..2u8 {
+            for axis in 0..3u8 {
+                w.write_all(&node.bounds[i][axis].to_le_bytes())?;
+            }
+        }
+        w.write_all(&node.offset.to_le_bytes())?;
+        w.write_all(&node.n_primitives.to_le_bytes())?;
+        w.write_all(&[node.axis, 0])?;
+    }
+    w.flush()
+}
+
+/// Loads a tree written by `write_cache` for exactly these primitives and
+/// build parameters, failing with `InvalidData` if the file is stale or
+/// corrupt.
+pub fn read_cache(
+    primitives: &[Rc<dyn Primitive>],
+    max_prims_in_node: usize,
+    split_method: SplitMethod,
+    path: &Path,
+) -> io::Result<BVHAccel> {
+    let mut r = BufReader::new(File::open(path)?);
+    let mut magic = [0u8; 4];
+    r.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(invalid_data("not a BVH cache file"));
+    }
+    if read_u32(&mut r)? != VERSION {
+        return Err(invalid_data("unsupported BVH cache version"));
+    }
+    if read_u64(&mut r)? != geometry_hash(primitives, max_prims_in_node, split_method) {
+        return Err(invalid_data("BVH cache does not match the geometry"));
+    }
+    let n_slots = read_u64(&mut r)? as usize;
+    let n_nodes = read_u64(&mut r)? as usize;
+    if n_nodes == 0 && !primitives.is_empty() {
+        return Err(invalid_data("empty BVH cache"));
+    }
+
+    let mut order = Vec::with_capacity(n_slots.min(1 << 24));
+    for _ in 0..n_slots {
+        let i = read_u32(&mut r)? as usize;
+        if i >= primitives.len() {
+            return Err(invalid_data("primitive index out of range"));
+        }
+        order.push(i);
+    }
+    let mut nodes = Vec::with_capacity(n_nodes.min(1 << 24));
+    for _ in 0..n_nodes {
+        let mut p = [0f32; 6];
+        for v in p.iter_mut() {
+            *v = read_f32(&mut r)?;
+        }
+        let offset = read_u32(&mut r)?;
+        let mut rest = [0u8; 4];
+        r.read_exact(&mut rest)?;
+        let n_primitives = u16::from_le_bytes([rest[0], rest[1]]);
+        let axis = rest[2];
+        let in_range = if n_primitives > 0 {
+            offset as usize + n_primitives as usize <= n_slots
+        } else {
+            axis < 3
+        };
+        if !in_range || p.iter().any(|v| v.is_nan()) {
+            return Err(invalid_data("corrupt BVH node"));
+        }
+        nodes.push(LinearBVHNode {
+            bounds: Bounds3f::from((Point3f::new(p[0], p[1], p[2]), Point3f::new(p[3], p[4], p[5]))),
+            offset,
+            n_primitives,
+            axis,
+        });
+    }
+    check_topology(&nodes)?;
+    Ok(BVHAccel::from_parts(primitives, max_prims_in_node, split_method, nodes, order))
+}
+
+/// Checks that the nodes form a single tree in depth first order that the
+/// fixed size traversal stack can walk: every interior node is followed by
+/// its first child and points past it to its second, and every node is
+/// reached exactly once.
+fn check_topology(nodes: &[LinearBVHNode]) -> io::Result<()> {
+    if nodes.is_empty() {
+        return Ok(());
+    }
+    let mut visited = vec![false; nodes.len()];
+    let mut stack = vec![(0, 0)];
+    while let Some((i, depth)) = stack.pop() {
+        if visited[i] {
+            return Err(invalid_data("BVH cache nodes do not form a tree"));
+        }
+        visited[i] = true;
+        let node = &nodes[i];
+        if node.n_primitives > 0 {
+            continue;
+        }
+        let second = node.offset as usize;
+        if i + 1 >= nodes.len() || second <= i + 1 || second >= nodes.len() {
+            return Err(invalid_data("corrupt BVH node"));
+        }
+        if depth == MAX_TRAVERSAL_DEPTH {
+            return Err(invalid_data("BVH cache tree is too deep"));
+        }
+        stack.push((second, depth + 1));
+        stack.push((i + 1, depth + 1));
+    }
+    if visited.iter().any(|&v| !v) {
+        return Err(invalid_data("BVH cache has unreachable nodes"));
+    }
+    Ok(())
+}
+
+/// Loads the tree from `path` when the cache matches the primitives and
+/// parameters, otherwise builds it and refreshes the cache. The cache is
+/// only an optimization, so failing to write it is not an error.
+pub fn load_or_build(
+    primitives: Vec<Rc<dyn Primitive>>,
+    max_prims_in_node: usize,
+    split_method: SplitMethod,
+    path: &Path,
+) -> BVHAccel {
+    if let Ok(bvh) = read_cache(&primitives, max_prims_in_node, split_method, path) {
+        return bvh;
+    }
+    let hash = geometry_hash(&primitives, max_prims_in_node, split_method);
+    let bvh = BVHAccel::new(primitives, max_prims_in_node, split_method);
+    let _ = write_cache(&bvh, hash, path);
+    bvh
+}
+
+#[test]
+fn cache_round_trip() {
+    use std::fs;
+    use geometry::vector::Vector3f;
+    use super::test_util::{closest_hit, triangle_primitives};
+
+    let to_prims = |offset: f32| -> Vec<Rc<dyn Primitive>> {
+        let mut p = Vec::new();
+        for i in 0..50 {
+            let c = Point3f::new((i % 5) as f32 * 2. + offset, (i / 5) as f32, (i * 7 % 11) as f32);
+            p.push(c);
+            p.push(c + Vector3f::new(1., 0., 0.3));
+            p.push(c + Vector3f::new(0., 1., -0.2));
+        }
+        triangle_primitives(p)
+    };
+    let path = ::std::env::temp_dir().join(format!("pbrt_bvh_cache_{}.bin", ::std::process::id()));
+    let _ = fs::remove_file(&path);
+
+    let prims = to_prims(0.);
+    let built = load_or_build(prims.clone(), 2, SplitMethod::SAH, &path);
+    let loaded = read_cache(&prims, 2, SplitMethod::SAH, &path).unwrap();
+    assert_eq!(loaded.nodes().len(), built.nodes().len());
+    assert_eq!(loaded.primitive_order(), built.primitive_order());
+    for i in 0..100 {
+        let o = Point3f::new((i % 10) as f32, (i / 10) as f32, -1.);
+        let d = Vector3f::new(0.1, 0.05, 1.);
+        assert_eq!(closest_hit(&built, &o, &d), closest_hit(&loaded, &o, &d));
+    }
+
+    // Stale caches are rejected and replaced
+    let moved = to_prims(0.5);
+    assert!(read_cache(&moved, 2, SplitMethod::SAH, &path).is_err());
+    assert!(read_cache(&prims, 4, SplitMethod::SAH, &path).is_err());
+    load_or_build(moved.clone(), 2, SplitMethod::SAH, &path);
+    assert!(read_cache(&moved, 2, SplitMethod::SAH, &path).is_ok());
+
+    fs::write(&path, b"PBVH garbage").unwrap();
+    assert!(read_cache(&moved, 2, SplitMethod::SAH, &path).is_err());
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn corrupt_node_offsets_are_rejected() {
+    use std::fs;
+    use super::test_util::random_triangles;
+
+    let prims = random_triangles(50, 3);
+    let bvh = BVHAccel::new(prims.clone(), 2, SplitMethod::SAH);
+    let hash = geometry_hash(&prims, 2, SplitMethod::SAH);
+    let path = ::std::env::temp_dir().join(format!("pbrt_bvh_corrupt_{}.bin", ::std::process::id()));
+    write_cache(&bvh, hash, &path).unwrap();
+    let bytes = fs::read(&path).unwrap();
+
+    let nodes_start = 32 + 4 * bvh.primitive_order().len();
+    let node_bytes = |i: usize| nodes_start + 32 * i;
+    let interior = bvh.nodes().iter().rposition(|n| n.n_primitives == 0).unwrap();
+    let last = bvh.nodes().len() - 1;
+    let mut corruptions: Vec<(usize, u32)> = Vec::new();
+    // Second child pointing back at the node, at its first child and at
+    // an earlier node
+    for &offset in &[interior, interior + 1, 0] {
+        corruptions.push((node_bytes(interior) + 24, offset as u32));
+    }
+    // Skipping a subtree leaves nodes unreachable
+    corruptions.push((node_bytes(0) + 24, last as u32));
+    // A last node turned interior has no children
+    corruptions.push((node_bytes(last) + 28, 0));
+    for &(at, value) in &corruptions {
+        let mut corrupt = bytes.clone();
+        corrupt[at..at + 4].copy_from_slice(&value.to_le_bytes());
+        fs::write(&path, &corrupt).unwrap();
+        assert!(read_cache(&prims, 2, SplitMethod::SAH, &path).is_err());
+    }
+    fs::write(&path, &bytes).unwrap();
+    assert!(read_cache(&prims, 2, SplitMethod::SAH, &path).is_ok());
+    fs::remove_file(&path).unwrap();
+}
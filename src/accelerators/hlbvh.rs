@@ -0,0 +1,250 @@
+use std::thread;
+
+use geometry::bounds::{Bounds, Bounds3f};
+
+use super::bvh::{bucket_index, centroid_bounds, equal_count_leaves, find_sah_split, partition, union_bounds,
+                 BVHBuildNode, BVHPrimitiveInfo, N_BUCKETS};
+
+const MORTON_BITS: u32 = 10;
+const MORTON_SCALE: f32 = (1 << MORTON_BITS) as f32;
+/// The top 12 bits of the Morton code select the treelet a primitive ends
+/// up in, the remaining 18 are resolved inside the treelet.
+const TREELET_MASK: u32 = 0b0011_1111_1111_1100_0000_0000_0000_0000;
+const FIRST_BIT_INDEX: i32 = 29 - 12;
+
+#[derive(Debug, Copy, Clone)]
+pub struct MortonPrimitive {
+    pub primitive_index: usize,
+    pub morton_code: u32,
+}
+
+/// Spreads the lower 10 bits of `x` so there are two zero bits between each.
+pub fn left_shift3(mut x: u32) -> u32 {
+    if x == 1 << 10 {
+        x -= 1;
+    }
+    x = (x | (x << 16)) & 0b0000_0011_0000_0000_0000_0000_1111_1111;
+    x = (x | (x << 8)) & 0b0000_0011_0000_0000_1111_0000_0000_1111;
+    x = (x | (x << 4)) & 0b0000_0011_0000_1100_0011_0000_1100_0011;
+    x = (x | (x << 2)) & 0b0000_1001_0010_0100_1001_0010_0100_1001;
+    x
+}
+
+/// Interleaves the bits of the three coordinates, each expected in `[0, 1024]`.
+pub fn encode_morton3(x: f32, y: f32, z: f32) -> u32 {
+    (left_shift3(z as u32) << 2) | (left_shift3(y as u32) << 1) | left_shift3(x as u32)
+}
+
+/// Least significant digit radix sort on the 30 bit Morton codes.
+pub fn radix_sort(v: &mut Vec<MortonPrimitive>) {
+    const BITS_PER_PASS: u32 = 6;
+    const N_RADIX_BUCKETS: usize = 1 << BITS_PER_PASS;
+    const BIT_MASK: u32 = (1 << BITS_PER_PASS) - 1;
+    let n_passes = 30u32.div_ceil(BITS_PER_PASS);
+
+    let mut temp = v.clone();
+    for pass in 0..n_passes {
+        let low_bit = pass * BITS_PER_PASS;
+        let (input, output) = if pass & 1 == 1 {
+            (&temp, &mut *v)
+        } else {
+            (&*v, &mut temp)
+        };
+
+        let mut bucket_count = [0usize; N_RADIX_BUCKETS];
+        for mp in input.iter() {
+            bucket_count[((mp.morton_code >> low_bit) & BIT_MASK) as usize] += 1;
+        }
+        let mut out_index = [0usize; N_RADIX_BUCKETS];
+        for i in 1..N_RADIX_BUCKETS {
+            out_index[i] = out_index[i - 1] + bucket_count[i - 1];
+        }
+        for mp in input.iter() {
+            let bucket = ((mp.morton_code >> low_bit) & BIT_MASK) as usize;
+            output[out_index[bucket]] = *mp;
+            out_index[bucket] += 1;
+        }
+    }
+    if n_passes & 1 == 1 {
+        ::std::mem::swap(v, &mut temp);
+    }
+}
+
+/// Builds a BVH by sorting the primitives along a Morton curve, emitting the
+/// treelets of each coarse grid cell in parallel and joining their roots with
+/// SAH splits. Leaves reference contiguous ranges of the Morton order, which
+/// is returned as the primitive ordering together with the node count.
+pub fn hlbvh_build(info: &[BVHPrimitiveInfo], max_prims_in_node: usize) -> (Box<BVHBuildNode>, usize, Vec<usize>) {
+    let bounds = centroid_bounds(info);
+    let mut morton_prims: Vec<MortonPrimitive> = info
+        .iter()
+        .map(|pi| {
+            let offset = bounds.offset(&pi.centroid) * MORTON_SCALE;
+            MortonPrimitive {
+                primitive_index: pi.primitive_number,
+                morton_code: encode_morton3(offset.x, offset.y, offset.z),
+            }
+        })
+        .collect();
+    radix_sort(&mut morton_prims);
+
+    // Find the ranges of primitives sharing a treelet
+    let mut treelets = Vec::new();
+    let mut start = 0;
+    for end in 1..morton_prims.len() + 1 {
+        if end == morton_prims.len()
+            || (morton_prims[start].morton_code & TREELET_MASK) != (morton_prims[end].morton_code & TREELET_MASK)
+        {
+            treelets.push((start, end));
+            start = end;
+        }
+    }
+
+    // Emit the treelets in parallel, each thread working on a share of them
+    let n_threads = thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1)
+        .min(treelets.len());
+    let chunk_size = treelets.len().div_ceil(n_threads);
+    let mut treelet_roots: Vec<(Box<BVHBuildNode>, usize)> = Vec::with_capacity(treelets.len());
+    thread::scope(|scope| {
+        let handles: Vec<_> = treelets
+            .chunks(chunk_size)
+            .map(|chunk| {
+                let morton_prims = &morton_prims;
+                scope.spawn(move || {
+                    chunk
+                        .iter()
+                        .map(|&(start, end)| {
+                            let mut nodes_created = 0;
+                            let root = emit_lbvh(
+                                info,
+                                morton_prims,
+                                start,
+                                end,
+                                FIRST_BIT_INDEX,
+                                max_prims_in_node,
+                                &mut nodes_created,
+                            );
+                            (root, nodes_created)
+                        })
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        for handle in handles {
+            treelet_roots.extend(handle.join().unwrap());
+        }
+    });
+
+    let mut total_nodes = treelet_roots.iter().map(|&(_, n)| n).sum();
+    let mut roots: Vec<Option<Box<BVHBuildNode>>> = treelet_roots.into_iter().map(|(root, _)| Some(root)).collect();
+    let mut root_info: Vec<BVHPrimitiveInfo> = roots
+        .iter()
+        .enumerate()
+        .map(|(i, root)| BVHPrimitiveInfo::new(i, root.as_ref().unwrap().bounds))
+        .collect();
+    let root = build_upper_sah(&mut roots, &mut root_info, &mut total_nodes);
+    let ordered = morton_prims.iter().map(|mp| mp.primitive_index).collect();
+    (root, total_nodes, ordered)
+}
+
+/// Splits `morton_prims[start..end]` at the first bit where the codes differ.
+fn emit_lbvh(
+    info: &[BVHPrimitiveInfo],
+    morton_prims: &[MortonPrimitive],
+    start: usize,
+    end: usize,
+    bit_index: i32,
+    max_prims_in_node: usize,
+    total_nodes: &mut usize,
+) -> Box<BVHBuildNode> {
+    let n_primitives = end - start;
+    if bit_index == -1 || n_primitives < max_prims_in_node {
+        *total_nodes += 1;
+        // Primitives with equal codes are split by count
+        return equal_count_leaves(start, end, max_prims_in_node, 0, total_nodes, &mut |start, end| {
+            let bounds = union_bounds(
+                morton_prims[start..end]
+                    .iter()
+                    .map(|mp| &info[mp.primitive_index].bounds),
+            );
+            Box::new(BVHBuildNode::leaf(start, end - start, bounds))
+        });
+    }
+
+    let mask = 1 << bit_index;
+    if (morton_prims[start].morton_code & mask) == (morton_prims[end - 1].morton_code & mask) {
+        return emit_lbvh(info, morton_prims, start, end, bit_index - 1, max_prims_in_node, total_nodes);
+    }
+
+    // Binary search for the first code with the bit set
+    let (mut lo, mut hi) = (start, end - 1);
+    while lo + 1 != hi {
+        let mid = (lo + hi) / 2;
+        if (morton_prims[lo].morton_code & mask) == (morton_prims[mid].morton_code & mask) {
+            lo = mid;
+        } else {
+            hi = mid;
+        }
+    }
+    let split = hi;
+
+    *total_nodes += 1;
+    let axis = (bit_index % 3) as u8;
+    let c0 = emit_lbvh(info, morton_prims, start, split, bit_index - 1, max_prims_in_node, total_nodes);
+    let c1 = emit_lbvh(info, morton_prims, split, end, bit_index - 1, max_prims_in_node, total_nodes);
+    Box::new(BVHBuildNode::interior(axis, c0, c1))
+}
+
+/// Joins treelet roots with SAH splits over their centroids. Unlike the main
+/// builder this never creates leaves, every root has to end up in the tree.
+fn build_upper_sah(
+    roots: &mut [Option<Box<BVHBuildNode>>],
+    info: &mut [BVHPrimitiveInfo],
+    total_nodes: &mut usize,
+) -> Box<BVHBuildNode> {
+    if info.len() == 1 {
+        return roots[info[0].primitive_number].take().unwrap();
+    }
+    *total_nodes += 1;
+
+    let bounds: Bounds3f = union_bounds(info.iter().map(|pi| &pi.bounds));
+    let cb = centroid_bounds(info);
+    let dim = cb.maximum_extent();
+    let mut mid = 0;
+    if cb[1][dim] > cb[0][dim] {
+        let (min_bucket, _) = find_sah_split(info, &bounds, &cb, dim);
+        mid = partition(info, |pi| bucket_index(&cb, &pi.centroid, dim, N_BUCKETS) <= min_bucket);
+    }
+    if mid == 0 || mid == info.len() {
+        mid = info.len() / 2;
+        info.select_nth_unstable_by(mid, |a, b| a.centroid[dim].partial_cmp(&b.centroid[dim]).unwrap());
+    }
+
+    let (left, right) = info.split_at_mut(mid);
+    let c0 = build_upper_sah(roots, left, total_nodes);
+    let c1 = build_upper_sah(roots, right, total_nodes);
+    Box::new(BVHBuildNode::interior(dim, c0, c1))
+}
+
+#[test]
+fn radix_sort_orders_morton_codes() {
+    let mut seed = 3u32;
+    let mut v: Vec<MortonPrimitive> = (0..1000)
+        .map(|i| {
+            seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+            MortonPrimitive {
+                primitive_index: i,
+                morton_code: seed >> 2,
+            }
+        })
+        .collect();
+    let mut expected = v.clone();
+    expected.sort_by_key(|mp| mp.morton_code);
+    radix_sort(&mut v);
+    assert!(v.iter().zip(&expected).all(|(a, b)| a.morton_code == b.morton_code));
+    assert_eq!(encode_morton3(1., 0., 0.), 1);
+    assert_eq!(encode_morton3(0., 0., 1.), 4);
+    assert_eq!(encode_morton3(1023., 1023., 1023.), (1 << 30) - 1);
+}
@@ -0,0 +1,443 @@
+use std::rc::Rc;
+
+use geometry::bounds::{Bounds, Bounds3f};
+use geometry::Point;
+use geometry::interaction::SurfaceInteraction;
+use geometry::point::Point3f;
+use geometry::ray::Ray_;
+use primitives::Primitive;
+
+use super::bvh::union_bounds;
+use super::stats::{NoStats, TraversalStats};
+
+const MAX_TODO: usize = 64;
+
+#[derive(Debug, Clone)]
+pub enum KdAccelNode {
+    /// `n_prims` entries of the primitive index table starting at `offset`.
+    Leaf { n_prims: usize, offset: usize },
+    /// The below child directly follows its parent in the node array.
+    Interior { axis: u8, split: f32, above_child: usize },
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum EdgeType {
+    Start,
+    End,
+}
+
+#[derive(Debug, Copy, Clone)]
+struct BoundEdge {
+    t: f32,
+    prim_num: usize,
+    edge_type: EdgeType,
+}
+
+#[derive(Copy, Clone)]
+struct KdToDo {
+    node: usize,
+    t_min: f32,
+    t_max: f32,
+}
+
+/// Kd-tree over `primitives`. Traversals are reported to `S`, which by
+/// default discards them.
+pub struct KdTreeAccel<S = NoStats> {
+    isect_cost: i32,
+    traversal_cost: i32,
+    max_prims: usize,
+    empty_bonus: f32,
+    primitives: Vec<Rc<dyn Primitive>>,
+    primitive_indices: Vec<usize>,
+    nodes: Vec<KdAccelNode>,
+    bounds: Bounds3f,
+    stats: S,
+}
+
+impl KdTreeAccel {
+    /// A `max_depth` of zero or less picks `8 + 1.3 log2(N)`; any depth is
+    /// capped by the size of the traversal stack.
+    pub fn new(
+        primitives: Vec<Rc<dyn Primitive>>,
+        isect_cost: i32,
+        traversal_cost: i32,
+        empty_bonus: f32,
+        max_prims: usize,
+        max_depth: i32,
+    ) -> KdTreeAccel {
+        KdTreeAccel::with_stats(primitives, isect_cost, traversal_cost, empty_bonus, max_prims, max_depth, NoStats)
+    }
+}
+
+impl<S: TraversalStats> KdTreeAccel<S> {
+    pub fn with_stats(
+        primitives: Vec<Rc<dyn Primitive>>,
+        isect_cost: i32,
+        traversal_cost: i32,
+        empty_bonus: f32,
+        max_prims: usize,
+        max_depth: i32,
+        stats: S,
+    ) -> KdTreeAccel<S> {
+        let mut accel = KdTreeAccel {
+            isect_cost,
+            traversal_cost,
+            max_prims: max_prims.max(1),
+            empty_bonus,
+            primitives,
+            primitive_indices: Vec::new(),
+            nodes: Vec::new(),
+            bounds: Bounds3f::from(&Point3f::zero()),
+            stats,
+        };
+        if accel.primitives.is_empty() {
+            return accel;
+        }
+        let max_depth = if max_depth <= 0 {
+            (8. + 1.3 * (accel.primitives.len() as f32).log2()).round() as i32
+        } else {
+            max_depth
+        };
+        // Traversal keeps at most one deferred child per level
+        let max_depth = max_depth.min(MAX_TODO as i32 - 1);
+
+        let prim_bounds: Vec<Bounds3f> = accel.primitives.iter().map(|p| p.world_bound()).collect();
+        accel.bounds = union_bounds(prim_bounds.iter());
+        let mut edges: [Vec<BoundEdge>; 3] = [Vec::new(), Vec::new(), Vec::new()];
+        let prim_nums: Vec<usize> = (0..accel.primitives.len()).collect();
+        let bounds = accel.bounds;
+        accel.build_tree(&bounds, &prim_bounds, &prim_nums, max_depth, &mut edges, 0);
+        accel
+    }
+
+    fn build_tree(
+        &mut self,
+        node_bounds: &Bounds3f,
+        all_prim_bounds: &[Bounds3f],
+        prim_nums: &[usize],
+        depth: i32,
+        edges: &mut [Vec<BoundEdge>; 3],
+        mut bad_refines: u32,
+    ) {
+        let n_primitives = prim_nums.len();
+        if n_primitives <= self.max_prims || depth == 0 {
+            self.push_leaf(prim_nums);
+            return;
+        }
+
+        // Choose the split with the lowest SAH cost, trying the other axes
+        // if the longest one has no useful split
+        let mut best: Option<(u8, usize)> = None;
+        let mut best_cost = f32::INFINITY;
+        let old_cost = self.isect_cost as f32 * n_primitives as f32;
+        let total_sa = node_bounds.surface_area();
+        let inv_total_sa = 1. / total_sa;
+        let d = node_bounds.diagonal();
+        let mut axis = node_bounds.maximum_extent();
+        for _ in 0..3 {
+            let a = axis as usize;
+            edges[a].clear();
+            for &pn in prim_nums {
+                let b = &all_prim_bounds[pn];
+                edges[a].push(BoundEdge { t: b[0][axis], prim_num: pn, edge_type: EdgeType::Start });
+                edges[a].push(BoundEdge { t: b[1][axis], prim_num: pn, edge_type: EdgeType::End });
+            }
+            edges[a].sort_by(|e0, e1| {
+                e0.t
+                    .partial_cmp(&e1.t)
+                    .unwrap()
+                    .then(e0.edge_type.cmp(&e1.edge_type))
+            });
+
+            let (mut n_below, mut n_above) = (0, n_primitives);
+            let other0 = (a + 1) % 3;
+            let other1 = (a + 2) % 3;
+            for (i, edge) in edges[a].iter().enumerate() {
+                if edge.edge_type == EdgeType::End {
+                    n_above -= 1;
+                }
+                let t = edge.t;
+                if t > node_bounds[0][axis] && t < node_bounds[1][axis] {
+                    let d_other = d[other0] * d[other1];
+                    let d_sum = d[other0] + d[other1];
+                    let below_sa = 2. * (d_other + (t - node_bounds[0][axis]) * d_sum);
+                    let above_sa = 2. * (d_other + (node_bounds[1][axis] - t) * d_sum);
+                    let p_below = below_sa * inv_total_sa;
+                    let p_above = above_sa * inv_total_sa;
+                    let eb = if n_above == 0 || n_below == 0 { self.empty_bonus } else { 0. };
+                    let cost = self.traversal_cost as f32
+                        + self.isect_cost as f32 * (1. - eb)
+                            * (p_below * n_below as f32 + p_above * n_above as f32);
+                    if cost < best_cost {
+                        best_cost = cost;
+                        best = Some((axis, i));
+                    }
+                }
+                if edge.edge_type == EdgeType::Start {
+                    n_below += 1;
+                }
+            }
+            if best.is_some() {
+                break;
+            }
+            axis = (axis + 1) % 3;
+        }
+
+        if best_cost > old_cost {
+            bad_refines += 1;
+        }
+        let (axis, offset) = match best {
+            Some(b) if !((best_cost > 4. * old_cost && n_primitives < 16) || bad_refines == 3) => b,
+            _ => {
+                self.push_leaf(prim_nums);
+                return;
+            }
+        };
+
+        // Classify the primitives against the split and recurse
+        let a = axis as usize;
+        let mut prims0 = Vec::new();
+        let mut prims1 = Vec::new();
+        for edge in &edges[a][..offset] {
+            if edge.edge_type == EdgeType::Start {
+                prims0.push(edge.prim_num);
+            }
+        }
+        for edge in &edges[a][offset + 1..] {
+            if edge.edge_type == EdgeType::End {
+                prims1.push(edge.prim_num);
+            }
+        }
+
+        let split = edges[a][offset].t;
+        let mut bounds0 = *node_bounds;
+        let mut bounds1 = *node_bounds;
+        bounds0[1][axis] = split;
+        bounds1[0][axis] = split;
+
+        let node_num = self.nodes.len();
+        self.nodes.push(KdAccelNode::Interior { axis, split, above_child: 0 });
+        self.build_tree(&bounds0, all_prim_bounds, &prims0, depth - 1, edges, bad_refines);
+        let above = self.nodes.len();
+        if let KdAccelNode::Interior { ref mut above_child, .. } = self.nodes[node_num] {
+            *above_child = above;
+        }
+        self.build_tree(&bounds1, all_prim_bounds, &prims1, depth - 1, edges, bad_refines);
+    }
+
+    fn push_leaf(&mut self, prim_nums: &[usize]) {
+        self.nodes.push(KdAccelNode::Leaf {
+            n_prims: prim_nums.len(),
+            offset: self.primitive_indices.len(),
+        });
+        self.primitive_indices.extend_from_slice(prim_nums);
+    }
+
+    pub fn nodes(&self) -> &[KdAccelNode] {
+        &self.nodes
+    }
+
+    pub fn stats(&self) -> &S {
+        &self.stats
+    }
+
+    fn leaf_primitives(&self, n_prims: usize, offset: usize) -> impl Iterator<Item = &Rc<dyn Primitive>> {
+        self.primitive_indices[offset..offset + n_prims]
+            .iter()
+            .map(move |&i| &self.primitives[i])
+    }
+
+    /// Returns the child to visit next, the child to defer (`usize::MAX` if
+    /// the ray's `[t_min, t_max]` interval does not reach it) and the ray
+    /// parameter of the split plane.
+    #[allow(clippy::too_many_arguments)]
+    fn order_children(
+        &self,
+        node: usize,
+        axis: u8,
+        split: f32,
+        above_child: usize,
+        ray: &Ray_,
+        t_min: f32,
+        t_max: f32,
+    ) -> (usize, usize, f32) {
+        let o = ray.o[axis];
+        let d = ray.d[axis];
+        let t_plane = (split - o) / d;
+        let below_first = o < split || (o == split && d <= 0.);
+        let (first, second) = if below_first {
+            (node + 1, above_child)
+        } else {
+            (above_child, node + 1)
+        };
+        if t_plane > t_max || t_plane <= 0. {
+            (first, usize::MAX, t_plane)
+        } else if t_plane < t_min {
+            (second, usize::MAX, t_plane)
+        } else {
+            (first, second, t_plane)
+        }
+    }
+}
+
+impl<S: TraversalStats> Primitive for KdTreeAccel<S> {
+    fn world_bound(&self) -> Bounds3f {
+        self.bounds
+    }
+
+    fn intersect(&self, ray: &Ray_) -> Option<SurfaceInteraction> {
+        if self.nodes.is_empty() {
+            return None;
+        }
+        let (mut t_min, mut t_max) = match self.bounds.intersect_p(ray) {
+            Some(t) => t,
+            None => {
+                self.stats.record_traversal(0);
+                return None;
+            }
+        };
+
+        let mut todo = [KdToDo { node: 0, t_min: 0., t_max: 0. }; MAX_TODO];
+        let mut todo_pos = 0;
+        let mut node = 0;
+        let mut isect = None;
+        let mut visited = 0;
+        loop {
+            if ray.tmax.get() < t_min {
+                break;
+            }
+            visited += 1;
+            match self.nodes[node] {
+                KdAccelNode::Interior { axis, split, above_child } => {
+                    let (first, second, t_plane) =
+                        self.order_children(node, axis, split, above_child, ray, t_min, t_max);
+                    node = first;
+                    if second != usize::MAX {
+                        todo[todo_pos] = KdToDo { node: second, t_min: t_plane, t_max };
+                        todo_pos += 1;
+                        t_max = t_plane;
+                    }
+                }
+                KdAccelNode::Leaf { n_prims, offset } => {
+                    for prim in self.leaf_primitives(n_prims, offset) {
+                        if let Some(si) = prim.intersect(ray) {
+                            isect = Some(si);
+                        }
+                    }
+                    if todo_pos == 0 {
+                        break;
+                    }
+                    todo_pos -= 1;
+                    node = todo[todo_pos].node;
+                    t_min = todo[todo_pos].t_min;
+                    t_max = todo[todo_pos].t_max;
+                }
+            }
+        }
+        self.stats.record_traversal(visited);
+        isect
+    }
+
+    fn intersect_p(&self, ray: &Ray_) -> bool {
+        if self.nodes.is_empty() {
+            return false;
+        }
+        let (mut t_min, mut t_max) = match self.bounds.intersect_p(ray) {
+            Some(t) => t,
+            None => {
+                self.stats.record_traversal(0);
+                return false;
+            }
+        };
+
+        let mut todo = [KdToDo { node: 0, t_min: 0., t_max: 0. }; MAX_TODO];
+        let mut todo_pos = 0;
+        let mut node = 0;
+        let mut visited = 0;
+        loop {
+            visited += 1;
+            match self.nodes[node] {
+                KdAccelNode::Interior { axis, split, above_child } => {
+                    let (first, second, t_plane) =
+                        self.order_children(node, axis, split, above_child, ray, t_min, t_max);
+                    node = first;
+                    if second != usize::MAX {
+                        todo[todo_pos] = KdToDo { node: second, t_min: t_plane, t_max };
+                        todo_pos += 1;
+                        t_max = t_plane;
+                    }
+                }
+                KdAccelNode::Leaf { n_prims, offset } => {
+                    for prim in self.leaf_primitives(n_prims, offset) {
+                        if prim.intersect_p(ray) {
+                            self.stats.record_traversal(visited);
+                            return true;
+                        }
+                    }
+                    if todo_pos == 0 {
+                        break;
+                    }
+                    todo_pos -= 1;
+                    node = todo[todo_pos].node;
+                    t_min = todo[todo_pos].t_min;
+                    t_max = todo[todo_pos].t_max;
+                }
+            }
+        }
+        self.stats.record_traversal(visited);
+        false
+    }
+}
+
+#[test]
+fn kdtree_matches_brute_force() {
+    use super::test_util::{brute_force_hit, closest_hit, random_rays, random_triangles};
+
+    let prims = random_triangles(200, 11);
+    let kdtree = KdTreeAccel::new(prims.clone(), 80, 1, 0.5, 1, -1);
+    assert!(kdtree.nodes().len() > 1);
+    for (o, d) in random_rays(300, 12) {
+        let brute = brute_force_hit(&prims, &o, &d);
+        assert_eq!(closest_hit(&kdtree, &o, &d), brute);
+        let shadow_ray = Ray_::new(&o, &d, f32::INFINITY, 0., None);
+        assert_eq!(kdtree.intersect_p(&shadow_ray), brute.is_some());
+    }
+}
+
+#[test]
+fn deep_trees_fit_the_traversal_stack() {
+    use geometry::vector::Vector3f;
+    use super::test_util::{brute_force_hit, closest_hit, triangle_primitives};
+
+    // Nested triangles halving in size around the origin; every split
+    // plane cuts off a few of them, which builds 78 levels if unbounded
+    let mut p = Vec::new();
+    for i in 0..110 {
+        let a = 0.5f32.powi(i);
+        p.push(Point3f::new(-a, -a, -a));
+        p.push(Point3f::new(a, a, a));
+        p.push(Point3f::new(a, -a, 0.));
+    }
+    let prims = triangle_primitives(p);
+    let kdtree = KdTreeAccel::new(prims.clone(), 80, 1, 0.5, 1, 100);
+
+    fn depth(nodes: &[KdAccelNode], node: usize) -> usize {
+        match nodes[node] {
+            KdAccelNode::Leaf { .. } => 0,
+            KdAccelNode::Interior { above_child, .. } => 1 + depth(nodes, node + 1).max(depth(nodes, above_child)),
+        }
+    }
+    assert_eq!(depth(kdtree.nodes(), 0), MAX_TODO - 1);
+
+    // Rays leaving the origin defer the outer child of every node, rays
+    // from outside hit the outermost triangles
+    for i in 0..16 {
+        let d = Vector3f::new(1.5, (i as f32 - 7.5) * 0.2, 2.);
+        for &o in &[Point3f::zero(), Point3f::zero() - d] {
+            let brute = brute_force_hit(&prims, &o, &d);
+            assert_eq!(closest_hit(&kdtree, &o, &d), brute);
+            assert_eq!(kdtree.intersect_p(&Ray_::new(&o, &d, f32::INFINITY, 0., None)), brute.is_some());
+        }
+        assert!(brute_force_hit(&prims, &(Point3f::zero() - d), &d).is_some());
+    }
+}
@@ -0,0 +1,10 @@
+pub mod bvh;
+pub mod cache;
+pub mod hlbvh;
+pub mod sbvh;
+pub mod kdtree;
+pub mod packet;
+pub mod stats;
+#[cfg(test)]
+pub mod test_util;
+pub mod wide;
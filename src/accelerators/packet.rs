@@ -0,0 +1,316 @@
+use std::rc::Rc;
+
+use geometry::bounds::Bounds3f;
+use geometry::interaction::SurfaceInteraction;
+use geometry::ray::Ray_;
+use geometry::vector::Vector3f;
+use primitives::Primitive;
+
+use super::bvh::{ray_inv_dir, BVHAccel};
+use super::stats::TraversalStats;
+
+/// Largest packet traced at once, one bit of the active mask per ray.
+pub const MAX_PACKET_SIZE: usize = 32;
+
+/// Conservative bounds of all rays of a packet whose directions share the
+/// same octant: ranges of origins and reciprocal directions per axis. A box
+/// missed by every ray these ranges can produce is missed by the packet.
+struct PacketFrustum {
+    o_min: [f32; 3],
+    o_max: [f32; 3],
+    inv_min: [f32; 3],
+    inv_max: [f32; 3],
+    dir_is_neg: [u8; 3],
+}
+
+impl PacketFrustum {
+    fn new(rays: &[Ray_], inv_dir: &[Vector3f], dir_is_neg: &[[u8; 3]]) -> Option<PacketFrustum> {
+        if dir_is_neg.iter().any(|neg| *neg != dir_is_neg[0]) {
+            return None;
+        }
+        let mut frustum = PacketFrustum {
+            o_min: [f32::INFINITY; 3],
+            o_max: [f32::NEG_INFINITY; 3],
+            inv_min: [f32::INFINITY; 3],
+            inv_max: [f32::NEG_INFINITY; 3],
+            dir_is_neg: dir_is_neg[0],
+        };
+        for (ray, inv) in rays.iter().zip(inv_dir) {
+            for axis in 0..3 {
+                frustum.o_min[axis] = frustum.o_min[axis].min(ray.o[axis]);
+                frustum.o_max[axis] = frustum.o_max[axis].max(ray.o[axis]);
+                frustum.inv_min[axis] = frustum.inv_min[axis].min(inv[axis]);
+                frustum.inv_max[axis] = frustum.inv_max[axis].max(inv[axis]);
+            }
+        }
+        Some(frustum)
+    }
+
+    /// Returns true only if no ray of the packet can hit `b` before `t_max`.
+    fn culls(&self, b: &Bounds3f, t_max: f32) -> bool {
+        let mut t_near = 0f32;
+        let mut t_far = t_max;
+        for axis in 0..3 {
+            let neg = self.dir_is_neg[axis];
+            let near_plane = b[neg][axis as u8];
+            let far_plane = b[1 - neg][axis as u8];
+            let near = interval_mul(
+                (near_plane - self.o_max[axis], near_plane - self.o_min[axis]),
+                (self.inv_min[axis], self.inv_max[axis]),
+            );
+            let far = interval_mul(
+                (far_plane - self.o_max[axis], far_plane - self.o_min[axis]),
+                (self.inv_min[axis], self.inv_max[axis]),
+            );
+            // NaNs from zero directions leave the bounds untouched
+            t_near = t_near.max(near.0);
+            t_far = t_far.min(far.1);
+        }
+        t_near > t_far
+    }
+}
+
+fn interval_mul(a: (f32, f32), b: (f32, f32)) -> (f32, f32) {
+    let p = [a.0 * b.0, a.0 * b.1, a.1 * b.0, a.1 * b.1];
+    if p.iter().any(|v| v.is_nan()) {
+        return (f32::NEG_INFINITY, f32::INFINITY);
+    }
+    (
+        p.iter().cloned().fold(f32::INFINITY, f32::min),
+        p.iter().cloned().fold(f32::NEG_INFINITY, f32::max),
+    )
+}
+
+impl<S: TraversalStats> BVHAccel<S> {
+    /// Intersects up to `MAX_PACKET_SIZE` rays at once, sharing one node
+    /// stack. Coherent packets whose directions lie in one octant, such as
+    /// camera rays, first test each node against the packet frustum.
+    pub fn intersect_packet(&self, rays: &[Ray_]) -> Vec<Option<SurfaceInteraction>> {
+        let mut isects: Vec<Option<SurfaceInteraction>> = (0..rays.len()).map(|_| None).collect();
+        self.traverse_packet(rays, |prims, i| {
+            for prim in prims {
+                if let Some(si) = prim.intersect(&rays[i]) {
+                    isects[i] = Some(si);
+                }
+            }
+            false
+        });
+        isects
+    }
+
+    /// Occlusion variant of `intersect_packet`, rays leave the packet as soon
+    /// as they are blocked.
+    pub fn intersect_p_packet(&self, rays: &[Ray_]) -> Vec<bool> {
+        let mut occluded = vec![false; rays.len()];
+        self.traverse_packet(rays, |prims, i| {
+            occluded[i] = prims.iter().any(|prim| prim.intersect_p(&rays[i]));
+            occluded[i]
+        });
+        occluded
+    }
+
+    /// Calls `visit` for every leaf and active ray hitting it, a return value
+    /// of true terminates that ray.
+    fn traverse_packet<F: FnMut(&[Rc<dyn Primitive>], usize) -> bool>(&self, rays: &[Ray_], mut visit: F) {
+        assert!(rays.len() <= MAX_PACKET_SIZE);
+        if self.nodes().is_empty() || rays.is_empty() {
+            return;
+        }
+        let nodes = self.nodes();
+        let (inv_dir, dir_is_neg): (Vec<Vector3f>, Vec<[u8; 3]>) = rays.iter().map(ray_inv_dir).unzip();
+        let frustum = PacketFrustum::new(rays, &inv_dir, &dir_is_neg);
+
+        let mut active: u32 = if rays.len() == 32 {
+            !0
+        } else {
+            (1 << rays.len()) - 1
+        };
+        let mut stack = [0usize; 64];
+        let mut stack_len = 0;
+        let mut current = 0;
+        loop {
+            let node = &nodes[current];
+            let mut hit_mask = 0u32;
+            let culled = match frustum {
+                Some(ref f) => {
+                    let t_max = (0..rays.len())
+                        .filter(|&i| active & (1 << i) != 0)
+                        .map(|i| rays[i].tmax.get())
+                        .fold(0., f32::max);
+                    f.culls(&node.bounds, t_max)
+                }
+                None => false,
+            };
+            if !culled {
+                for i in 0..rays.len() {
+                    if active & (1 << i) != 0 && node.bounds.intersect_p_inv(&rays[i], &inv_dir[i], &dir_is_neg[i]) {
+                        hit_mask |= 1 << i;
+                    }
+                }
+            }
+
+            if hit_mask != 0 {
+                if node.n_primitives > 0 {
+                    let first = node.offset as usize;
+                    let prims = &self.primitives()[first..first + node.n_primitives as usize];
+                    for i in 0..rays.len() {
+                        if hit_mask & (1 << i) != 0 && visit(prims, i) {
+                            active &= !(1 << i);
+                        }
+                    }
+                    if active == 0 {
+                        return;
+                    }
+                } else {
+                    // Order the children by the direction of the first ray hitting the node
+                    let first_ray = hit_mask.trailing_zeros() as usize;
+                    if dir_is_neg[first_ray][node.axis as usize] != 0 {
+                        stack[stack_len] = current + 1;
+                        current = node.offset as usize;
+                    } else {
+                        stack[stack_len] = node.offset as usize;
+                        current += 1;
+                    }
+                    stack_len += 1;
+                    continue;
+                }
+            }
+            if stack_len == 0 {
+                break;
+            }
+            stack_len -= 1;
+            current = stack[stack_len];
+        }
+    }
+}
+
+/// Rays submitted for batched tracing, e.g. the shadow rays of a whole tile.
+/// Tracing groups them by direction octant into packets and returns results
+/// in submission order.
+pub struct RayStream {
+    rays: Vec<Ray_>,
+    packet_size: usize,
+}
+
+impl RayStream {
+    pub fn new(packet_size: usize) -> RayStream {
+        RayStream {
+            rays: Vec::new(),
+            packet_size: packet_size.clamp(1, MAX_PACKET_SIZE),
+        }
+    }
+
+    /// Adds a ray and returns its index in the results.
+    pub fn push(&mut self, ray: Ray_) -> usize {
+        self.rays.push(ray);
+        self.rays.len() - 1
+    }
+
+    pub fn len(&self) -> usize {
+        self.rays.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rays.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.rays.clear();
+    }
+
+    pub fn rays(&self) -> &[Ray_] {
+        &self.rays
+    }
+
+    /// Ray indices grouped by octant, each group split into packets.
+    fn packets(&self) -> Vec<Vec<usize>> {
+        let mut octants: [Vec<usize>; 8] = Default::default();
+        for (i, ray) in self.rays.iter().enumerate() {
+            let octant = (ray.d.x < 0.) as usize | ((ray.d.y < 0.) as usize) << 1 | ((ray.d.z < 0.) as usize) << 2;
+            octants[octant].push(i);
+        }
+        octants
+            .iter()
+            .flat_map(|indices| indices.chunks(self.packet_size).map(|c| c.to_vec()))
+            .collect()
+    }
+
+    fn gather(&self, indices: &[usize]) -> Vec<Ray_> {
+        indices
+            .iter()
+            .map(|&i| {
+                let r = &self.rays[i];
+                Ray_::new(&r.o, &r.d, r.tmax.get(), r.time, r.medium.clone())
+            })
+            .collect()
+    }
+
+    /// Closest hits of all rays, also shortening each ray's `tmax`.
+    pub fn intersect<S: TraversalStats>(&self, accel: &BVHAccel<S>) -> Vec<Option<SurfaceInteraction>> {
+        let mut results: Vec<Option<SurfaceInteraction>> = (0..self.rays.len()).map(|_| None).collect();
+        for packet in self.packets() {
+            let rays = self.gather(&packet);
+            for ((&i, isect), ray) in packet.iter().zip(accel.intersect_packet(&rays)).zip(&rays) {
+                self.rays[i].tmax.set(ray.tmax.get());
+                results[i] = isect;
+            }
+        }
+        results
+    }
+
+    pub fn occluded<S: TraversalStats>(&self, accel: &BVHAccel<S>) -> Vec<bool> {
+        let mut results = vec![false; self.rays.len()];
+        for packet in self.packets() {
+            let rays = self.gather(&packet);
+            for (&i, occluded) in packet.iter().zip(accel.intersect_p_packet(&rays)) {
+                results[i] = occluded;
+            }
+        }
+        results
+    }
+}
+
+#[test]
+fn packets_match_single_rays() {
+    use geometry::point::Point3f;
+    use super::bvh::SplitMethod;
+    use super::test_util::{lcg, random_triangles};
+
+    let bvh = BVHAccel::new(random_triangles(300, 13), 2, SplitMethod::SAH);
+
+    // Camera-like 4x4 packets from a pinhole at the scene corner
+    let eye = Point3f::new(5., 5., -5.);
+    for tile in 0..16 {
+        let rays: Vec<Ray_> = (0..16)
+            .map(|i| {
+                let x = (tile % 4 * 4 + i % 4) as f32 / 16. - 0.5;
+                let y = (tile / 4 * 4 + i / 4) as f32 / 16. - 0.5;
+                Ray_::new(&eye, &Vector3f::new(x, y, 1.), f32::INFINITY, 0., None)
+            })
+            .collect();
+        let hits = bvh.intersect_packet(&rays);
+        for (ray, hit) in rays.iter().zip(hits) {
+            let single = Ray_::new(&ray.o, &ray.d, f32::INFINITY, 0., None);
+            let expected = bvh.intersect(&single).map(|_| single.tmax.get());
+            assert_eq!(hit.map(|_| ray.tmax.get()), expected);
+        }
+    }
+
+    // Incoherent shadow rays submitted as a stream
+    let mut rand = lcg(14);
+    let mut stream = RayStream::new(8);
+    for _ in 0..200 {
+        let o = Point3f::new(rand() * 10., rand() * 10., rand() * 10.);
+        let d = Vector3f::new(rand() - 0.5, rand() - 0.5, rand() - 0.5);
+        stream.push(Ray_::new(&o, &d, rand() * 3., 0., None));
+    }
+    let occluded = stream.occluded(&bvh);
+    let expected: Vec<bool> = stream.rays().iter().map(|ray| bvh.intersect_p(ray)).collect();
+    assert_eq!(occluded, expected);
+    assert!(occluded.iter().any(|&o| o));
+    // Closest hits shorten the rays, so they are compared last
+    let closest = stream.intersect(&bvh);
+    for (isect, &occluded) in closest.iter().zip(&occluded) {
+        assert_eq!(isect.is_some(), occluded);
+    }
+}
@@ -0,0 +1,287 @@
+use geometry::bounds::{Bounds, Bounds3f};
+
+use super::bvh::{bucket_index, centroid_bounds, equal_count_leaves, find_sah_split, partition, union_bounds,
+                 BVHBuildNode, BVHPrimitiveInfo, N_BUCKETS};
+
+const N_SPATIAL_BINS: usize = 16;
+/// Keeps the tree shallow enough for the 64 entry traversal stack.
+const MAX_DEPTH: u32 = 48;
+
+enum Split {
+    Object { dim: u8, bucket: usize },
+    Spatial { dim: u8, position: f32 },
+}
+
+struct SpatialBin {
+    bounds: Option<Bounds3f>,
+    entries: usize,
+    exits: usize,
+}
+
+fn union_opt(a: Option<Bounds3f>, b: Bounds3f) -> Option<Bounds3f> {
+    Some(match a {
+        Some(a) => Bounds::bounds_union(&a, b),
+        None => b,
+    })
+}
+
+fn area_opt(b: &Option<Bounds3f>) -> f32 {
+    b.map_or(0., |b| b.surface_area())
+}
+
+/// Builds a BVH that may split primitive references at planes as well as
+/// partition them (Stich et al. 2009). References straddling a spatial split
+/// are duplicated with their bounds clipped to either side, so leaves can
+/// share primitives. Spatial splits are only evaluated where the children of
+/// the best object split overlap by more than `alpha` times the root surface
+/// area. Returns the tree, its node count and the primitive ordering.
+pub fn sbvh_build(info: &[BVHPrimitiveInfo], max_prims_in_node: usize, alpha: f32) -> (Box<BVHBuildNode>, usize, Vec<usize>) {
+    let mut refs = info.to_vec();
+    let root_area = union_bounds(info.iter().map(|pi| &pi.bounds)).surface_area();
+    let mut builder = SpatialSplitBuilder {
+        max_prims_in_node,
+        min_overlap: alpha * root_area,
+        total_nodes: 0,
+        order: Vec::with_capacity(info.len()),
+    };
+    let root = builder.build(&mut refs, 0);
+    (root, builder.total_nodes, builder.order)
+}
+
+struct SpatialSplitBuilder {
+    max_prims_in_node: usize,
+    min_overlap: f32,
+    total_nodes: usize,
+    order: Vec<usize>,
+}
+
+impl SpatialSplitBuilder {
+    /// Leaf over `refs`, or leaves split by count if there are more than
+    /// `max_prims_in_node`.
+    fn leaf(&mut self, refs: &[BVHPrimitiveInfo], bounds: Bounds3f) -> Box<BVHBuildNode> {
+        let order = &mut self.order;
+        equal_count_leaves(0, refs.len(), self.max_prims_in_node, 0, &mut self.total_nodes, &mut |start, end| {
+            let bounds = if end - start == refs.len() {
+                bounds
+            } else {
+                union_bounds(refs[start..end].iter().map(|r| &r.bounds))
+            };
+            let first = order.len();
+            order.extend(refs[start..end].iter().map(|r| r.primitive_number));
+            Box::new(BVHBuildNode::leaf(first, end - start, bounds))
+        })
+    }
+
+    fn build(&mut self, refs: &mut Vec<BVHPrimitiveInfo>, depth: u32) -> Box<BVHBuildNode> {
+        self.total_nodes += 1;
+        let bounds = union_bounds(refs.iter().map(|r| &r.bounds));
+        let n_refs = refs.len();
+        if n_refs == 1 || depth >= MAX_DEPTH {
+            return self.leaf(refs, bounds);
+        }
+
+        // Best object split over the centroid bins
+        let cb = centroid_bounds(refs);
+        let dim = cb.maximum_extent();
+        let mut best: Option<(Split, f32)> = None;
+        if cb[1][dim] > cb[0][dim] {
+            let (bucket, cost) = find_sah_split(refs, &bounds, &cb, dim);
+            let mut left = None;
+            let mut right = None;
+            for r in refs.iter() {
+                if bucket_index(&cb, &r.centroid, dim, N_BUCKETS) <= bucket {
+                    left = union_opt(left, r.bounds);
+                } else {
+                    right = union_opt(right, r.bounds);
+                }
+            }
+            let overlap = match (left, right) {
+                (Some(l), Some(r)) => overlap_area(&l, &r),
+                _ => 0.,
+            };
+            best = Some((Split::Object { dim, bucket }, cost));
+            if overlap > self.min_overlap {
+                best = self.find_spatial_split(refs, &bounds, best);
+            }
+        } else {
+            best = self.find_spatial_split(refs, &bounds, best);
+        }
+
+        let split = match best {
+            Some((split, cost)) if n_refs > self.max_prims_in_node || cost < n_refs as f32 => split,
+            _ => return self.leaf(refs, bounds),
+        };
+
+        let axis = dim_of(&split);
+        let (mut left, mut right) = match split {
+            Split::Object { dim, bucket } => {
+                let mid = partition(refs, |r| bucket_index(&cb, &r.centroid, dim, N_BUCKETS) <= bucket);
+                let right = refs.split_off(mid);
+                (::std::mem::take(refs), right)
+            }
+            Split::Spatial { dim, position } => split_references(refs, &bounds, dim, position),
+        };
+        if left.is_empty() || right.is_empty() {
+            let mut all = left;
+            all.append(&mut right);
+            return self.leaf(&all, bounds);
+        }
+        let c0 = self.build(&mut left, depth + 1);
+        let c1 = self.build(&mut right, depth + 1);
+        Box::new(BVHBuildNode::interior(axis, c0, c1))
+    }
+
+    /// Bins the clipped reference bounds along each axis and returns the
+    /// spatial split if it beats `best`.
+    fn find_spatial_split(
+        &self,
+        refs: &[BVHPrimitiveInfo],
+        bounds: &Bounds3f,
+        mut best: Option<(Split, f32)>,
+    ) -> Option<(Split, f32)> {
+        let total_area = bounds.surface_area();
+        for dim in 0..3u8 {
+            let lo = bounds[0][dim];
+            let extent = bounds[1][dim] - lo;
+            if extent <= 0. {
+                continue;
+            }
+            let bin_width = extent / N_SPATIAL_BINS as f32;
+            let bin_of = |x: f32| (((x - lo) / bin_width) as usize).min(N_SPATIAL_BINS - 1);
+
+            let mut bins: Vec<SpatialBin> = (0..N_SPATIAL_BINS)
+                .map(|_| SpatialBin {
+                    bounds: None,
+                    entries: 0,
+                    exits: 0,
+                })
+                .collect();
+            for r in refs {
+                let first = bin_of(r.bounds[0][dim]);
+                let last = bin_of(r.bounds[1][dim]);
+                for (b, bin) in bins.iter_mut().enumerate().take(last + 1).skip(first) {
+                    let mut bin_bounds = *bounds;
+                    bin_bounds[0][dim] = lo + b as f32 * bin_width;
+                    bin_bounds[1][dim] = lo + (b + 1) as f32 * bin_width;
+                    bin.bounds = union_opt(bin.bounds, Bounds::intersect(&r.bounds, &bin_bounds));
+                }
+                bins[first].entries += 1;
+                bins[last].exits += 1;
+            }
+
+            // Sweep from the right to get the bounds above every plane
+            let mut right_area = [0f32; N_SPATIAL_BINS];
+            let mut right_bounds = None;
+            for i in (1..N_SPATIAL_BINS).rev() {
+                if let Some(b) = bins[i].bounds {
+                    right_bounds = union_opt(right_bounds, b);
+                }
+                right_area[i] = area_opt(&right_bounds);
+            }
+            let mut left_bounds = None;
+            let (mut n_left, mut n_right) = (0, refs.len());
+            for i in 0..N_SPATIAL_BINS - 1 {
+                if let Some(b) = bins[i].bounds {
+                    left_bounds = union_opt(left_bounds, b);
+                }
+                n_left += bins[i].entries;
+                n_right -= bins[i].exits;
+                if n_left == 0 || n_right == 0 {
+                    continue;
+                }
+                let cost = 0.125
+                    + (n_left as f32 * area_opt(&left_bounds) + n_right as f32 * right_area[i + 1]) / total_area;
+                if best.as_ref().is_none_or(|&(_, c)| cost < c) {
+                    let position = lo + (i + 1) as f32 * bin_width;
+                    best = Some((Split::Spatial { dim, position }, cost));
+                }
+            }
+        }
+        best
+    }
+}
+
+fn dim_of(split: &Split) -> u8 {
+    match *split {
+        Split::Object { dim, .. } | Split::Spatial { dim, .. } => dim,
+    }
+}
+
+fn overlap_area(a: &Bounds3f, b: &Bounds3f) -> f32 {
+    let i = Bounds::intersect(a, b);
+    if (0..3u8).any(|d| i[0][d] > i[1][d]) {
+        0.
+    } else {
+        i.surface_area()
+    }
+}
+
+/// Distributes the references to both sides of the plane, clipping the ones
+/// straddling it.
+fn split_references(
+    refs: &mut Vec<BVHPrimitiveInfo>,
+    bounds: &Bounds3f,
+    dim: u8,
+    position: f32,
+) -> (Vec<BVHPrimitiveInfo>, Vec<BVHPrimitiveInfo>) {
+    let mut left_box = *bounds;
+    left_box[1][dim] = position;
+    let mut right_box = *bounds;
+    right_box[0][dim] = position;
+
+    let mut left = Vec::with_capacity(refs.len());
+    let mut right = Vec::with_capacity(refs.len());
+    for r in refs.drain(..) {
+        if r.bounds[1][dim] <= position {
+            left.push(r);
+        } else if r.bounds[0][dim] >= position {
+            right.push(r);
+        } else {
+            let n = r.primitive_number;
+            left.push(BVHPrimitiveInfo::new(n, Bounds::intersect(&r.bounds, &left_box)));
+            right.push(BVHPrimitiveInfo::new(n, Bounds::intersect(&r.bounds, &right_box)));
+        }
+    }
+    (left, right)
+}
+
+#[test]
+fn spatial_splits_reduce_cost_for_thin_triangles() {
+    use geometry::point::Point3f;
+    use geometry::vector::Vector3f;
+    use super::bvh::{BVHAccel, SplitMethod};
+    use super::test_util::{closest_hit, lcg, random_triangle_points, triangle_primitives};
+
+    // Small clutter crossed by a few long axis aligned beams, which drag the
+    // bounds of every node they end up in across the whole scene
+    let mut rand = lcg(41);
+    let mut p = random_triangle_points(300, 0.5, &mut rand);
+    for i in 0..3 {
+        let a = 2.5 + i as f32 * 2.5;
+        let beams = [
+            (Point3f::new(0., a, a), Vector3f::new(10., 0., 0.), Vector3f::new(0., 0.1, 0.)),
+            (Point3f::new(a, 0., a), Vector3f::new(0., 10., 0.), Vector3f::new(0., 0., 0.1)),
+            (Point3f::new(a, a, 0.), Vector3f::new(0., 0., 10.), Vector3f::new(0.1, 0., 0.)),
+        ];
+        for &(o, length, width) in beams.iter() {
+            p.push(o);
+            p.push(o + length);
+            p.push(o + width);
+        }
+    }
+    let prims = triangle_primitives(p);
+
+    let sah = BVHAccel::new(prims.clone(), 2, SplitMethod::SAH);
+    let sbvh = BVHAccel::new(prims.clone(), 2, SplitMethod::SBVH(1e-5));
+    assert!(sbvh.sah_cost() < 0.85 * sah.sah_cost());
+    assert!(sbvh.primitives().len() > prims.len());
+    // An overlap threshold above the root area disables spatial splits
+    let no_spatial = BVHAccel::new(prims.clone(), 2, SplitMethod::SBVH(1.));
+    assert_eq!(no_spatial.primitives().len(), prims.len());
+
+    for _ in 0..500 {
+        let o = Point3f::new(rand() * 10. + 0.05, rand() * 10. + 0.05, -1.);
+        let d = Vector3f::new(rand() - 0.5, rand() - 0.5, 1.);
+        assert_eq!(closest_hit(&sbvh, &o, &d), closest_hit(&sah, &o, &d));
+    }
+}
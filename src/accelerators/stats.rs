@@ -0,0 +1,318 @@
+use std::cell::Cell;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::mem;
+use std::path::Path;
+
+use geometry::bounds::Bounds3f;
+use geometry::ray::Ray_;
+use primitives::Primitive;
+
+use super::bvh::BVHAccel;
+
+/// Receives the number of nodes each traversal of an accelerator visited.
+/// Accelerators default to `NoStats`, so the bookkeeping compiles away unless
+/// `TraversalCounters` is passed in.
+pub trait TraversalStats {
+    fn record_traversal(&self, nodes_visited: u64);
+
+    /// Number of rays traced so far and the nodes they visited in total.
+    fn traversals(&self) -> (u64, u64) {
+        (0, 0)
+    }
+}
+
+#[derive(Debug, Default, Copy, Clone)]
+pub struct NoStats;
+
+impl TraversalStats for NoStats {
+    #[inline(always)]
+    fn record_traversal(&self, _nodes_visited: u64) {}
+}
+
+#[derive(Debug, Default)]
+pub struct TraversalCounters {
+    rays_traced: Cell<u64>,
+    nodes_visited: Cell<u64>,
+}
+
+impl TraversalCounters {
+    pub fn new() -> TraversalCounters {
+        TraversalCounters::default()
+    }
+
+    /// Zero until rays have been traced.
+    pub fn avg_nodes_per_ray(&self) -> f32 {
+        match self.rays_traced.get() {
+            0 => 0.,
+            rays => self.nodes_visited.get() as f32 / rays as f32,
+        }
+    }
+
+    pub fn reset(&self) {
+        self.rays_traced.set(0);
+        self.nodes_visited.set(0);
+    }
+}
+
+impl TraversalStats for TraversalCounters {
+    fn record_traversal(&self, nodes_visited: u64) {
+        self.rays_traced.set(self.rays_traced.get() + 1);
+        self.nodes_visited.set(self.nodes_visited.get() + nodes_visited);
+    }
+
+    fn traversals(&self) -> (u64, u64) {
+        (self.rays_traced.get(), self.nodes_visited.get())
+    }
+}
+
+/// Summary of the shape of a BVH and of the traversals it has seen.
+#[derive(Debug, Clone)]
+pub struct BVHStats {
+    pub n_nodes: usize,
+    pub n_interior: usize,
+    pub n_leaves: usize,
+    /// Number of leaves holding `i` primitives at index `i`.
+    pub leaf_histogram: Vec<usize>,
+    pub sah_cost: f32,
+    pub max_depth: usize,
+    pub memory_bytes: usize,
+    /// Zero until rays have been traced through the tree, or if it does not
+    /// count them.
+    pub avg_nodes_per_ray: f32,
+}
+
+impl BVHStats {
+    pub fn new<S: TraversalStats>(bvh: &BVHAccel<S>) -> BVHStats {
+        let nodes = bvh.nodes();
+        let mut stats = BVHStats {
+            n_nodes: nodes.len(),
+            n_interior: 0,
+            n_leaves: 0,
+            leaf_histogram: Vec::new(),
+            sah_cost: bvh.sah_cost(),
+            max_depth: 0,
+            memory_bytes: mem::size_of_val(nodes)
+                + mem::size_of_val(bvh.primitives())
+                + mem::size_of_val(bvh.primitive_order()),
+            avg_nodes_per_ray: 0.,
+        };
+        let mut stack = Vec::new();
+        if !nodes.is_empty() {
+            stack.push((0, 1));
+        }
+        while let Some((i, depth)) = stack.pop() {
+            let node = &nodes[i];
+            stats.max_depth = stats.max_depth.max(depth);
+            if node.n_primitives > 0 {
+                let n = node.n_primitives as usize;
+                stats.n_leaves += 1;
+                if stats.leaf_histogram.len() <= n {
+                    stats.leaf_histogram.resize(n + 1, 0);
+                }
+                stats.leaf_histogram[n] += 1;
+            } else {
+                stats.n_interior += 1;
+                stack.push((i + 1, depth + 1));
+                stack.push((node.offset as usize, depth + 1));
+            }
+        }
+        let (rays, visited) = bvh.stats().traversals();
+        if rays > 0 {
+            stats.avg_nodes_per_ray = visited as f32 / rays as f32;
+        }
+        stats
+    }
+}
+
+impl fmt::Display for BVHStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "BVH: {} nodes ({} interior, {} leaves), depth {}, {:.1} KiB",
+            self.n_nodes,
+            self.n_interior,
+            self.n_leaves,
+            self.max_depth,
+            self.memory_bytes as f32 / 1024.
+        )?;
+        writeln!(
+            f,
+            "  SAH cost {:.3}, {:.2} nodes visited per ray",
+            self.sah_cost, self.avg_nodes_per_ray
+        )?;
+        for (n, &count) in self.leaf_histogram.iter().enumerate().filter(|&(_, &c)| c > 0) {
+            writeln!(f, "  leaves with {:3} primitives: {}", n, count)?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes the bounds of all nodes down to `max_depth` (the root being at
+/// depth one) as a wireframe OBJ, one group of twelve line elements per box.
+pub fn write_bounds_obj<S: TraversalStats>(bvh: &BVHAccel<S>, max_depth: usize, path: &Path) -> io::Result<()> {
+    let mut w = BufWriter::new(File::create(path)?);
+    let nodes = bvh.nodes();
+    let mut stack = Vec::new();
+    if !nodes.is_empty() {
+        stack.push((0, 1));
+    }
+    let mut n_boxes = 0;
+    while let Some((i, depth)) = stack.pop() {
+        if depth > max_depth {
+            continue;
+        }
+        write_box(&mut w, &nodes[i].bounds, n_boxes * 8 + 1, i, depth)?;
+        n_boxes += 1;
+        if nodes[i].n_primitives == 0 {
+            stack.push((nodes[i].offset as usize, depth + 1));
+            stack.push((i + 1, depth + 1));
+        }
+    }
+    w.flush()
+}
+
+fn write_box<W: Write>(w: &mut W, b: &Bounds3f, first_vertex: usize, node: usize, depth: usize) -> io::Result<()> {
+    const EDGES: [(usize, usize); 12] = [
+        (0, 1), (2, 3), (4, 5), (6, 7),
+        (0, 2), (1, 3), (4, 6), (5, 7),
+        (0, 4), (1, 5), (2, 6), (3, 7),
+    ];
+    writeln!(w, "g node{}_depth{}", node, depth)?;
+    for corner in 0..8u8 {
+        writeln!(w, "v {} {} {}", b[corner & 1].x, b[(corner >> 1) & 1].y, b[(corner >> 2) & 1].z)?;
+    }
+    for &(a, b) in EDGES.iter() {
+        writeln!(w, "l {} {}", first_vertex + a, first_vertex + b)?;
+    }
+    Ok(())
+}
+
+/// Traces one ray per pixel through `accel` and returns the number of nodes
+/// each visited, row by row, as recorded by the accelerator's `counters`.
+pub fn traversal_heat_map<P: Primitive, F: Fn(usize, usize) -> Ray_>(
+    accel: &P,
+    counters: &TraversalCounters,
+    width: usize,
+    height: usize,
+    ray_for_pixel: F,
+) -> Vec<u64> {
+    let mut steps = Vec::with_capacity(width * height);
+    for y in 0..height {
+        for x in 0..width {
+            let (_, before) = counters.traversals();
+            accel.intersect(&ray_for_pixel(x, y));
+            steps.push(counters.traversals().1 - before);
+        }
+    }
+    steps
+}
+
+/// Writes traversal steps as a binary PPM, mapping zero steps to black and
+/// the maximum to white through blue and red.
+pub fn write_heat_map(steps: &[u64], width: usize, height: usize, path: &Path) -> io::Result<()> {
+    assert_eq!(steps.len(), width * height);
+    let max = steps.iter().cloned().max().unwrap_or(0).max(1) as f32;
+    let mut w = BufWriter::new(File::create(path)?);
+    write!(w, "P6\n{} {}\n255\n", width, height)?;
+    for &s in steps {
+        let t = s as f32 / max * 3.;
+        let (blue, red, white) = (t.min(1.), (t - 1.).clamp(0., 1.), (t - 2.).clamp(0., 1.));
+        let rgb = [
+            (red * 255.) as u8,
+            (white * 255.) as u8,
+            ((blue - red + white) * 255.) as u8,
+        ];
+        w.write_all(&rgb)?;
+    }
+    w.flush()
+}
+
+#[test]
+fn stats_describe_tree_and_traversals() {
+    use std::fs;
+    use geometry::point::Point3f;
+    use geometry::vector::Vector3f;
+    use super::bvh::SplitMethod;
+    use super::test_util::triangle_primitives;
+
+    let mut p = Vec::new();
+    for i in 0..64 {
+        let c = Point3f::new((i % 8) as f32, (i / 8) as f32, 0.);
+        p.push(c);
+        p.push(c + Vector3f::new(0.9, 0., 0.));
+        p.push(c + Vector3f::new(0., 0.9, 0.));
+    }
+    let bvh = BVHAccel::with_stats(triangle_primitives(p), 4, SplitMethod::SAH, TraversalCounters::new());
+
+    let stats = BVHStats::new(&bvh);
+    assert_eq!(stats.n_nodes, stats.n_interior + stats.n_leaves);
+    assert_eq!(stats.n_leaves, stats.n_interior + 1);
+    let leaf_prims: usize = stats.leaf_histogram.iter().enumerate().map(|(n, c)| n * c).sum();
+    assert_eq!(leaf_prims, 64);
+    assert!(stats.max_depth >= 5 && stats.max_depth < 64);
+    assert_eq!(stats.avg_nodes_per_ray, 0.);
+
+    let steps = traversal_heat_map(&bvh, bvh.stats(), 16, 16, |x, y| {
+        let o = Point3f::new(x as f32 * 0.5 + 0.1, y as f32 * 0.5 + 0.1, -1.);
+        Ray_::new(&o, &Vector3f::new(0., 0., 1.), f32::INFINITY, 0., None)
+    });
+    assert!(steps.iter().all(|&s| s >= 1));
+    let stats = BVHStats::new(&bvh);
+    let total: u64 = steps.iter().sum();
+    assert_eq!(stats.avg_nodes_per_ray, total as f32 / 256.);
+
+    let dir = ::std::env::temp_dir();
+    let obj = dir.join(format!("pbrt_bvh_bounds_{}.obj", ::std::process::id()));
+    write_bounds_obj(&bvh, 3, &obj).unwrap();
+    let text = fs::read_to_string(&obj).unwrap();
+    assert_eq!(text.lines().filter(|l| l.starts_with("g ")).count(), 7);
+    assert_eq!(text.lines().filter(|l| l.starts_with("l ")).count(), 7 * 12);
+    let ppm = dir.join(format!("pbrt_bvh_heat_{}.ppm", ::std::process::id()));
+    write_heat_map(&steps, 16, 16, &ppm).unwrap();
+    assert_eq!(fs::read(&ppm).unwrap().len(), "P6\n16 16\n255\n".len() + 16 * 16 * 3);
+    fs::remove_file(&obj).unwrap();
+    fs::remove_file(&ppm).unwrap();
+}
+
+#[test]
+fn all_accelerators_count_traversals() {
+    use geometry::point::Point3f;
+    use geometry::vector::Vector3f;
+    use super::bvh::SplitMethod;
+    use super::kdtree::KdTreeAccel;
+    use super::test_util::{random_rays, random_triangles};
+    use super::wide::WideBVH;
+
+    let prims = random_triangles(200, 41);
+    let bvh = BVHAccel::with_stats(prims.clone(), 2, SplitMethod::SAH, TraversalCounters::new());
+    let kdtree = KdTreeAccel::with_stats(prims, 80, 1, 0.5, 1, -1, TraversalCounters::new());
+    let wide = WideBVH::<4, _>::with_stats(&bvh, TraversalCounters::new());
+    let rays = random_rays(100, 42);
+    let trace = |accel: &dyn Primitive| {
+        for (o, d) in &rays {
+            accel.intersect(&Ray_::new(o, d, f32::INFINITY, 0., None));
+            accel.intersect_p(&Ray_::new(o, d, f32::INFINITY, 0., None));
+        }
+    };
+    trace(&bvh);
+    trace(&kdtree);
+    trace(&wide);
+    for counters in &[bvh.stats(), kdtree.stats(), wide.stats()] {
+        let (rays_traced, nodes_visited) = counters.traversals();
+        assert_eq!(rays_traced, 200);
+        assert!(nodes_visited > rays_traced);
+    }
+    // Four children per node take fewer steps than two
+    assert!(wide.stats().avg_nodes_per_ray() < bvh.stats().avg_nodes_per_ray());
+
+    bvh.stats().reset();
+    assert_eq!(bvh.stats().traversals(), (0, 0));
+    assert_eq!(bvh.stats().avg_nodes_per_ray(), 0.);
+    let steps = traversal_heat_map(&kdtree, kdtree.stats(), 4, 4, |x, y| {
+        let o = Point3f::new(x as f32 - 2., y as f32 - 2., -10.);
+        Ray_::new(&o, &Vector3f::new(0., 0., 1.), f32::INFINITY, 0., None)
+    });
+    assert_eq!(steps.len(), 16);
+}
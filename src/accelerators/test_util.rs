@@ -0,0 +1,72 @@
+use std::rc::Rc;
+
+use geometry::point::Point3f;
+use geometry::ray::Ray_;
+use geometry::transform::{Matrix4, Transform};
+use geometry::vector::Vector3f;
+use primitives::{GeometricPrimitive, Primitive};
+use shapes::triangle::{create_triangle_mesh, TriangleMesh};
+
+/// Linear congruential generator returning floats in [0, 1).
+pub fn lcg(seed: u32) -> impl FnMut() -> f32 {
+    let mut seed = seed;
+    move || {
+        seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+        (seed >> 8) as f32 / (1 << 24) as f32
+    }
+}
+
+/// Vertices of `n` triangles of about `size` scattered over [0, 10]^3.
+pub fn random_triangle_points(n: usize, size: f32, rand: &mut dyn FnMut() -> f32) -> Vec<Point3f> {
+    let mut p = Vec::new();
+    for _ in 0..n {
+        let c = Point3f::new(rand() * 10., rand() * 10., rand() * 10.);
+        for _ in 0..3 {
+            p.push(c + Vector3f::new(rand() - 0.5, rand() - 0.5, rand() - 0.5) * size);
+        }
+    }
+    p
+}
+
+pub fn mesh_primitives(mesh: TriangleMesh) -> Vec<Rc<dyn Primitive>> {
+    create_triangle_mesh(Rc::new(mesh))
+        .into_iter()
+        .map(|shape| Rc::new(GeometricPrimitive::new(shape)) as Rc<dyn Primitive>)
+        .collect()
+}
+
+/// One primitive per consecutive vertex triple, in world space.
+pub fn triangle_primitives(p: Vec<Point3f>) -> Vec<Rc<dyn Primitive>> {
+    let identity = Transform::new(Matrix4::new()).unwrap();
+    let indices = (0..p.len()).collect();
+    mesh_primitives(TriangleMesh::new(&identity, false, indices, p, None, None))
+}
+
+pub fn random_triangles(n: usize, seed: u32) -> Vec<Rc<dyn Primitive>> {
+    triangle_primitives(random_triangle_points(n, 1., &mut lcg(seed)))
+}
+
+/// Rays starting below the scene and heading roughly along +z.
+pub fn random_rays(n: usize, seed: u32) -> Vec<(Point3f, Vector3f)> {
+    let mut rand = lcg(seed);
+    (0..n)
+        .map(|_| {
+            let o = Point3f::new(rand() * 12. - 1., rand() * 12. - 1., -1.);
+            (o, Vector3f::new(rand() - 0.5, rand() - 0.5, 1.))
+        })
+        .collect()
+}
+
+/// Distance to the closest hit, if any.
+pub fn closest_hit(accel: &dyn Primitive, o: &Point3f, d: &Vector3f) -> Option<f32> {
+    let ray = Ray_::new(o, d, f32::INFINITY, 0., None);
+    accel.intersect(&ray).map(|_| ray.tmax.get())
+}
+
+/// Closest hit found by testing every primitive.
+pub fn brute_force_hit(prims: &[Rc<dyn Primitive>], o: &Point3f, d: &Vector3f) -> Option<f32> {
+    let ray = Ray_::new(o, d, f32::INFINITY, 0., None);
+    prims
+        .iter()
+        .fold(None, |best, prim| prim.intersect(&ray).map(|_| ray.tmax.get()).or(best))
+}
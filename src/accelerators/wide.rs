@@ -0,0 +1,385 @@
+use std::rc::Rc;
+
+use geometry::bounds::{Bounds, Bounds3f};
+use geometry::interaction::SurfaceInteraction;
+use geometry::ray::Ray_;
+use primitives::Primitive;
+use util::gamma;
+
+use super::bvh::{ray_inv_dir, BVHAccel, LinearBVHNode};
+use super::stats::{NoStats, TraversalStats};
+
+const EMPTY: u32 = u32::MAX;
+/// Each level of the 64 levels the binary build supports defers at most
+/// seven children of an 8-wide node, leaves included.
+const MAX_STACK: usize = 64 * 7 + 1;
+
+/// Node with up to `N` children whose bounds are stored per axis, so a ray
+/// can be tested against all of them in one pass over the arrays.
+#[derive(Debug, Clone)]
+pub struct WideBVHNode<const N: usize> {
+    pub bounds_min: [[f32; N]; 3],
+    pub bounds_max: [[f32; N]; 3],
+    /// Node index of an interior child or first primitive of a leaf child,
+    /// `u32::MAX` for unused slots.
+    pub child: [u32; N],
+    /// Primitive count of leaf children, zero for interior children.
+    pub n_primitives: [u16; N],
+}
+
+impl<const N: usize> WideBVHNode<N> {
+    fn empty() -> WideBVHNode<N> {
+        WideBVHNode {
+            bounds_min: [[f32::INFINITY; N]; 3],
+            bounds_max: [[f32::NEG_INFINITY; N]; 3],
+            child: [EMPTY; N],
+            n_primitives: [0; N],
+        }
+    }
+
+    fn set_child(&mut self, i: usize, bounds: &Bounds3f, child: u32, n_primitives: u16) {
+        for axis in 0..3u8 {
+            self.bounds_min[axis as usize][i] = bounds[0][axis];
+            self.bounds_max[axis as usize][i] = bounds[1][axis];
+        }
+        self.child[i] = child;
+        self.n_primitives[i] = n_primitives;
+    }
+
+    pub fn n_children(&self) -> usize {
+        self.child.iter().filter(|&&c| c != EMPTY).count()
+    }
+
+    /// Slab test against all child boxes, returning the entry distance of
+    /// every child hit and `INFINITY` for the others. Groups of four
+    /// children are tested in one SSE register where available.
+    fn intersect_children(&self, ray: &RayLanes, t_max: f32) -> [f32; N] {
+        let mut hits = [f32::INFINITY; N];
+        #[allow(unused_mut)]
+        let mut start = 0;
+        #[cfg(all(target_arch = "x86_64", target_feature = "sse"))]
+        while start + 4 <= N {
+            sse::intersect_children4(self, start, ray, t_max, &mut hits[start..start + 4]);
+            start += 4;
+        }
+        for (i, hit) in hits.iter_mut().enumerate().skip(start) {
+            let mut t0 = 0f32;
+            let mut t1 = t_max;
+            for axis in 0..3 {
+                let (near, far) = if ray.dir_is_neg[axis] {
+                    (self.bounds_max[axis][i], self.bounds_min[axis][i])
+                } else {
+                    (self.bounds_min[axis][i], self.bounds_max[axis][i])
+                };
+                let t_near = (near - ray.o[axis]) * ray.inv_dir[axis];
+                let t_far = (far - ray.o[axis]) * ray.inv_dir[axis] * ray.far_scale;
+                t0 = t0.max(t_near);
+                t1 = t1.min(t_far);
+            }
+            if t0 <= t1 {
+                *hit = t0;
+            }
+        }
+        hits
+    }
+}
+
+/// Per axis ray data used by the box tests.
+struct RayLanes {
+    o: [f32; 3],
+    inv_dir: [f32; 3],
+    dir_is_neg: [bool; 3],
+    /// Factor widening the far slab distances to cover rounding errors.
+    far_scale: f32,
+}
+
+impl RayLanes {
+    fn new(ray: &Ray_) -> RayLanes {
+        let (inv, dir_is_neg) = ray_inv_dir(ray);
+        RayLanes {
+            o: [ray.o.x, ray.o.y, ray.o.z],
+            inv_dir: [inv.x, inv.y, inv.z],
+            dir_is_neg: [dir_is_neg[0] != 0, dir_is_neg[1] != 0, dir_is_neg[2] != 0],
+            far_scale: 1. + 2. * gamma(3),
+        }
+    }
+}
+
+#[cfg(all(target_arch = "x86_64", target_feature = "sse"))]
+mod sse {
+    use std::arch::x86_64::*;
+
+    use super::{RayLanes, WideBVHNode};
+
+    /// Tests children `start..start + 4` of `node`, with the same results
+    /// as the scalar loop: a NaN distance from a ray in a slab plane is
+    /// ignored by taking the other operand of min and max.
+    pub fn intersect_children4<const N: usize>(
+        node: &WideBVHNode<N>,
+        start: usize,
+        ray: &RayLanes,
+        t_max: f32,
+        hits: &mut [f32],
+    ) {
+        assert!(start + 4 <= N && hits.len() == 4);
+        unsafe {
+            let mut t0 = _mm_setzero_ps();
+            let mut t1 = _mm_set1_ps(t_max);
+            for axis in 0..3 {
+                let (near, far) = if ray.dir_is_neg[axis] {
+                    (&node.bounds_max[axis], &node.bounds_min[axis])
+                } else {
+                    (&node.bounds_min[axis], &node.bounds_max[axis])
+                };
+                let o = _mm_set1_ps(ray.o[axis]);
+                let inv_dir = _mm_set1_ps(ray.inv_dir[axis]);
+                let t_near = _mm_mul_ps(_mm_sub_ps(_mm_loadu_ps(near.as_ptr().add(start)), o), inv_dir);
+                let t_far = _mm_mul_ps(
+                    _mm_mul_ps(_mm_sub_ps(_mm_loadu_ps(far.as_ptr().add(start)), o), inv_dir),
+                    _mm_set1_ps(ray.far_scale),
+                );
+                t0 = _mm_max_ps(t_near, t0);
+                t1 = _mm_min_ps(t_far, t1);
+            }
+            let hit = _mm_cmple_ps(t0, t1);
+            let entry = _mm_or_ps(_mm_and_ps(hit, t0), _mm_andnot_ps(hit, _mm_set1_ps(f32::INFINITY)));
+            _mm_storeu_ps(hits.as_mut_ptr(), entry);
+        }
+    }
+}
+
+/// BVH with `N` children per node, built by collapsing a binary `BVHAccel`.
+/// Traversals are reported to `S`, which by default discards them.
+pub struct WideBVH<const N: usize, S = NoStats> {
+    primitives: Vec<Rc<dyn Primitive>>,
+    nodes: Vec<WideBVHNode<N>>,
+    bounds: Bounds3f,
+    stats: S,
+}
+
+pub type BVH4 = WideBVH<4>;
+pub type BVH8 = WideBVH<8>;
+
+impl<const N: usize> WideBVH<N> {
+    pub fn new<T: TraversalStats>(bvh: &BVHAccel<T>) -> WideBVH<N> {
+        WideBVH::with_stats(bvh, NoStats)
+    }
+}
+
+impl<const N: usize, S: TraversalStats> WideBVH<N, S> {
+    pub fn with_stats<T: TraversalStats>(bvh: &BVHAccel<T>, stats: S) -> WideBVH<N, S> {
+        assert!(N >= 2);
+        let binary = bvh.nodes();
+        let mut wide = WideBVH {
+            primitives: bvh.primitives().to_vec(),
+            nodes: Vec::new(),
+            bounds: bvh.world_bound(),
+            stats,
+        };
+        if binary.is_empty() {
+            return wide;
+        }
+        if binary[0].n_primitives > 0 {
+            let mut root = WideBVHNode::empty();
+            root.set_child(0, &binary[0].bounds, binary[0].offset, binary[0].n_primitives);
+            wide.nodes.push(root);
+        } else {
+            wide.collapse(binary, 0);
+        }
+        wide
+    }
+
+    /// Pulls up grandchildren of `node` until it has `N` children, always
+    /// opening the interior child with the largest surface area.
+    fn collapse(&mut self, binary: &[LinearBVHNode], node: usize) -> u32 {
+        let mut children = vec![node + 1, binary[node].offset as usize];
+        while children.len() < N {
+            let mut largest: Option<(usize, f32)> = None;
+            for (i, &c) in children.iter().enumerate() {
+                if binary[c].n_primitives > 0 {
+                    continue;
+                }
+                let area = binary[c].bounds.surface_area();
+                if largest.is_none_or(|(_, a)| area > a) {
+                    largest = Some((i, area));
+                }
+            }
+            match largest {
+                Some((i, _)) => {
+                    let c = children.swap_remove(i);
+                    children.push(c + 1);
+                    children.push(binary[c].offset as usize);
+                }
+                None => break,
+            }
+        }
+
+        let index = self.nodes.len();
+        self.nodes.push(WideBVHNode::empty());
+        for (i, &c) in children.iter().enumerate() {
+            let (child, n_primitives) = if binary[c].n_primitives > 0 {
+                (binary[c].offset, binary[c].n_primitives)
+            } else {
+                (self.collapse(binary, c), 0)
+            };
+            self.nodes[index].set_child(i, &binary[c].bounds, child, n_primitives);
+        }
+        index as u32
+    }
+
+    pub fn nodes(&self) -> &[WideBVHNode<N>] {
+        &self.nodes
+    }
+
+    pub fn stats(&self) -> &S {
+        &self.stats
+    }
+
+    /// Visits the children hit by `ray` nearest first. `visit_leaf` returns
+    /// true to stop the traversal.
+    fn traverse<F: FnMut(&[Rc<dyn Primitive>]) -> bool>(&self, ray: &Ray_, mut visit_leaf: F) {
+        if self.nodes.is_empty() {
+            return;
+        }
+        let lanes = RayLanes::new(ray);
+        // Interior nodes and leaves waiting to be visited with their entry
+        // distance, the nearest on top
+        let mut stack = [StackEntry { child: 0, n_primitives: 0, t_entry: 0. }; MAX_STACK];
+        let mut stack_len = 1;
+        let mut visited = 0;
+        while stack_len > 0 {
+            stack_len -= 1;
+            let entry = stack[stack_len];
+            if entry.t_entry > ray.tmax.get() {
+                continue;
+            }
+            visited += 1;
+            if entry.n_primitives > 0 {
+                let first = entry.child as usize;
+                if visit_leaf(&self.primitives[first..first + entry.n_primitives as usize]) {
+                    break;
+                }
+                continue;
+            }
+            let node = &self.nodes[entry.child as usize];
+            let hits = node.intersect_children(&lanes, ray.tmax.get());
+
+            // Insert the children hit far to near above the entries already
+            // on the stack
+            let base = stack_len;
+            for (i, &t_entry) in hits.iter().enumerate() {
+                if node.child[i] == EMPTY || t_entry == f32::INFINITY {
+                    continue;
+                }
+                let entry = StackEntry { child: node.child[i], n_primitives: node.n_primitives[i], t_entry };
+                let mut j = stack_len;
+                while j > base && stack[j - 1].t_entry < t_entry {
+                    stack[j] = stack[j - 1];
+                    j -= 1;
+                }
+                stack[j] = entry;
+                stack_len += 1;
+            }
+        }
+        self.stats.record_traversal(visited);
+    }
+}
+
+#[derive(Copy, Clone)]
+struct StackEntry {
+    child: u32,
+    n_primitives: u16,
+    t_entry: f32,
+}
+
+impl<const N: usize, S: TraversalStats> Primitive for WideBVH<N, S> {
+    fn world_bound(&self) -> Bounds3f {
+        self.bounds
+    }
+
+    fn intersect(&self, ray: &Ray_) -> Option<SurfaceInteraction> {
+        let mut isect = None;
+        self.traverse(ray, |prims| {
+            for prim in prims {
+                if let Some(si) = prim.intersect(ray) {
+                    isect = Some(si);
+                }
+            }
+            false
+        });
+        isect
+    }
+
+    fn intersect_p(&self, ray: &Ray_) -> bool {
+        let mut hit = false;
+        self.traverse(ray, |prims| {
+            hit = prims.iter().any(|prim| prim.intersect_p(ray));
+            hit
+        });
+        hit
+    }
+}
+
+#[test]
+fn wide_bvh_matches_binary() {
+    use super::bvh::SplitMethod;
+    use super::test_util::{random_rays, random_triangles};
+
+    let bvh = BVHAccel::new(random_triangles(300, 7), 2, SplitMethod::SAH);
+    let bvh4 = BVH4::new(&bvh);
+    let bvh8 = BVH8::new(&bvh);
+    // Widths that are not a multiple of the SIMD width test some children
+    // in the scalar loop
+    let bvh6 = WideBVH::<6>::new(&bvh);
+    let bvh2 = WideBVH::<2>::new(&bvh);
+    assert!(bvh4.nodes().len() < bvh.nodes().len() / 2);
+    assert!(bvh8.nodes().len() < bvh4.nodes().len());
+    for (o, d) in random_rays(500, 9) {
+        let ray = Ray_::new(&o, &d, f32::INFINITY, 0., None);
+        let expected = bvh.intersect(&ray).map(|_| ray.tmax.get());
+        let wide: [&dyn Primitive; 4] = [&bvh4, &bvh8, &bvh6, &bvh2];
+        for accel in wide.iter() {
+            let ray = Ray_::new(&o, &d, f32::INFINITY, 0., None);
+            assert_eq!(accel.intersect(&ray).map(|_| ray.tmax.get()), expected);
+            let shadow_ray = Ray_::new(&o, &d, f32::INFINITY, 0., None);
+            assert_eq!(accel.intersect_p(&shadow_ray), expected.is_some());
+        }
+    }
+}
+
+/// Run with `cargo test --release wide_bvh_benchmark -- --ignored --nocapture`.
+#[test]
+#[ignore]
+fn wide_bvh_benchmark() {
+    use std::time::Instant;
+    use super::bvh::SplitMethod;
+    use super::test_util::{random_rays, random_triangles};
+
+    let bvh = BVHAccel::new(random_triangles(100_000, 31), 4, SplitMethod::SAH);
+    let bvh4 = BVH4::new(&bvh);
+    let bvh8 = BVH8::new(&bvh);
+    let rays = random_rays(200_000, 37);
+    let accels: [(&str, &dyn Primitive, usize); 3] = [
+        ("binary", &bvh, bvh.nodes().len()),
+        ("4-wide", &bvh4, bvh4.nodes().len()),
+        ("8-wide", &bvh8, bvh8.nodes().len()),
+    ];
+    for &(name, accel, n_nodes) in accels.iter() {
+        let start = Instant::now();
+        let mut n_hits = 0;
+        for &(o, d) in &rays {
+            let ray = Ray_::new(&o, &d, f32::INFINITY, 0., None);
+            if accel.intersect(&ray).is_some() {
+                n_hits += 1;
+            }
+        }
+        let seconds = start.elapsed().as_secs_f64();
+        println!(
+            "{}: {} nodes, {} hits, {:.0} rays/s",
+            name,
+            n_nodes,
+            n_hits,
+            rays.len() as f64 / seconds
+        );
+    }
+}
@@ -0,0 +1,113 @@
+use std::f32::consts::PI;
+
+use geometry::point::{Point2f, Point3f};
+use geometry::ray::Ray_;
+use geometry::vector::Vector3f;
+use sampling::equal_area_square_to_sphere;
+
+use super::{Camera, CameraBase, CameraSample};
+
+/// How raster positions map to directions on the sphere.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum EnvironmentMapping {
+    /// x spans the azimuth and y the polar angle from +y down to -y.
+    LatLong,
+    /// Equal-area octahedral mapping, best used with a square film.
+    EqualAreaOctahedral,
+}
+
+/// Captures the full sphere of directions around the camera position, e.g.
+/// for rendering environment maps.
+pub struct EnvironmentCamera {
+    base: CameraBase,
+    resolution: Point2f,
+    mapping: EnvironmentMapping,
+}
+
+impl EnvironmentCamera {
+    pub fn new(
+        base: CameraBase,
+        resolution: &Point2f,
+        mapping: EnvironmentMapping,
+    ) -> EnvironmentCamera {
+        EnvironmentCamera {
+            base,
+            resolution: *resolution,
+            mapping,
+        }
+    }
+
+    /// Camera space direction seen at a raster position.
+    pub fn direction(&self, p_film: &Point2f) -> Vector3f {
+        let uv = Point2f::new(p_film.x / self.resolution.x, p_film.y / self.resolution.y);
+        match self.mapping {
+            EnvironmentMapping::LatLong => {
+                let theta = PI * uv.y;
+                let phi = 2. * PI * uv.x;
+                Vector3f::new(theta.sin() * phi.cos(), theta.cos(), theta.sin() * phi.sin())
+            }
+            EnvironmentMapping::EqualAreaOctahedral => {
+                let uv = Point2f::new(uv.x.clamp(0., 1.), uv.y.clamp(0., 1.));
+                let d = equal_area_square_to_sphere(&uv);
+                // Keep +y as the up direction like the lat-long mapping
+                Vector3f::new(d.x, d.z, d.y)
+            }
+        }
+    }
+}
+
+impl Camera for EnvironmentCamera {
+    fn generate_ray(&self, sample: &CameraSample) -> Option<(Ray_, f32)> {
+        let time = self.base.ray_time(sample, &self.resolution);
+        let d = self.direction(&sample.p_film);
+        let ray = Ray_::new(&Point3f::new(0., 0., 0.), &d, f32::INFINITY, time, None);
+        Some((self.base.to_world(&ray), 1.))
+    }
+
+    fn shutter_open(&self) -> f32 {
+        self.base.shutter.open
+    }
+
+    fn shutter_close(&self) -> f32 {
+        self.base.shutter.end_time()
+    }
+}
+
+#[test]
+fn environment_mappings_cover_the_sphere() {
+    use geometry::Metric;
+    use geometry::transform::Transform;
+
+    let camera_to_world = Transform::translate(&Vector3f::new(1., 2., 3.));
+    let sample = |x: f32, y: f32| CameraSample {
+        p_film: Point2f::new(x, y),
+        p_lens: Point2f::new(0.5, 0.5),
+        time: 0.,
+    };
+
+    let latlong = EnvironmentCamera::new(CameraBase::new(&camera_to_world, 0., 1.), &Point2f::new(360., 180.), EnvironmentMapping::LatLong);
+    let (top, _) = latlong.generate_ray(&sample(100., 0.)).unwrap();
+    assert!((top.o - Point3f::new(1., 2., 3.)).norm() < 1e-6);
+    assert!((top.d - Vector3f::new(0., 1., 0.)).norm() < 1e-5);
+    let (horizon, _) = latlong.generate_ray(&sample(90., 90.)).unwrap();
+    assert!((horizon.d - Vector3f::new(0., 0., 1.)).norm() < 1e-5);
+
+    // Equal areas of the square map to equal solid angles: the cap above
+    // y = 0.5 covers a quarter of the sphere
+    let octahedral = EnvironmentCamera::new(CameraBase::new(&camera_to_world, 0., 1.), &Point2f::new(256., 256.), EnvironmentMapping::EqualAreaOctahedral);
+    let mut n_cap = 0;
+    for y in 0..256 {
+        for x in 0..256 {
+            let (ray, _) = octahedral.generate_ray(&sample(x as f32 + 0.5, y as f32 + 0.5)).unwrap();
+            assert!((ray.d.length_squared() - 1.).abs() < 1e-4);
+            if ray.d.y > 0.5 {
+                n_cap += 1;
+            }
+        }
+    }
+    assert!((n_cap as f32 / (256. * 256.) - 0.25).abs() < 0.01);
+
+    let (rd, _) = octahedral.generate_ray_differential(&sample(40.5, 200.5)).unwrap();
+    assert!(rd.has_differential);
+    assert!((rd.rx_dir - rd.ray.d).norm() > 0. && (rd.rx_dir - rd.ray.d).norm() < 0.05);
+}
@@ -0,0 +1,137 @@
+use geometry::point::{Point2f, Point3f};
+use geometry::ray::Ray_;
+use geometry::vector::Vector3f;
+
+use super::{Camera, CameraBase, CameraSample};
+
+/// How the angle from the optical axis maps to the distance from the center
+/// of the image circle.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum FisheyeProjection {
+    /// Distance proportional to the angle.
+    Equidistant,
+    /// Distance proportional to `sin(theta / 2)`, preserving solid angle.
+    Equisolid,
+    /// Distance proportional to `tan(theta / 2)`, preserving local shapes.
+    /// Needs a field of view below 360 degrees.
+    Stereographic,
+}
+
+/// Fisheye lens whose image circle is inscribed in the shorter axis of the
+/// film. Samples outside the circle produce no ray.
+pub struct FisheyeCamera {
+    base: CameraBase,
+    resolution: Point2f,
+    projection: FisheyeProjection,
+    theta_max: f32,
+}
+
+impl FisheyeCamera {
+    /// `fov` is the angle in degrees across the diameter of the image circle.
+    pub fn new(
+        base: CameraBase,
+        resolution: &Point2f,
+        fov: f32,
+        projection: FisheyeProjection,
+    ) -> FisheyeCamera {
+        let max_fov = if projection == FisheyeProjection::Stereographic {
+            359.
+        } else {
+            360.
+        };
+        FisheyeCamera {
+            base,
+            resolution: *resolution,
+            projection,
+            theta_max: fov.clamp(0., max_fov).to_radians() / 2.,
+        }
+    }
+
+    /// Camera space direction seen at a raster position, if it lies inside
+    /// the image circle.
+    pub fn direction(&self, p_film: &Point2f) -> Option<Vector3f> {
+        let radius = self.resolution.x.min(self.resolution.y) / 2.;
+        let x = (p_film.x - self.resolution.x / 2.) / radius;
+        let y = (p_film.y - self.resolution.y / 2.) / radius;
+        let r = (x * x + y * y).sqrt();
+        if r > 1. {
+            return None;
+        }
+        let theta = match self.projection {
+            FisheyeProjection::Equidistant => r * self.theta_max,
+            FisheyeProjection::Equisolid => 2. * (r * (self.theta_max / 2.).sin()).asin(),
+            FisheyeProjection::Stereographic => 2. * (r * (self.theta_max / 2.).tan()).atan(),
+        };
+        if r == 0. {
+            return Some(Vector3f::new(0., 0., 1.));
+        }
+        // Raster y points down, camera y up
+        let sin_theta = theta.sin();
+        Some(Vector3f::new(sin_theta * x / r, -sin_theta * y / r, theta.cos()))
+    }
+}
+
+impl Camera for FisheyeCamera {
+    fn generate_ray(&self, sample: &CameraSample) -> Option<(Ray_, f32)> {
+        let d = self.direction(&sample.p_film)?;
+        let time = self.base.ray_time(sample, &self.resolution);
+        let ray = Ray_::new(&Point3f::new(0., 0., 0.), &d, f32::INFINITY, time, None);
+        Some((self.base.to_world(&ray), 1.))
+    }
+
+    fn shutter_open(&self) -> f32 {
+        self.base.shutter.open
+    }
+
+    fn shutter_close(&self) -> f32 {
+        self.base.shutter.end_time()
+    }
+}
+
+#[test]
+fn fisheye_projections_map_radius_to_angle() {
+    use geometry::{Metric, VectorSpace};
+    use geometry::transform::Transform;
+
+    let identity = Transform::translate(&Vector3f::new(0., 0., 0.));
+    let resolution = Point2f::new(300., 200.);
+    let sample = |x: f32, y: f32| CameraSample {
+        p_film: Point2f::new(x, y),
+        p_lens: Point2f::new(0.5, 0.5),
+        time: 0.,
+    };
+    let axis = Vector3f::new(0., 0., 1.);
+    let angle = |d: &Vector3f| d.dot(&axis).clamp(-1., 1.).acos().to_degrees();
+
+    for &projection in [
+        FisheyeProjection::Equidistant,
+        FisheyeProjection::Equisolid,
+        FisheyeProjection::Stereographic,
+    ].iter()
+    {
+        let camera = FisheyeCamera::new(CameraBase::new(&identity, 0., 1.), &resolution, 180., projection);
+        let (center, _) = camera.generate_ray(&sample(150., 100.)).unwrap();
+        assert!((center.d - axis).norm() < 1e-6);
+        // The rim of the image circle sees half the field of view
+        let (rim, _) = camera.generate_ray(&sample(150., 0.)).unwrap();
+        assert!((angle(&rim.d) - 90.).abs() < 1e-3);
+        assert!(rim.d.y > 0.99);
+        let (left, _) = camera.generate_ray(&sample(50., 100.)).unwrap();
+        assert!(left.d.x < -0.99);
+        assert!(camera.generate_ray(&sample(10., 10.)).is_none());
+        assert!(camera.generate_ray(&sample(260., 100.)).is_none());
+
+        // Halfway to the rim the projections differ
+        let (half, _) = camera.generate_ray(&sample(200., 100.)).unwrap();
+        let expected = match projection {
+            FisheyeProjection::Equidistant => 45.,
+            FisheyeProjection::Equisolid => 2. * (0.5 * 45f32.to_radians().sin()).asin().to_degrees(),
+            FisheyeProjection::Stereographic => 2. * (0.5f32).atan().to_degrees(),
+        };
+        assert!((angle(&half.d) - expected).abs() < 1e-3);
+    }
+
+    let wide = FisheyeCamera::new(CameraBase::new(&identity, 0., 1.), &resolution, 360., FisheyeProjection::Equidistant);
+    let (rim, _) = wide.generate_ray(&sample(250., 100.)).unwrap();
+    assert!((rim.d + axis).norm() < 1e-5);
+}
@@ -0,0 +1,167 @@
+pub mod environment;
+pub mod fisheye;
+pub mod orthographic;
+pub mod perspective;
+pub mod realistic;
+pub mod shutter;
+pub mod stereo;
+
+use geometry::Point;
+use geometry::bounds::Bounds2f;
+use geometry::point::{Point2f, Point3f};
+use geometry::ray::{RayDifferential, Ray_};
+use geometry::transform::{AnimatedTransform, Transform};
+use geometry::vector::Vector3f;
+
+use self::shutter::{Shutter, ShutterCurve};
+
+/// Film position, lens position and time in [0, 1) used to generate one
+/// camera ray.
+#[derive(Debug, Copy, Clone)]
+pub struct CameraSample {
+    pub p_film: Point2f,
+    pub p_lens: Point2f,
+    pub time: f32,
+}
+
+pub trait Camera {
+    /// Returns the world space ray for `sample` together with the weight of
+    /// the radiance arriving along it.
+    fn generate_ray(&self, sample: &CameraSample) -> Option<(Ray_, f32)>;
+
+    /// Like `generate_ray`, adding the rays for samples one pixel over in x
+    /// and y. Cameras that can compute them directly should override this.
+    fn generate_ray_differential(&self, sample: &CameraSample) -> Option<(RayDifferential, f32)> {
+        let (ray, weight) = self.generate_ray(sample)?;
+        let mut rd = RayDifferential::new(&ray);
+        let mut shifted = *sample;
+        shifted.p_film.x += 1.;
+        let rx = self.generate_ray(&shifted);
+        shifted.p_film.x -= 1.;
+        shifted.p_film.y += 1.;
+        let ry = self.generate_ray(&shifted);
+        if let (Some((rx, _)), Some((ry, _))) = (rx, ry) {
+            rd.rx_origin = rx.o;
+            rd.rx_dir = rx.d;
+            rd.ry_origin = ry.o;
+            rd.ry_dir = ry.d;
+            rd.has_differential = true;
+        }
+        Some((rd, weight))
+    }
+
+    fn shutter_open(&self) -> f32;
+    fn shutter_close(&self) -> f32;
+}
+
+/// Screen window of an image with the given resolution, spanning [-1, 1]
+/// along its shorter axis.
+pub fn default_screen_window(resolution: &Point2f) -> Bounds2f {
+    let aspect = resolution.x / resolution.y;
+    if aspect > 1. {
+        Bounds2f::from((Point2f::new(-aspect, -1.), Point2f::new(aspect, 1.)))
+    } else {
+        Bounds2f::from((Point2f::new(-1., -1. / aspect), Point2f::new(1., 1. / aspect)))
+    }
+}
+
+/// Placement of the camera over time and the shutter that decides when
+/// each sample is exposed, shared by all cameras.
+#[derive(Debug, Clone)]
+pub struct CameraBase {
+    pub camera_to_world: AnimatedTransform,
+    pub shutter: Shutter,
+}
+
+impl CameraBase {
+    /// Static camera with a box shutter open from `shutter_open` to
+    /// `shutter_close`.
+    pub fn new(camera_to_world: &Transform<f32>, shutter_open: f32, shutter_close: f32) -> CameraBase {
+        CameraBase {
+            camera_to_world: AnimatedTransform::new(camera_to_world, shutter_open, camera_to_world, shutter_close),
+            shutter: Shutter::new(shutter_open, shutter_close, ShutterCurve::Box),
+        }
+    }
+
+    pub fn animated(camera_to_world: AnimatedTransform, shutter: Shutter) -> CameraBase {
+        CameraBase {
+            camera_to_world,
+            shutter,
+        }
+    }
+
+    /// Exposure time of `sample` on a film of the given resolution.
+    pub fn ray_time(&self, sample: &CameraSample, resolution: &Point2f) -> f32 {
+        self.shutter.sample_time(sample.time, sample.p_film.y / resolution.y)
+    }
+
+    /// Moves a camera space ray to world space at the time it carries.
+    pub fn to_world(&self, ray: &Ray_) -> Ray_ {
+        self.camera_to_world.transform_ray(ray)
+    }
+
+    pub fn differential_to_world(&self, rd: &RayDifferential) -> RayDifferential {
+        self.camera_to_world.transform_ray_differential(rd)
+    }
+}
+
+/// State shared by cameras that map the film through a projective
+/// transformation: raster space, with y pointing down, to screen space on
+/// the `screen_window` and from there to camera space.
+#[derive(Debug, Clone)]
+pub struct ProjectiveCamera {
+    pub base: CameraBase,
+    pub resolution: Point2f,
+    pub camera_to_screen: Transform<f32>,
+    pub screen_to_raster: Transform<f32>,
+    pub raster_to_screen: Transform<f32>,
+    pub raster_to_camera: Transform<f32>,
+    pub lens_radius: f32,
+    pub focal_distance: f32,
+}
+
+impl ProjectiveCamera {
+    pub fn new(
+        base: CameraBase,
+        camera_to_screen: &Transform<f32>,
+        resolution: &Point2f,
+        screen_window: &Bounds2f,
+        lens_radius: f32,
+        focal_distance: f32,
+    ) -> ProjectiveCamera {
+        let (s_min, s_max) = (screen_window[0], screen_window[1]);
+        let screen_to_raster = Transform::scale(resolution.x, resolution.y, 1.)
+            * Transform::scale(1. / (s_max.x - s_min.x), 1. / (s_min.y - s_max.y), 1.)
+            * Transform::translate(&Vector3f::new(-s_min.x, -s_max.y, 0.));
+        let raster_to_screen = screen_to_raster.inverse();
+        let raster_to_camera = &camera_to_screen.inverse() * &raster_to_screen;
+        ProjectiveCamera {
+            base,
+            resolution: *resolution,
+            camera_to_screen: camera_to_screen.clone(),
+            screen_to_raster,
+            raster_to_screen,
+            raster_to_camera,
+            lens_radius,
+            focal_distance,
+        }
+    }
+
+    /// Camera space position of a film sample.
+    pub fn film_to_camera(&self, p_film: &Point2f) -> Point3f {
+        &self.raster_to_camera * Point3f::new(p_film.x, p_film.y, 0.)
+    }
+
+    /// Camera space offsets of one pixel step in x and y on the film.
+    pub fn pixel_offsets(&self) -> (Vector3f, Vector3f) {
+        let origin = self.film_to_camera(&Point2f::zero());
+        (
+            self.film_to_camera(&Point2f::new(1., 0.)) - origin,
+            self.film_to_camera(&Point2f::new(0., 1.)) - origin,
+        )
+    }
+
+    pub fn ray_time(&self, sample: &CameraSample) -> f32 {
+        self.base.ray_time(sample, &self.resolution)
+    }
+}
@@ -0,0 +1,134 @@
+use geometry::Vector;
+use geometry::bounds::Bounds2f;
+use geometry::point::{Point2f, Point3f};
+use geometry::ray::{RayDifferential, Ray_};
+use geometry::transform::Transform;
+use geometry::vector::Vector3f;
+use sampling::concentric_sample_disk;
+
+use super::{Camera, CameraBase, CameraSample, ProjectiveCamera};
+
+/// Parallel projection of the screen window onto the film. With a positive
+/// lens radius rays leave a disk around their film position and converge on
+/// the plane at `focal_distance`.
+pub struct OrthographicCamera {
+    projective: ProjectiveCamera,
+    dx_camera: Vector3f,
+    dy_camera: Vector3f,
+}
+
+impl OrthographicCamera {
+    pub fn new(
+        base: CameraBase,
+        resolution: &Point2f,
+        screen_window: &Bounds2f,
+        lens_radius: f32,
+        focal_distance: f32,
+    ) -> OrthographicCamera {
+        let projective = ProjectiveCamera::new(
+            base,
+            &Transform::orthographic(0., 1.),
+            resolution,
+            screen_window,
+            lens_radius,
+            focal_distance,
+        );
+        let (dx_camera, dy_camera) = projective.pixel_offsets();
+        OrthographicCamera {
+            projective,
+            dx_camera,
+            dy_camera,
+        }
+    }
+
+    /// Camera space origin and direction of the ray for `sample`.
+    fn camera_ray(&self, sample: &CameraSample) -> (Point3f, Vector3f) {
+        let p_camera = self.projective.film_to_camera(&sample.p_film);
+        let lens_radius = self.projective.lens_radius;
+        if lens_radius <= 0. {
+            return (p_camera, Vector3f::new(0., 0., 1.));
+        }
+        let p_lens = concentric_sample_disk(&sample.p_lens);
+        let offset = Vector3f::new(p_lens.x * lens_radius, p_lens.y * lens_radius, 0.);
+        let d = Vector3f::new(0., 0., self.projective.focal_distance) - offset;
+        (p_camera + offset, Vector::normalize(&d))
+    }
+}
+
+impl Camera for OrthographicCamera {
+    fn generate_ray(&self, sample: &CameraSample) -> Option<(Ray_, f32)> {
+        let (o, d) = self.camera_ray(sample);
+        let ray = Ray_::new(&o, &d, f32::INFINITY, self.projective.ray_time(sample), None);
+        Some((self.projective.base.to_world(&ray), 1.))
+    }
+
+    /// Neighbouring pixels use the same lens offset, so their rays are the
+    /// central ray shifted by the precomputed pixel offsets.
+    fn generate_ray_differential(&self, sample: &CameraSample) -> Option<(RayDifferential, f32)> {
+        let (o, d) = self.camera_ray(sample);
+        let ray = Ray_::new(&o, &d, f32::INFINITY, self.projective.ray_time(sample), None);
+        let mut rd = RayDifferential::new(&ray);
+        rd.rx_origin = o + self.dx_camera;
+        rd.ry_origin = o + self.dy_camera;
+        rd.rx_dir = d;
+        rd.ry_dir = d;
+        rd.has_differential = true;
+        Some((self.projective.base.differential_to_world(&rd), 1.))
+    }
+
+    fn shutter_open(&self) -> f32 {
+        self.projective.base.shutter.open
+    }
+
+    fn shutter_close(&self) -> f32 {
+        self.projective.base.shutter.end_time()
+    }
+}
+
+#[test]
+fn orthographic_rays_are_parallel_and_focus() {
+    use geometry::Metric;
+    use geometry::ray::Ray;
+
+    let eye = Point3f::new(0., 0., -5.);
+    let world_to_camera = Transform::look_at(&eye, &Point3f::new(0., 0., 0.), &Vector3f::new(0., 1., 0.)).unwrap();
+    let resolution = Point2f::new(100., 50.);
+    // Frame a 20 x 10 region of the view plane
+    let window = Bounds2f::from((Point2f::new(-10., -5.), Point2f::new(10., 5.)));
+    let sample = |x: f32, y: f32| CameraSample {
+        p_film: Point2f::new(x, y),
+        p_lens: Point2f::new(0.7, 0.1),
+        time: 0.,
+    };
+
+    let camera = OrthographicCamera::new(CameraBase::new(&world_to_camera.inverse(), 0., 1.), &resolution, &window, 0., 0.);
+    let (corner, _) = camera.generate_ray(&sample(0., 0.)).unwrap();
+    let (center, _) = camera.generate_ray(&sample(50., 25.)).unwrap();
+    assert!((corner.d - center.d).norm() < 1e-5);
+    assert!((center.o - eye).norm() < 1e-4);
+    assert!((corner.o - (eye + Vector3f::new(-10., 5., 0.))).norm() < 1e-4);
+
+    let (rd, _) = camera.generate_ray_differential(&sample(20., 10.)).unwrap();
+    let (rx, _) = camera.generate_ray(&sample(21., 10.)).unwrap();
+    let (ry, _) = camera.generate_ray(&sample(20., 11.)).unwrap();
+    assert!((rd.rx_origin - rx.o).norm() < 1e-4 && (rd.ry_origin - ry.o).norm() < 1e-4);
+    assert!(((rd.rx_origin - rd.ray.o).norm() - 0.2).abs() < 1e-4);
+
+    // Rays through different lens points of one pixel meet at the focal plane
+    let lens = OrthographicCamera::new(CameraBase::new(&world_to_camera.inverse(), 0., 1.), &resolution, &window, 0.5, 3.);
+    let mut focus = Vec::new();
+    for &(u, v) in [(0.1, 0.2), (0.9, 0.5), (0.4, 0.95)].iter() {
+        let (ray, _) = lens.generate_ray(&CameraSample {
+            p_lens: Point2f::new(u, v),
+            ..sample(20., 10.)
+        }).unwrap();
+        focus.push(ray.point((-2. - ray.o.z) / ray.d.z));
+    }
+    let (pinhole, _) = camera.generate_ray(&sample(20., 10.)).unwrap();
+    for p in &focus {
+        assert!((*p - pinhole.point(3.)).norm() < 1e-4);
+    }
+    let (rd, _) = lens.generate_ray_differential(&sample(20., 10.)).unwrap();
+    let (rx, _) = lens.generate_ray(&sample(21., 10.)).unwrap();
+    assert!((rd.rx_origin - rx.o).norm() < 1e-4 && (rd.rx_dir - rx.d).norm() < 1e-5);
+}
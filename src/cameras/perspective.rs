@@ -0,0 +1,155 @@
+use geometry::Vector;
+use geometry::bounds::Bounds2f;
+use geometry::point::{Point2f, Point3f};
+use geometry::ray::{RayDifferential, Ray_};
+use geometry::transform::Transform;
+use geometry::vector::Vector3f;
+use sampling::concentric_sample_disk;
+
+use super::{Camera, CameraBase, CameraSample, ProjectiveCamera};
+
+/// Pinhole camera, or a thin lens camera focused at `focal_distance` when the
+/// lens radius is positive.
+pub struct PerspectiveCamera {
+    projective: ProjectiveCamera,
+    dx_camera: Vector3f,
+    dy_camera: Vector3f,
+}
+
+impl PerspectiveCamera {
+    /// `fov` is the angle in degrees spanned by the shorter axis of the
+    /// screen window.
+    pub fn new(
+        base: CameraBase,
+        resolution: &Point2f,
+        screen_window: &Bounds2f,
+        lens_radius: f32,
+        focal_distance: f32,
+        fov: f32,
+    ) -> PerspectiveCamera {
+        let camera_to_screen = Transform::perspective(fov, 1e-2, 1000.).unwrap();
+        let projective = ProjectiveCamera::new(
+            base,
+            &camera_to_screen,
+            resolution,
+            screen_window,
+            lens_radius,
+            focal_distance,
+        );
+        let (dx_camera, dy_camera) = projective.pixel_offsets();
+        PerspectiveCamera {
+            projective,
+            dx_camera,
+            dy_camera,
+        }
+    }
+
+    /// Camera space ray through `p_camera` on the z = 1 plane, refracted by
+    /// the thin lens at `p_lens` when there is one.
+    fn camera_ray(&self, p_camera: &Point3f, p_lens: &Point2f) -> (Point3f, Vector3f) {
+        let d = Vector::normalize(&(p_camera - &Point3f::new(0., 0., 0.)));
+        if self.projective.lens_radius <= 0. {
+            return (Point3f::new(0., 0., 0.), d);
+        }
+        let ft = self.projective.focal_distance / d.z;
+        let p_focus = Point3f::new(d.x * ft, d.y * ft, d.z * ft);
+        let o = Point3f::new(p_lens.x, p_lens.y, 0.);
+        (o, Vector::normalize(&(p_focus - o)))
+    }
+
+    fn lens_point(&self, sample: &CameraSample) -> Point2f {
+        let p = concentric_sample_disk(&sample.p_lens);
+        Point2f::new(p.x * self.projective.lens_radius, p.y * self.projective.lens_radius)
+    }
+}
+
+impl Camera for PerspectiveCamera {
+    fn generate_ray(&self, sample: &CameraSample) -> Option<(Ray_, f32)> {
+        let p_camera = self.projective.film_to_camera(&sample.p_film);
+        let (o, d) = self.camera_ray(&p_camera, &self.lens_point(sample));
+        let ray = Ray_::new(&o, &d, f32::INFINITY, self.projective.ray_time(sample), None);
+        Some((self.projective.base.to_world(&ray), 1.))
+    }
+
+    fn generate_ray_differential(&self, sample: &CameraSample) -> Option<(RayDifferential, f32)> {
+        let p_camera = self.projective.film_to_camera(&sample.p_film);
+        let p_lens = self.lens_point(sample);
+        let (o, d) = self.camera_ray(&p_camera, &p_lens);
+        let ray = Ray_::new(&o, &d, f32::INFINITY, self.projective.ray_time(sample), None);
+        let mut rd = RayDifferential::new(&ray);
+        // The offset rays pass through the same lens point
+        let (rx_origin, rx_dir) = self.camera_ray(&(p_camera + self.dx_camera), &p_lens);
+        let (ry_origin, ry_dir) = self.camera_ray(&(p_camera + self.dy_camera), &p_lens);
+        rd.rx_origin = rx_origin;
+        rd.rx_dir = rx_dir;
+        rd.ry_origin = ry_origin;
+        rd.ry_dir = ry_dir;
+        rd.has_differential = true;
+        Some((self.projective.base.differential_to_world(&rd), 1.))
+    }
+
+    fn shutter_open(&self) -> f32 {
+        self.projective.base.shutter.open
+    }
+
+    fn shutter_close(&self) -> f32 {
+        self.projective.base.shutter.end_time()
+    }
+}
+
+#[test]
+fn perspective_rays_follow_view_and_focus() {
+    use geometry::{Metric, VectorSpace};
+    use geometry::ray::Ray;
+    use super::default_screen_window;
+
+    let eye = Point3f::new(1., 2., 3.);
+    let look = Point3f::new(1., 2., 13.);
+    let world_to_camera = Transform::look_at(&eye, &look, &Vector3f::new(0., 1., 0.)).unwrap();
+    let resolution = Point2f::new(200., 100.);
+    let window = default_screen_window(&resolution);
+    let sample = |x: f32, y: f32| CameraSample {
+        p_film: Point2f::new(x, y),
+        p_lens: Point2f::new(0.3, 0.8),
+        time: 0.5,
+    };
+
+    let pinhole = PerspectiveCamera::new(CameraBase::new(&world_to_camera.inverse(), 0., 1.), &resolution, &window, 0., 1e6, 90.);
+    let (center, weight) = pinhole.generate_ray(&sample(100., 50.)).unwrap();
+    assert_eq!(weight, 1.);
+    assert_eq!(center.time, 0.5);
+    assert!((center.o - eye).norm() < 1e-4);
+    assert!((center.d - Vector3f::new(0., 0., 1.)).norm() < 1e-4);
+    // The 90 degree field of view spans the shorter, vertical axis
+    let (top, _) = pinhole.generate_ray(&sample(100., 0.)).unwrap();
+    assert!((top.d.dot(Vector3f::new(0., 0., 1.)) - (45f32).to_radians().cos()).abs() < 1e-4);
+    assert!(top.d.y > 0.);
+
+    // Differentials match the rays of the neighbouring pixels
+    let (rd, _) = pinhole.generate_ray_differential(&sample(30.5, 70.5)).unwrap();
+    let (rx, _) = pinhole.generate_ray(&sample(31.5, 70.5)).unwrap();
+    let (ry, _) = pinhole.generate_ray(&sample(30.5, 71.5)).unwrap();
+    assert!(rd.has_differential);
+    assert!((rd.rx_dir - rx.d).norm() < 1e-4 && (rd.ry_dir - ry.d).norm() < 1e-4);
+
+    // Thin lens rays through different lens points meet on the focal plane
+    let lens = PerspectiveCamera::new(CameraBase::new(&world_to_camera.inverse(), 0., 1.), &resolution, &window, 0.5, 4., 90.);
+    let mut focus = Vec::new();
+    for &(u, v) in [(0.1, 0.2), (0.9, 0.5), (0.4, 0.95)].iter() {
+        let s = CameraSample {
+            p_lens: Point2f::new(u, v),
+            ..sample(150., 20.)
+        };
+        let (ray, _) = lens.generate_ray(&s).unwrap();
+        assert!((ray.o - eye).norm() > 1e-3);
+        focus.push(ray.point((7. - ray.o.z) / ray.d.z));
+    }
+    assert!((focus[0] - focus[1]).norm() < 1e-3 && (focus[0] - focus[2]).norm() < 1e-3);
+    let (rd, _) = lens.generate_ray_differential(&sample(150., 20.)).unwrap();
+    let p = rd.ray.point((7. - rd.ray.o.z) / rd.ray.d.z);
+    let px = rd.rx_origin + rd.rx_dir * ((7. - rd.rx_origin.z) / rd.rx_dir.z);
+    let (rx, _) = pinhole.generate_ray(&sample(151., 20.)).unwrap();
+    let (r0, _) = pinhole.generate_ray(&sample(150., 20.)).unwrap();
+    let pinhole_step = rx.point(4. / rx.d.z) - r0.point(4. / r0.d.z);
+    assert!(((px - p) - pinhole_step).norm() < 1e-3);
+}
@@ -0,0 +1,525 @@
+use std::error::Error;
+use std::fmt;
+use std::thread;
+
+use geometry::{lerp, Metric, Vector, VectorSpace};
+use geometry::bounds::{Bounds, Bounds2f};
+use geometry::point::{Point2f, Point3f};
+use geometry::ray::Ray_;
+use geometry::vector::Vector3f;
+use sampling::radical_inverse;
+
+use super::{Camera, CameraBase, CameraSample};
+
+/// Number of film radii the exit pupil is bounded at.
+const N_PUPIL_BOUNDS: usize = 64;
+/// Rays traced from the film to bound each exit pupil.
+const N_PUPIL_SAMPLES: u64 = 128 * 128;
+
+/// One spherical interface of a lens system, or the aperture stop when the
+/// curvature radius is zero. Lengths are in meters, `thickness` is the
+/// distance to the next interface towards the film and `eta` the index of
+/// refraction behind the interface (zero for the stop).
+#[derive(Debug, Copy, Clone)]
+pub struct LensElementInterface {
+    pub curvature_radius: f32,
+    pub thickness: f32,
+    pub eta: f32,
+    pub aperture_radius: f32,
+}
+
+#[derive(Debug)]
+pub enum LensError {
+    Table(String),
+    /// The lens system cannot form an image, so it cannot be focused.
+    Focus,
+}
+
+impl fmt::Display for LensError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LensError::Table(ref message) => write!(f, "Invalid lens table: {}", message),
+            LensError::Focus => write!(f, "Lens system does not focus"),
+        }
+    }
+}
+
+impl Error for LensError {
+    fn description(&self) -> &str {
+        "Invalid lens system"
+    }
+}
+
+/// Parses a lens prescription with one interface per row, from the scene
+/// side towards the film: curvature radius, thickness, index of refraction
+/// and aperture diameter, all in millimeters. `#` starts a comment.
+pub fn parse_lens_table(text: &str) -> Result<Vec<LensElementInterface>, LensError> {
+    let mut values = Vec::new();
+    for line in text.lines() {
+        let line = line.split('#').next().unwrap_or("");
+        for token in line.split_whitespace() {
+            let v = token
+                .parse::<f32>()
+                .map_err(|_| LensError::Table(format!("'{}' is not a number", token)))?;
+            values.push(v);
+        }
+    }
+    if values.is_empty() || values.len() % 4 != 0 {
+        return Err(LensError::Table(format!(
+            "expected four values per element, got {}",
+            values.len()
+        )));
+    }
+    Ok(values
+        .chunks(4)
+        .map(|row| LensElementInterface {
+            curvature_radius: row[0] * 0.001,
+            thickness: row[1] * 0.001,
+            eta: row[2],
+            aperture_radius: row[3] * 0.001 / 2.,
+        })
+        .collect())
+}
+
+/// Intersection with the sphere of radius `radius` centered on the optical
+/// axis at `z_center`, returning the distance along the ray and the normal
+/// facing against it.
+fn intersect_spherical_element(radius: f32, z_center: f32, o: &Point3f, d: &Vector3f) -> Option<(f32, Vector3f)> {
+    let oc = Vector3f::new(o.x, o.y, o.z - z_center);
+    let a = d.dot(d);
+    let b = 2. * d.dot(&oc);
+    let c = oc.dot(oc) - radius * radius;
+    let discrim = b as f64 * b as f64 - 4. * a as f64 * c as f64;
+    if discrim < 0. {
+        return None;
+    }
+    let root_discrim = discrim.sqrt() as f32;
+    let q = if b < 0. {
+        -0.5 * (b - root_discrim)
+    } else {
+        -0.5 * (b + root_discrim)
+    };
+    let (t0, t1) = (q / a, c / q);
+    let (t0, t1) = if t0 > t1 { (t1, t0) } else { (t0, t1) };
+    // Pick the half of the sphere that forms the element
+    let t = if (d.z > 0.) ^ (radius < 0.) { t0 } else { t1 };
+    if t < 0. {
+        return None;
+    }
+    let n = Vector::normalize(&(oc + *d * t));
+    Some((t, if n.dot(*d) > 0. { -n } else { n }))
+}
+
+/// Refracts the direction `wi` pointing away from the surface, with `n` on
+/// its side and `eta` the ratio of the incident to the transmitted index.
+fn refract(wi: &Vector3f, n: &Vector3f, eta: f32) -> Option<Vector3f> {
+    let cos_theta_i = n.dot(wi);
+    let sin2_theta_i = (1. - cos_theta_i * cos_theta_i).max(0.);
+    let sin2_theta_t = eta * eta * sin2_theta_i;
+    if sin2_theta_t >= 1. {
+        return None;
+    }
+    let cos_theta_t = (1. - sin2_theta_t).sqrt();
+    Some(-*wi * eta + *n * (eta * cos_theta_i - cos_theta_t))
+}
+
+/// Lens elements in "lens space", where the film sits at z = 0 and the
+/// lens extends towards negative z. Camera space is the same with z flipped.
+#[derive(Debug, Clone)]
+struct LensSystem {
+    elements: Vec<LensElementInterface>,
+}
+
+impl LensSystem {
+    fn front_z(&self) -> f32 {
+        self.elements.iter().map(|e| e.thickness).sum()
+    }
+
+    fn rear_z(&self) -> f32 {
+        self.elements.last().unwrap().thickness
+    }
+
+    fn rear_element_radius(&self) -> f32 {
+        self.elements.last().unwrap().aperture_radius
+    }
+
+    /// Tests the ray against one interface at `element_z` and bends it.
+    /// `eta_ratio` is the incident over the transmitted index.
+    fn pass_interface(
+        element: &LensElementInterface,
+        element_z: f32,
+        eta_ratio: f32,
+        o: &mut Point3f,
+        d: &mut Vector3f,
+    ) -> bool {
+        let (t, n) = if element.curvature_radius == 0. {
+            // A ray bent back towards where it came from never reaches the stop
+            if d.z == 0. {
+                return false;
+            }
+            ((element_z - o.z) / d.z, Vector3f::new(0., 0., 0.))
+        } else {
+            let z_center = element_z + element.curvature_radius;
+            match intersect_spherical_element(element.curvature_radius, z_center, o, d) {
+                Some(hit) => hit,
+                None => return false,
+            }
+        };
+        if t < 0. {
+            return false;
+        }
+        let p_hit = *o + *d * t;
+        if p_hit.x * p_hit.x + p_hit.y * p_hit.y > element.aperture_radius * element.aperture_radius {
+            return false;
+        }
+        *o = p_hit;
+        if element.curvature_radius != 0. {
+            match refract(&Vector::normalize(&-*d), &n, eta_ratio) {
+                Some(w) => *d = w,
+                None => return false,
+            }
+        }
+        true
+    }
+
+    /// Traces a camera space ray leaving the film through the elements and
+    /// returns it in camera space once it leaves the front element.
+    fn trace_from_film(&self, o: &Point3f, d: &Vector3f) -> Option<(Point3f, Vector3f)> {
+        let mut o = Point3f::new(o.x, o.y, -o.z);
+        let mut d = Vector3f::new(d.x, d.y, -d.z);
+        if d.z >= 0. {
+            return None;
+        }
+        let mut element_z = 0.;
+        for i in (0..self.elements.len()).rev() {
+            let element = &self.elements[i];
+            element_z -= element.thickness;
+            let eta_t = if i > 0 && self.elements[i - 1].eta != 0. {
+                self.elements[i - 1].eta
+            } else {
+                1.
+            };
+            if !LensSystem::pass_interface(element, element_z, element.eta / eta_t, &mut o, &mut d) {
+                return None;
+            }
+        }
+        Some((Point3f::new(o.x, o.y, -o.z), Vector3f::new(d.x, d.y, -d.z)))
+    }
+
+    /// Traces a camera space ray entering the front element towards the
+    /// film and returns it in camera space once it leaves the rear element.
+    fn trace_from_scene(&self, o: &Point3f, d: &Vector3f) -> Option<(Point3f, Vector3f)> {
+        let mut o = Point3f::new(o.x, o.y, -o.z);
+        let mut d = Vector3f::new(d.x, d.y, -d.z);
+        let mut element_z = -self.front_z();
+        for i in 0..self.elements.len() {
+            let element = &self.elements[i];
+            let eta_i = if i == 0 || self.elements[i - 1].eta == 0. {
+                1.
+            } else {
+                self.elements[i - 1].eta
+            };
+            let eta_t = if element.eta != 0. { element.eta } else { 1. };
+            if !LensSystem::pass_interface(element, element_z, eta_i / eta_t, &mut o, &mut d) {
+                return None;
+            }
+            element_z += element.thickness;
+        }
+        Some((Point3f::new(o.x, o.y, -o.z), Vector3f::new(d.x, d.y, -d.z)))
+    }
+
+    /// Principal plane and focal point along z of a ray parallel to the
+    /// axis, `r_in`, after it passed the lens as `r_out`.
+    fn cardinal_points(r_in: &(Point3f, Vector3f), r_out: &(Point3f, Vector3f)) -> (f32, f32) {
+        let (o, d) = r_out;
+        let tf = -o.x / d.x;
+        let tp = (r_in.0.x - o.x) / d.x;
+        (-(o.z + d.z * tp), -(o.z + d.z * tf))
+    }
+
+    /// Principal planes and focal points on the scene side and the film side
+    /// of the lens, measured along lens space z. `film_diagonal` is in meters.
+    fn thick_lens_approximation(&self, film_diagonal: f32) -> Option<([f32; 2], [f32; 2])> {
+        // Stay close to the axis so the paraxial approximation holds
+        let x = 0.001 * film_diagonal;
+        let scene_ray = (Point3f::new(x, 0., self.front_z() + 1.), Vector3f::new(0., 0., -1.));
+        let film_ray = self.trace_from_scene(&scene_ray.0, &scene_ray.1)?;
+        let (pz0, fz0) = LensSystem::cardinal_points(&scene_ray, &film_ray);
+
+        let film_ray = (Point3f::new(x, 0., self.rear_z() - 1.), Vector3f::new(0., 0., 1.));
+        let scene_ray = self.trace_from_film(&film_ray.0, &film_ray.1)?;
+        let (pz1, fz1) = LensSystem::cardinal_points(&film_ray, &scene_ray);
+        Some(([pz0, pz1], [fz0, fz1]))
+    }
+
+    /// Distance from the film to the rear element that focuses the thick
+    /// lens approximation at `focus_distance` in front of the film.
+    fn focus_thick_lens(&self, focus_distance: f32, film_diagonal: f32) -> Option<f32> {
+        let (pz, fz) = self.thick_lens_approximation(film_diagonal)?;
+        let f = fz[0] - pz[0];
+        let z = -focus_distance;
+        let c = (pz[1] - z - pz[0]) * (pz[1] - z - 4. * f - pz[0]);
+        if c <= 0. {
+            return None;
+        }
+        let delta = 0.5 * (pz[1] - z + pz[0] - c.sqrt());
+        let thickness = self.rear_z() + delta;
+        if thickness > 0. {
+            Some(thickness)
+        } else {
+            None
+        }
+    }
+
+    /// Bounds on the rear element plane of the directions through which
+    /// light from the film segment [x0, x1] on the x axis leaves the lens.
+    fn bound_exit_pupil(&self, x0: f32, x1: f32) -> Bounds2f {
+        let r = 1.5 * self.rear_element_radius();
+        let proj_rear_bounds = Bounds2f::from((Point2f::new(-r, -r), Point2f::new(r, r)));
+        let mut pupil_bounds: Option<Bounds2f> = None;
+        for i in 0..N_PUPIL_SAMPLES {
+            let p_film = Point3f::new(lerp((i as f32 + 0.5) / N_PUPIL_SAMPLES as f32, x0, x1), 0., 0.);
+            let u = Point2f::new(radical_inverse(2, i), radical_inverse(3, i));
+            let p_rear = proj_rear_bounds.lerp(&u);
+            // Skip tracing samples that cannot grow the bounds
+            if let Some(b) = pupil_bounds {
+                if Bounds::inside(&p_rear, &b) {
+                    continue;
+                }
+            }
+            let p_rear = Point3f::new(p_rear.x, p_rear.y, self.rear_z());
+            if self.trace_from_film(&p_film, &(p_rear - p_film)).is_some() {
+                let p = Point2f::new(p_rear.x, p_rear.y);
+                pupil_bounds = Some(match pupil_bounds {
+                    Some(b) => Bounds::point_union(&b, &p),
+                    None => Bounds2f::from(&p),
+                });
+            }
+        }
+        match pupil_bounds {
+            // Grow by the sample spacing to cover directions between samples
+            Some(b) => Bounds::expand(
+                &b,
+                2. * proj_rear_bounds.diagonal().norm() / (N_PUPIL_SAMPLES as f32).sqrt(),
+            ),
+            None => proj_rear_bounds,
+        }
+    }
+}
+
+/// Camera that traces rays from the film through a tabulated system of
+/// spherical lens elements (Kolb et al. 1995), sampling the exit pupil as
+/// seen from each film position.
+pub struct RealisticCamera {
+    base: CameraBase,
+    resolution: Point2f,
+    /// Film rectangle in meters on the z = 0 plane.
+    physical_extent: Bounds2f,
+    simple_weighting: bool,
+    lens: LensSystem,
+    exit_pupil_bounds: Vec<Bounds2f>,
+}
+
+impl RealisticCamera {
+    /// `film_diagonal` and `aperture_diameter` are in millimeters; the stop
+    /// of `elements` is narrowed to the aperture diameter if it is smaller.
+    /// The film is moved so the lens focuses at `focus_distance` meters.
+    /// With `simple_weighting` the ray weights only account for vignetting,
+    /// otherwise they give the radiometrically correct sensor response.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        base: CameraBase,
+        resolution: &Point2f,
+        film_diagonal: f32,
+        aperture_diameter: f32,
+        focus_distance: f32,
+        simple_weighting: bool,
+        elements: &[LensElementInterface],
+    ) -> Result<RealisticCamera, LensError> {
+        if elements.is_empty() {
+            return Err(LensError::Table("no lens elements".to_string()));
+        }
+        let mut elements = elements.to_vec();
+        for element in elements.iter_mut().filter(|e| e.curvature_radius == 0.) {
+            element.aperture_radius = element.aperture_radius.min(aperture_diameter * 0.001 / 2.);
+        }
+        let diagonal = film_diagonal * 0.001;
+        let mut lens = LensSystem { elements };
+        let thickness = lens.focus_thick_lens(focus_distance, diagonal).ok_or(LensError::Focus)?;
+        lens.elements.last_mut().unwrap().thickness = thickness;
+
+        let aspect = resolution.y / resolution.x;
+        let x = (diagonal * diagonal / (1. + aspect * aspect)).sqrt();
+        let y = aspect * x;
+        let physical_extent = Bounds2f::from((Point2f::new(-x / 2., -y / 2.), Point2f::new(x / 2., y / 2.)));
+
+        // Bound the exit pupil for rings of film radii in parallel
+        let n_threads = thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1)
+            .min(N_PUPIL_BOUNDS);
+        let chunk_size = N_PUPIL_BOUNDS.div_ceil(n_threads);
+        let indices: Vec<usize> = (0..N_PUPIL_BOUNDS).collect();
+        let mut exit_pupil_bounds = Vec::with_capacity(N_PUPIL_BOUNDS);
+        thread::scope(|scope| {
+            let handles: Vec<_> = indices
+                .chunks(chunk_size)
+                .map(|chunk| {
+                    let lens = &lens;
+                    scope.spawn(move || {
+                        chunk
+                            .iter()
+                            .map(|&i| {
+                                let r0 = i as f32 / N_PUPIL_BOUNDS as f32 * diagonal / 2.;
+                                let r1 = (i + 1) as f32 / N_PUPIL_BOUNDS as f32 * diagonal / 2.;
+                                lens.bound_exit_pupil(r0, r1)
+                            })
+                            .collect::<Vec<_>>()
+                    })
+                })
+                .collect();
+            for handle in handles {
+                exit_pupil_bounds.extend(handle.join().unwrap());
+            }
+        });
+
+        Ok(RealisticCamera {
+            base,
+            resolution: *resolution,
+            physical_extent,
+            simple_weighting,
+            lens,
+            exit_pupil_bounds,
+        })
+    }
+
+    /// Samples a point on the rear element plane within the exit pupil bounds
+    /// for `p_film`, returning it with the area of the sampled bounds.
+    fn sample_exit_pupil(&self, p_film: &Point2f, lens_sample: &Point2f) -> (Point3f, f32) {
+        let r_film = (p_film.x * p_film.x + p_film.y * p_film.y).sqrt();
+        let diagonal = self.physical_extent.diagonal().norm();
+        let r_index = ((r_film / (diagonal / 2.) * N_PUPIL_BOUNDS as f32) as usize).min(N_PUPIL_BOUNDS - 1);
+        let pupil_bounds = &self.exit_pupil_bounds[r_index];
+        let p_lens = pupil_bounds.lerp(lens_sample);
+
+        // The bounds were computed along +x, rotate them to the film point
+        let (sin_theta, cos_theta) = if r_film != 0. {
+            (p_film.y / r_film, p_film.x / r_film)
+        } else {
+            (0., 1.)
+        };
+        (
+            Point3f::new(
+                cos_theta * p_lens.x - sin_theta * p_lens.y,
+                sin_theta * p_lens.x + cos_theta * p_lens.y,
+                self.lens.rear_z(),
+            ),
+            pupil_bounds.surface_area(),
+        )
+    }
+}
+
+impl Camera for RealisticCamera {
+    fn generate_ray(&self, sample: &CameraSample) -> Option<(Ray_, f32)> {
+        let s = Point2f::new(sample.p_film.x / self.resolution.x, sample.p_film.y / self.resolution.y);
+        let p_film2 = self.physical_extent.lerp(&s);
+        // The lens flips the image, so the film is mirrored in x
+        let p_film = Point3f::new(-p_film2.x, p_film2.y, 0.);
+        let (p_rear, pupil_area) = self.sample_exit_pupil(&Point2f::new(p_film.x, p_film.y), &sample.p_lens);
+        let d_film = p_rear - p_film;
+        let (o, d) = self.lens.trace_from_film(&p_film, &d_film)?;
+
+        let time = self.base.ray_time(sample, &self.resolution);
+        let ray = Ray_::new(&o, &Vector::normalize(&d), f32::INFINITY, time, None);
+        let cos_theta = Vector::normalize(&d_film).z;
+        let cos4_theta = (cos_theta * cos_theta) * (cos_theta * cos_theta);
+        let weight = if self.simple_weighting {
+            cos4_theta * pupil_area / self.exit_pupil_bounds[0].surface_area()
+        } else {
+            let rear_z = self.lens.rear_z();
+            (self.base.shutter.close - self.base.shutter.open) * (cos4_theta * pupil_area) / (rear_z * rear_z)
+        };
+        Some((self.base.to_world(&ray), weight))
+    }
+
+    fn shutter_open(&self) -> f32 {
+        self.base.shutter.open
+    }
+
+    fn shutter_close(&self) -> f32 {
+        self.base.shutter.end_time()
+    }
+}
+
+#[test]
+fn double_gauss_lens_focuses() {
+    use geometry::transform::Transform;
+
+    // 50mm f/2 double Gauss lens (US patent 2,673,491)
+    let table = "
+        # radius  thickness  eta    aperture
+        29.475    3.76       1.67   25.2
+        84.83     0.12       1      25.2
+        19.275    4.025      1.67   23
+        40.77     3.275      1.699  23
+        12.75     5.705      1      18
+        0         4.5        0      17.1
+        -14.495   1.18       1.603  17
+        40.77     6.065      1.658  20
+        -20.385   0.19       1      20
+        437.065   3.22       1.717  20
+        -39.73    0          1      20
+    ";
+    let elements = parse_lens_table(table).unwrap();
+    assert_eq!(elements.len(), 11);
+    assert!(parse_lens_table("1 2 3").is_err());
+    assert!(parse_lens_table("1 2 x 4").is_err());
+
+    let lens = LensSystem {
+        elements: elements.clone(),
+    };
+    let (pz, fz) = lens.thick_lens_approximation(0.035).unwrap();
+    let focal_length = fz[0] - pz[0];
+    assert!((focal_length - 0.05).abs() < 0.005, "focal length {}", focal_length);
+
+    let camera_to_world = Transform::translate(&Vector3f::new(0., 0., 0.));
+    let resolution = Point2f::new(64., 48.);
+    let camera = RealisticCamera::new(CameraBase::new(&camera_to_world, 0., 1.), &resolution, 35., 8., 2., true, &elements).unwrap();
+    // Focusing at 2m moves the film slightly behind the infinity focus
+    let rear = camera.lens.rear_z();
+    assert!(rear > 0.02 && rear < 0.06);
+    assert!(Bounds::inside(&Point2f::new(0., 0.), &camera.exit_pupil_bounds[0]));
+
+    // Rays from the center of the film cross the axis near the focus distance
+    let mut n_rays = 0;
+    for i in 0..64 {
+        let sample = CameraSample {
+            p_film: Point2f::new(32., 24.),
+            p_lens: Point2f::new(radical_inverse(2, i), radical_inverse(3, i)),
+            time: 0.,
+        };
+        if let Some((ray, weight)) = camera.generate_ray(&sample) {
+            assert!(weight > 0.);
+            assert!(ray.d.z > 0.);
+            let r2 = ray.d.x * ray.d.x + ray.d.y * ray.d.y;
+            if r2 < 1e-12 {
+                continue;
+            }
+            let t = -(ray.o.x * ray.d.x + ray.o.y * ray.d.y) / r2;
+            let z = ray.o.z + t * ray.d.z;
+            assert!((z - 2.).abs() < 0.1, "ray focused at {}", z);
+            n_rays += 1;
+        }
+    }
+    assert!(n_rays > 32);
+
+    // Off axis film points image the scene on the opposite side
+    let (ray, _) = camera
+        .generate_ray(&CameraSample {
+            p_film: Point2f::new(8., 24.),
+            p_lens: Point2f::new(0.5, 0.5),
+            time: 0.,
+        })
+        .unwrap();
+    assert!(ray.d.x < 0.);
+}
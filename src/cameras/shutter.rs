@@ -0,0 +1,175 @@
+use geometry::lerp;
+
+/// Openness of the shutter over its open interval.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ShutterCurve {
+    /// Fully open for the whole interval.
+    Box,
+    /// Opens linearly until the middle of the interval and closes again.
+    Triangle,
+    /// Openness at evenly spaced times from opening to closing, linearly
+    /// interpolated in between.
+    Tabulated(Vec<f32>),
+}
+
+/// Order in which the rows of a rolling shutter are exposed.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum RollingShutter {
+    TopToBottom { readout_time: f32 },
+    BottomToTop { readout_time: f32 },
+}
+
+/// Maps sample times in [0, 1) to exposure times, distributed following the
+/// shutter curve. With a rolling shutter each film row is exposed for the
+/// same duration, starting later the further it is read out.
+#[derive(Debug, Clone)]
+pub struct Shutter {
+    pub open: f32,
+    pub close: f32,
+    pub rolling: Option<RollingShutter>,
+    values: Vec<f32>,
+    /// Integral of the curve up to each tabulated value, normalized to one.
+    cdf: Vec<f32>,
+}
+
+impl Shutter {
+    pub fn new(open: f32, close: f32, curve: ShutterCurve) -> Shutter {
+        let values = match curve {
+            ShutterCurve::Box => vec![1., 1.],
+            ShutterCurve::Triangle => vec![0., 1., 0.],
+            ShutterCurve::Tabulated(values) => {
+                assert!(values.len() >= 2 && values.iter().all(|&v| v >= 0.));
+                values
+            }
+        };
+        let mut cdf = vec![0.];
+        for w in values.windows(2) {
+            let last = *cdf.last().unwrap();
+            cdf.push(last + (w[0] + w[1]) / 2.);
+        }
+        let total = *cdf.last().unwrap();
+        if total > 0. {
+            for c in cdf.iter_mut() {
+                *c /= total;
+            }
+        } else {
+            // A curve that never opens is treated as a box
+            for (i, c) in cdf.iter_mut().enumerate() {
+                *c = i as f32 / (values.len() - 1) as f32;
+            }
+        }
+        Shutter {
+            open,
+            close,
+            rolling: None,
+            values,
+            cdf,
+        }
+    }
+
+    /// Position in [0, 1] of the open interval at which the integral of the
+    /// curve reaches `u`.
+    fn sample_curve(&self, u: f32) -> f32 {
+        let n_segments = self.values.len() - 1;
+        let i = match self.cdf.iter().position(|&c| c > u) {
+            Some(i) => (i.max(1) - 1).min(n_segments - 1),
+            None => n_segments - 1,
+        };
+        let (a, b) = (self.values[i], self.values[i + 1]);
+        let segment_area = self.cdf[i + 1] - self.cdf[i];
+        let target = if segment_area > 0. {
+            ((u - self.cdf[i]) / segment_area).clamp(0., 1.)
+        } else {
+            0.
+        };
+        // Solve the integral of the linear openness over the segment,
+        // (a s + (b - a) s^2 / 2) / ((a + b) / 2) = target, for s
+        let s = if (b - a).abs() < 1e-6 * (a + b) || a + b == 0. {
+            target
+        } else {
+            let area = (a + b) / 2. * target;
+            (-a + (a * a + 2. * (b - a) * area).max(0.).sqrt()) / (b - a)
+        };
+        (i as f32 + s.clamp(0., 1.)) / n_segments as f32
+    }
+
+    /// Exposure time for the sample time `u` on the film row at `row`, the
+    /// fraction of the image height from the top.
+    pub fn sample_time(&self, u: f32, row: f32) -> f32 {
+        let time = lerp(self.sample_curve(u), self.open, self.close);
+        match self.rolling {
+            Some(RollingShutter::TopToBottom { readout_time }) => time + row.clamp(0., 1.) * readout_time,
+            Some(RollingShutter::BottomToTop { readout_time }) => time + (1. - row.clamp(0., 1.)) * readout_time,
+            None => time,
+        }
+    }
+
+    /// Last time any row is exposed.
+    pub fn end_time(&self) -> f32 {
+        match self.rolling {
+            Some(RollingShutter::TopToBottom { readout_time })
+            | Some(RollingShutter::BottomToTop { readout_time }) => self.close + readout_time,
+            None => self.close,
+        }
+    }
+}
+
+#[test]
+fn shutter_curves_and_rolling_readout() {
+    use geometry::point::Point2f;
+    use geometry::transform::{AnimatedTransform, Transform};
+    use geometry::vector::Vector3f;
+    use super::{default_screen_window, Camera, CameraBase, CameraSample};
+    use super::perspective::PerspectiveCamera;
+
+    let box_shutter = Shutter::new(1., 3., ShutterCurve::Box);
+    for &(u, t) in [(0., 1.), (0.25, 1.5), (0.5, 2.), (0.999, 2.998)].iter() {
+        assert!((box_shutter.sample_time(u, 0.7) - t).abs() < 1e-4);
+    }
+
+    // Half of the triangle's area lies before its peak, an eighth before a
+    // quarter of the interval
+    let triangle = Shutter::new(0., 1., ShutterCurve::Triangle);
+    assert!((triangle.sample_time(0.5, 0.) - 0.5).abs() < 1e-4);
+    assert!((triangle.sample_time(0.125, 0.) - 0.25).abs() < 1e-4);
+    assert!((triangle.sample_time(0.875, 0.) - 0.75).abs() < 1e-4);
+
+    // Closed for the first quarter, opening fully by the middle
+    let tabulated = Shutter::new(0., 1., ShutterCurve::Tabulated(vec![0., 0., 1., 1., 1.]));
+    for i in 0..100 {
+        let t = tabulated.sample_time((i as f32 + 0.5) / 100., 0.);
+        assert!(t > 0.25 && t < 1.);
+    }
+    let ramp = Shutter::new(0., 1., ShutterCurve::Tabulated(vec![0., 1.]));
+    assert!((ramp.sample_time(0.25, 0.) - 0.5).abs() < 1e-4);
+
+    let mut rolling = Shutter::new(0., 0.01, ShutterCurve::Box);
+    rolling.rolling = Some(RollingShutter::TopToBottom { readout_time: 0.03 });
+    assert!((rolling.sample_time(0.5, 0.) - 0.005).abs() < 1e-6);
+    assert!((rolling.sample_time(0.5, 1.) - 0.035).abs() < 1e-6);
+    assert_eq!(rolling.end_time(), 0.04);
+    rolling.rolling = Some(RollingShutter::BottomToTop { readout_time: 0.03 });
+    assert!((rolling.sample_time(0.5, 1.) - 0.005).abs() < 1e-6);
+
+    // A camera panning during a rolling exposure sees its rows at different
+    // positions
+    let start = Transform::translate(&Vector3f::new(0., 0., 0.));
+    let end = Transform::translate(&Vector3f::new(4., 0., 0.));
+    rolling.rolling = Some(RollingShutter::TopToBottom { readout_time: 0.03 });
+    let base = CameraBase::animated(AnimatedTransform::new(&start, 0., &end, 0.04), rolling);
+    let resolution = Point2f::new(100., 100.);
+    let camera = PerspectiveCamera::new(base, &resolution, &default_screen_window(&resolution), 0., 1., 60.);
+    let sample = |y: f32| CameraSample {
+        p_film: Point2f::new(50., y),
+        p_lens: Point2f::new(0.5, 0.5),
+        time: 0.5,
+    };
+    let (top, _) = camera.generate_ray(&sample(0.)).unwrap();
+    let (bottom, _) = camera.generate_ray(&sample(100.)).unwrap();
+    assert!((top.time - 0.005).abs() < 1e-6 && (bottom.time - 0.035).abs() < 1e-6);
+    assert!((top.o.x - 0.5).abs() < 1e-4 && (bottom.o.x - 3.5).abs() < 1e-4);
+    assert_eq!(top.o.y, bottom.o.y);
+    let (rd, _) = camera.generate_ray_differential(&sample(100.)).unwrap();
+    assert!((rd.ray.o.x - 3.5).abs() < 1e-4 && (rd.rx_origin.x - 3.5).abs() < 1e-4);
+    assert_eq!(camera.shutter_close(), 0.04);
+}
@@ -0,0 +1,360 @@
+use std::io;
+use std::path::Path;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use geometry::bounds::{Bounds2f, Bounds2i};
+use geometry::point::{Point2, Point2f, Point2i};
+use filters::{Filter, FilterTable};
+use imageio::pfm::write_pfm_file;
+use imageio::png::write_png_file;
+use imageio::tonemap::ToneMapping;
+
+/// Float accumulated with compare and swap, so splats from several threads
+/// can land on the same pixel without a lock.
+#[derive(Debug, Default)]
+struct AtomicFloat {
+    bits: AtomicU32,
+}
+
+impl AtomicFloat {
+    fn load(&self) -> f32 {
+        f32::from_bits(self.bits.load(Ordering::Relaxed))
+    }
+
+    fn add(&self, v: f32) {
+        let mut old = self.bits.load(Ordering::Relaxed);
+        loop {
+            let new = (f32::from_bits(old) + v).to_bits();
+            match self.bits.compare_exchange_weak(old, new, Ordering::Relaxed, Ordering::Relaxed) {
+                Ok(_) => return,
+                Err(current) => old = current,
+            }
+        }
+    }
+}
+
+/// Weighted sum of the radiance samples contributing to a pixel.
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct Pixel {
+    pub rgb: [f32; 3],
+    pub filter_weight_sum: f32,
+}
+
+/// Image being rendered. Only the pixels inside the crop window are stored;
+/// samples are taken in a slightly larger area so the filter footprint of
+/// the edge pixels is fully covered.
+#[derive(Debug)]
+pub struct Film {
+    pub full_resolution: Point2i,
+    pub cropped_pixel_bounds: Bounds2i,
+    pub filter_table: FilterTable,
+    /// Factor applied to the pixel values when the image is read back.
+    pub scale: f32,
+    pixels: Mutex<Vec<Pixel>>,
+    splats: Vec<[AtomicFloat; 3]>,
+}
+
+impl Film {
+    /// `crop_window` is given in normalized device coordinates, [0, 1]^2
+    /// covering the full image with y pointing down.
+    pub fn new(resolution: &Point2i, crop_window: &Bounds2f, filter: &dyn Filter, scale: f32) -> Film {
+        let (c_min, c_max) = (crop_window[0], crop_window[1]);
+        let (res_x, res_y) = (resolution.x as f32, resolution.y as f32);
+        let cropped_pixel_bounds = Bounds2i::from((
+            Point2 {
+                x: (res_x * c_min.x.clamp(0., 1.)).ceil() as i32,
+                y: (res_y * c_min.y.clamp(0., 1.)).ceil() as i32,
+            },
+            Point2 {
+                x: (res_x * c_max.x.clamp(0., 1.)).ceil() as i32,
+                y: (res_y * c_max.y.clamp(0., 1.)).ceil() as i32,
+            },
+        ));
+        let n_pixels = cropped_pixel_bounds.area() as usize;
+        Film {
+            full_resolution: *resolution,
+            cropped_pixel_bounds,
+            filter_table: FilterTable::new(filter),
+            scale,
+            pixels: Mutex::new(vec![Pixel::default(); n_pixels]),
+            splats: (0..n_pixels).map(|_| Default::default()).collect(),
+        }
+    }
+
+    /// Pixels whose samples can contribute to the cropped image: the crop
+    /// window grown by the filter radius, minus the half pixel offset of
+    /// pixel centers.
+    pub fn sample_bounds(&self) -> Bounds2i {
+        let (p_min, p_max) = (self.cropped_pixel_bounds[0], self.cropped_pixel_bounds[1]);
+        let radius = self.filter_table.radius;
+        Bounds2i::from((
+            Point2 {
+                x: (p_min.x as f32 + 0.5 - radius.x).floor() as i32,
+                y: (p_min.y as f32 + 0.5 - radius.y).floor() as i32,
+            },
+            Point2 {
+                x: (p_max.x as f32 - 0.5 + radius.x).ceil() as i32,
+                y: (p_max.y as f32 - 0.5 + radius.y).ceil() as i32,
+            },
+        ))
+    }
+
+    /// Tile for the samples taken in `sample_bounds`, covering every stored
+    /// pixel they can contribute to.
+    pub fn film_tile(&self, sample_bounds: &Bounds2i) -> FilmTile<'_> {
+        let (s_min, s_max) = (sample_bounds[0], sample_bounds[1]);
+        let radius = self.filter_table.radius;
+        let p0 = Point2 {
+            x: (s_min.x as f32 - 0.5 - radius.x).ceil() as i32,
+            y: (s_min.y as f32 - 0.5 - radius.y).ceil() as i32,
+        };
+        let p1 = Point2 {
+            x: (s_max.x as f32 - 0.5 + radius.x).floor() as i32 + 1,
+            y: (s_max.y as f32 - 0.5 + radius.y).floor() as i32 + 1,
+        };
+        let pixel_bounds = Bounds2i::intersect(&Bounds2i::from((p0, p1)), &self.cropped_pixel_bounds);
+        FilmTile::new(&pixel_bounds, &self.filter_table)
+    }
+
+    /// Tile covering exactly the stored pixels of `pixel_bounds`, for
+    /// samples added with `FilmTile::add_pixel_sample`. Unlike `film_tile`
+    /// the bounds are not grown by the filter radius, so tiles for disjoint
+    /// bounds never share pixels.
+    pub fn pixel_tile(&self, pixel_bounds: &Bounds2i) -> FilmTile<'_> {
+        let pixel_bounds = Bounds2i::intersect(pixel_bounds, &self.cropped_pixel_bounds);
+        FilmTile::new(&pixel_bounds, &self.filter_table)
+    }
+
+    /// Adds the sums of a finished tile to the image.
+    pub fn merge_film_tile(&self, tile: FilmTile) {
+        let mut pixels = self.pixels.lock().unwrap();
+        for p in tile.pixel_bounds.pixels() {
+            let tile_pixel = tile.pixel(&p);
+            let pixel = &mut pixels[self.offset(&p)];
+            for c in 0..3 {
+                pixel.rgb[c] += tile_pixel.rgb[c];
+            }
+            pixel.filter_weight_sum += tile_pixel.filter_weight_sum;
+        }
+    }
+
+    /// Adds a contribution that is not filtered or normalized by the sample
+    /// count of the pixel, e.g. from paths traced from the lights. Splats
+    /// outside the crop window are dropped.
+    pub fn add_splat(&self, p_film: &Point2f, rgb: [f32; 3]) {
+        if rgb.iter().any(|v| v.is_nan() || v.is_infinite()) {
+            return;
+        }
+        let p = Point2 {
+            x: p_film.x.floor() as i32,
+            y: p_film.y.floor() as i32,
+        };
+        if !Bounds2i::inside_exclusive(&p, &self.cropped_pixel_bounds) {
+            return;
+        }
+        let splat = &self.splats[self.offset(&p)];
+        for c in 0..3 {
+            splat[c].add(rgb[c]);
+        }
+    }
+
+    pub fn pixel(&self, p: &Point2i) -> Pixel {
+        self.pixels.lock().unwrap()[self.offset(p)]
+    }
+
+    /// Final values of the cropped image in scanline order: the filtered
+    /// samples plus the splats scaled by `splat_scale`, all scaled by
+    /// `scale`.
+    pub fn rgb(&self, splat_scale: f32) -> Vec<[f32; 3]> {
+        let pixels = self.pixels.lock().unwrap();
+        pixels
+            .iter()
+            .zip(self.splats.iter())
+            .map(|(pixel, splat)| {
+                let mut rgb = [0.; 3];
+                for c in 0..3 {
+                    if pixel.filter_weight_sum != 0. {
+                        rgb[c] = (pixel.rgb[c] / pixel.filter_weight_sum).max(0.);
+                    }
+                    rgb[c] = (rgb[c] + splat_scale * splat[c].load()) * self.scale;
+                }
+                rgb
+            })
+            .collect()
+    }
+
+    /// Width and height of the cropped image.
+    pub fn cropped_size(&self) -> (usize, usize) {
+        let (p_min, p_max) = (self.cropped_pixel_bounds[0], self.cropped_pixel_bounds[1]);
+        ((p_max.x - p_min.x).max(0) as usize, (p_max.y - p_min.y).max(0) as usize)
+    }
+
+    pub fn write_pfm(&self, path: &Path, splat_scale: f32) -> io::Result<()> {
+        let (width, height) = self.cropped_size();
+        write_pfm_file(path, &self.rgb(splat_scale), width, height)
+    }
+
+    pub fn write_png(&self, path: &Path, splat_scale: f32, tone_mapping: &ToneMapping) -> io::Result<()> {
+        let (width, height) = self.cropped_size();
+        write_png_file(path, &self.rgb(splat_scale), width, height, tone_mapping)
+    }
+
+    fn offset(&self, p: &Point2i) -> usize {
+        let (p_min, p_max) = (self.cropped_pixel_bounds[0], self.cropped_pixel_bounds[1]);
+        ((p.y - p_min.y) * (p_max.x - p_min.x) + (p.x - p_min.x)) as usize
+    }
+}
+
+/// Pixel sums for one region of the film, filled by a single thread and
+/// merged back with `Film::merge_film_tile`.
+#[derive(Debug, Clone)]
+pub struct FilmTile<'a> {
+    pixel_bounds: Bounds2i,
+    filter_table: &'a FilterTable,
+    pixels: Vec<Pixel>,
+}
+
+impl<'a> FilmTile<'a> {
+    pub fn new(pixel_bounds: &Bounds2i, filter_table: &'a FilterTable) -> FilmTile<'a> {
+        FilmTile {
+            pixel_bounds: *pixel_bounds,
+            filter_table,
+            pixels: vec![Pixel::default(); pixel_bounds.area().max(0) as usize],
+        }
+    }
+
+    pub fn pixel_bounds(&self) -> Bounds2i {
+        self.pixel_bounds
+    }
+
+    /// Adds a radiance sample at the continuous raster position `p_film`
+    /// to every pixel of the tile within the filter radius, weighted by the
+    /// tabulated filter.
+    pub fn add_sample(&mut self, p_film: &Point2f, rgb: [f32; 3], sample_weight: f32) {
+        let table = self.filter_table;
+        let (radius, inv_radius) = (table.radius, table.inv_radius);
+        // Pixel centers lie at half integer positions
+        let p = Point2f::new(p_film.x - 0.5, p_film.y - 0.5);
+        let (b_min, b_max) = (self.pixel_bounds[0], self.pixel_bounds[1]);
+        let x0 = ((p.x - radius.x).ceil() as i32).max(b_min.x);
+        let y0 = ((p.y - radius.y).ceil() as i32).max(b_min.y);
+        let x1 = ((p.x + radius.x).floor() as i32 + 1).min(b_max.x);
+        let y1 = ((p.y + radius.y).floor() as i32 + 1).min(b_max.y);
+        for y in y0..y1 {
+            let iy = table.index(y as f32 - p.y, inv_radius.y);
+            for x in x0..x1 {
+                let ix = table.index(x as f32 - p.x, inv_radius.x);
+                let filter_weight = table.weight(ix, iy);
+                let offset = self.offset(&Point2 { x, y });
+                let pixel = &mut self.pixels[offset];
+                for (sum, v) in pixel.rgb.iter_mut().zip(rgb.iter()) {
+                    *sum += v * sample_weight * filter_weight;
+                }
+                pixel.filter_weight_sum += filter_weight;
+            }
+        }
+    }
+
+    /// Adds a sample whose film position was drawn with `Filter::sample`
+    /// around the center of `p_pixel`, so it only contributes to that
+    /// pixel. `filter_weight` is the weight returned by the filter. When all
+    /// samples are added this way, the cropped pixel bounds suffice as sample
+    /// bounds and tiles from `Film::pixel_tile` need not overlap.
+    pub fn add_pixel_sample(&mut self, p_pixel: &Point2i, rgb: [f32; 3], filter_weight: f32) {
+        if !Bounds2i::inside_exclusive(p_pixel, &self.pixel_bounds) {
+            return;
+        }
+        let offset = self.offset(p_pixel);
+        let pixel = &mut self.pixels[offset];
+        for (sum, v) in pixel.rgb.iter_mut().zip(rgb.iter()) {
+            *sum += v * filter_weight;
+        }
+        pixel.filter_weight_sum += filter_weight;
+    }
+
+    pub fn pixel(&self, p: &Point2i) -> Pixel {
+        self.pixels[self.offset(p)]
+    }
+
+    fn offset(&self, p: &Point2i) -> usize {
+        let (p_min, p_max) = (self.pixel_bounds[0], self.pixel_bounds[1]);
+        ((p.y - p_min.y) * (p_max.x - p_min.x) + (p.x - p_min.x)) as usize
+    }
+}
+
+#[test]
+fn tiles_merge_into_cropped_film() {
+    use std::thread;
+    use filters::boxfilter::BoxFilter;
+    use geometry::vector::Vector2f;
+
+    let resolution = Point2 { x: 40, y: 20 };
+    let crop = Bounds2f::from((Point2f::new(0.25, 0.5), Point2f::new(0.75, 1.)));
+    let film = Film::new(&resolution, &crop, &BoxFilter::new(&Vector2f::new(1., 1.)), 2.);
+    assert_eq!(film.cropped_pixel_bounds, Bounds2i::from((Point2 { x: 10, y: 10 }, Point2 { x: 30, y: 20 })));
+    let sample_bounds = film.sample_bounds();
+    assert_eq!(sample_bounds, Bounds2i::from((Point2 { x: 9, y: 9 }, Point2 { x: 31, y: 21 })));
+
+    // Split the sample bounds into rows of tiles filled on separate threads,
+    // one sample per pixel with the pixel's x coordinate as its value
+    let rows: Vec<i32> = (sample_bounds[0].y..sample_bounds[1].y).step_by(4).collect();
+    let tiles = thread::scope(|scope| {
+        let handles: Vec<_> = rows
+            .iter()
+            .map(|&y0| {
+                let film = &film;
+                scope.spawn(move || {
+                    let bounds = Bounds2i::from((
+                        Point2 { x: sample_bounds[0].x, y: y0 },
+                        Point2 { x: sample_bounds[1].x, y: (y0 + 4).min(sample_bounds[1].y) },
+                    ));
+                    let mut tile = film.film_tile(&bounds);
+                    for p in bounds.pixels() {
+                        let v = p.x as f32;
+                        tile.add_sample(&Point2f::new(p.x as f32 + 0.5, p.y as f32 + 0.5), [v, 1., 0.], 1.);
+                    }
+                    tile
+                })
+            })
+            .collect();
+        handles.into_iter().map(|h| h.join().unwrap()).collect::<Vec<_>>()
+    });
+    for tile in tiles {
+        assert!(Bounds2i::intersect(&tile.pixel_bounds(), &film.cropped_pixel_bounds) == tile.pixel_bounds());
+        film.merge_film_tile(tile);
+    }
+
+    // Each pixel sees the 3 x 3 samples around it
+    for p in film.cropped_pixel_bounds.pixels() {
+        let pixel = film.pixel(&p);
+        assert_eq!(pixel.filter_weight_sum, 9.);
+        assert_eq!(pixel.rgb[0], 9. * p.x as f32);
+    }
+    film.add_splat(&Point2f::new(12.5, 10.2), [4., 0., 0.]);
+    film.add_splat(&Point2f::new(12.9, 10.9), [4., 0., 0.]);
+    film.add_splat(&Point2f::new(2., 2.), [100., 0., 0.]);
+    let rgb = film.rgb(0.5);
+    assert_eq!(rgb.len(), 200);
+    assert_eq!(film.cropped_size(), (20, 10));
+    assert_eq!(rgb[0], [20., 2., 0.]);
+    assert_eq!(rgb[2], [2. * (12. + 4.), 2., 0.]);
+
+    // Importance sampled filtering keeps each sample in its own pixel
+    let film = Film::new(&resolution, &crop, &BoxFilter::new(&Vector2f::new(1., 1.)), 1.);
+    let left = Bounds2i::from((Point2 { x: 0, y: 0 }, Point2 { x: 13, y: 40 }));
+    let right = Bounds2i::from((Point2 { x: 13, y: 0 }, Point2 { x: 40, y: 40 }));
+    let mut tile = film.pixel_tile(&left);
+    let other = film.pixel_tile(&right);
+    assert_eq!(tile.pixel_bounds(), Bounds2i::from((Point2 { x: 10, y: 10 }, Point2 { x: 13, y: 20 })));
+    assert_eq!(other.pixel_bounds(), Bounds2i::from((Point2 { x: 13, y: 10 }, Point2 { x: 30, y: 20 })));
+    let p_pixel = Point2 { x: 12, y: 15 };
+    tile.add_pixel_sample(&p_pixel, [2., 0., 0.], 1.);
+    tile.add_pixel_sample(&p_pixel, [4., 0., 0.], 0.5);
+    tile.add_pixel_sample(&Point2 { x: 0, y: 0 }, [4., 0., 0.], 1.);
+    tile.add_pixel_sample(&Point2 { x: 13, y: 15 }, [4., 0., 0.], 1.);
+    film.merge_film_tile(tile);
+    film.merge_film_tile(other);
+    assert_eq!(film.pixel(&p_pixel), Pixel { rgb: [4., 0., 0.], filter_weight_sum: 1.5 });
+    assert_eq!(film.pixel(&Point2 { x: 13, y: 15 }).filter_weight_sum, 0.);
+}
@@ -0,0 +1,38 @@
+use geometry::lerp;
+use geometry::point::Point2f;
+use geometry::vector::Vector2f;
+
+use super::{Filter, FilterSample};
+
+/// Equal weight over the whole support. Cheap, but prone to aliasing.
+#[derive(Debug, Clone)]
+pub struct BoxFilter {
+    radius: Vector2f,
+}
+
+impl BoxFilter {
+    pub fn new(radius: &Vector2f) -> BoxFilter {
+        BoxFilter { radius: *radius }
+    }
+}
+
+impl Filter for BoxFilter {
+    fn radius(&self) -> Vector2f {
+        self.radius
+    }
+
+    fn evaluate(&self, p: &Point2f) -> f32 {
+        if p.x.abs() <= self.radius.x && p.y.abs() <= self.radius.y {
+            1.
+        } else {
+            0.
+        }
+    }
+
+    fn sample(&self, u: &Point2f) -> FilterSample {
+        FilterSample {
+            p: Point2f::new(lerp(u.x, -self.radius.x, self.radius.x), lerp(u.y, -self.radius.y, self.radius.y)),
+            weight: 1.,
+        }
+    }
+}
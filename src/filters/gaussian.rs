@@ -0,0 +1,47 @@
+use geometry::point::Point2f;
+use geometry::vector::Vector2f;
+
+use super::{Filter, FilterSample, FilterSampler};
+
+/// Gaussian `exp(-alpha d^2)`, shifted down by its value at the radius so it
+/// goes to zero at the edge of the support. Larger `alpha` falls off faster.
+#[derive(Debug, Clone)]
+pub struct GaussianFilter {
+    radius: Vector2f,
+    alpha: f32,
+    exp_x: f32,
+    exp_y: f32,
+    sampler: FilterSampler,
+}
+
+impl GaussianFilter {
+    pub fn new(radius: &Vector2f, alpha: f32) -> GaussianFilter {
+        let exp_x = (-alpha * radius.x * radius.x).exp();
+        let exp_y = (-alpha * radius.y * radius.y).exp();
+        GaussianFilter {
+            radius: *radius,
+            alpha,
+            exp_x,
+            exp_y,
+            sampler: FilterSampler::new(radius, |p| gaussian(p.x, alpha, exp_x) * gaussian(p.y, alpha, exp_y)),
+        }
+    }
+}
+
+fn gaussian(d: f32, alpha: f32, exp_v: f32) -> f32 {
+    ((-alpha * d * d).exp() - exp_v).max(0.)
+}
+
+impl Filter for GaussianFilter {
+    fn radius(&self) -> Vector2f {
+        self.radius
+    }
+
+    fn evaluate(&self, p: &Point2f) -> f32 {
+        gaussian(p.x, self.alpha, self.exp_x) * gaussian(p.y, self.alpha, self.exp_y)
+    }
+
+    fn sample(&self, u: &Point2f) -> FilterSample {
+        self.sampler.sample(u)
+    }
+}
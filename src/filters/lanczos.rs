@@ -0,0 +1,58 @@
+use std::f32::consts::PI;
+
+use geometry::point::Point2f;
+use geometry::vector::Vector2f;
+
+use super::{Filter, FilterSample, FilterSampler};
+
+/// Sinc windowed by a wider sinc, `tau` being the number of sinc lobes
+/// inside the window.
+#[derive(Debug, Clone)]
+pub struct LanczosSincFilter {
+    radius: Vector2f,
+    tau: f32,
+    sampler: FilterSampler,
+}
+
+impl LanczosSincFilter {
+    pub fn new(radius: &Vector2f, tau: f32) -> LanczosSincFilter {
+        let (rx, ry) = (radius.x, radius.y);
+        LanczosSincFilter {
+            radius: *radius,
+            tau,
+            sampler: FilterSampler::new(radius, |p| windowed_sinc(p.x, rx, tau) * windowed_sinc(p.y, ry, tau)),
+        }
+    }
+}
+
+fn sinc(x: f32) -> f32 {
+    let x = x.abs();
+    if x < 1e-5 {
+        1.
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+fn windowed_sinc(x: f32, radius: f32, tau: f32) -> f32 {
+    let x = x.abs();
+    if x > radius {
+        0.
+    } else {
+        sinc(x) * sinc(x / tau)
+    }
+}
+
+impl Filter for LanczosSincFilter {
+    fn radius(&self) -> Vector2f {
+        self.radius
+    }
+
+    fn evaluate(&self, p: &Point2f) -> f32 {
+        windowed_sinc(p.x, self.radius.x, self.tau) * windowed_sinc(p.y, self.radius.y, self.tau)
+    }
+
+    fn sample(&self, u: &Point2f) -> FilterSample {
+        self.sampler.sample(u)
+    }
+}
@@ -0,0 +1,58 @@
+use geometry::point::Point2f;
+use geometry::vector::Vector2f;
+
+use super::{Filter, FilterSample, FilterSampler};
+
+/// Mitchell-Netravali cubic (Mitchell and Netravali 1988). `b` and `c` trade
+/// blurring against ringing; `b + 2c = 1` is the recommended family and
+/// `b = c = 1/3` the usual choice.
+#[derive(Debug, Clone)]
+pub struct MitchellFilter {
+    radius: Vector2f,
+    inv_radius: Vector2f,
+    b: f32,
+    c: f32,
+    sampler: FilterSampler,
+}
+
+impl MitchellFilter {
+    pub fn new(radius: &Vector2f, b: f32, c: f32) -> MitchellFilter {
+        let inv_radius = Vector2f::new(1. / radius.x, 1. / radius.y);
+        MitchellFilter {
+            radius: *radius,
+            inv_radius,
+            b,
+            c,
+            sampler: FilterSampler::new(radius, |p| {
+                mitchell_1d(p.x * inv_radius.x, b, c) * mitchell_1d(p.y * inv_radius.y, b, c)
+            }),
+        }
+    }
+}
+
+/// Cubic over [-1, 1], the radius mapped to the filter's [-2, 2] domain.
+fn mitchell_1d(x: f32, b: f32, c: f32) -> f32 {
+    let x = (2. * x).abs();
+    if x > 2. {
+        0.
+    } else if x > 1. {
+        ((-b - 6. * c) * x * x * x + (6. * b + 30. * c) * x * x + (-12. * b - 48. * c) * x + (8. * b + 24. * c))
+            * (1. / 6.)
+    } else {
+        ((12. - 9. * b - 6. * c) * x * x * x + (-18. + 12. * b + 6. * c) * x * x + (6. - 2. * b)) * (1. / 6.)
+    }
+}
+
+impl Filter for MitchellFilter {
+    fn radius(&self) -> Vector2f {
+        self.radius
+    }
+
+    fn evaluate(&self, p: &Point2f) -> f32 {
+        mitchell_1d(p.x * self.inv_radius.x, self.b, self.c) * mitchell_1d(p.y * self.inv_radius.y, self.b, self.c)
+    }
+
+    fn sample(&self, u: &Point2f) -> FilterSample {
+        self.sampler.sample(u)
+    }
+}
@@ -0,0 +1,226 @@
+pub mod boxfilter;
+pub mod gaussian;
+pub mod lanczos;
+pub mod mitchell;
+pub mod triangle;
+
+use geometry::lerp;
+use geometry::point::Point2f;
+use geometry::vector::Vector2f;
+use sampling::Distribution2D;
+
+/// Reconstruction filter weighting the contribution of a sample to the
+/// pixels around it.
+pub trait Filter {
+    /// Half width of the filter support in x and y.
+    fn radius(&self) -> Vector2f;
+
+    /// Weight at offset `p` from the filter center. Zero outside the radius.
+    fn evaluate(&self, p: &Point2f) -> f32;
+
+    /// Draws an offset from the filter center roughly proportional to the
+    /// filter. Adding each sample to the single pixel it was drawn for,
+    /// scaled by the returned weight, matches filtering with `evaluate` up
+    /// to a constant factor.
+    fn sample(&self, u: &Point2f) -> FilterSample;
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct FilterSample {
+    pub p: Point2f,
+    pub weight: f32,
+}
+
+/// Samples filters without an analytic inverse from their absolute value,
+/// tabulated at 32 cells per unit of radius. Negative lobes come back as
+/// negative weights.
+#[derive(Debug, Clone)]
+pub struct FilterSampler {
+    radius: Vector2f,
+    nx: usize,
+    ny: usize,
+    func: Vec<f32>,
+    distribution: Distribution2D,
+}
+
+impl FilterSampler {
+    pub fn new<F: Fn(&Point2f) -> f32>(radius: &Vector2f, evaluate: F) -> FilterSampler {
+        let nx = ((32. * radius.x) as usize).max(1);
+        let ny = ((32. * radius.y) as usize).max(1);
+        let mut func = Vec::with_capacity(nx * ny);
+        for y in 0..ny {
+            for x in 0..nx {
+                let p = Point2f::new(
+                    lerp((x as f32 + 0.5) / nx as f32, -radius.x, radius.x),
+                    lerp((y as f32 + 0.5) / ny as f32, -radius.y, radius.y),
+                );
+                func.push(evaluate(&p));
+            }
+        }
+        let abs_func: Vec<f32> = func.iter().map(|f| f.abs()).collect();
+        FilterSampler {
+            radius: *radius,
+            nx,
+            ny,
+            distribution: Distribution2D::new(&abs_func, nx, ny),
+            func,
+        }
+    }
+
+    pub fn sample(&self, u: &Point2f) -> FilterSample {
+        let (p01, pdf01) = self.distribution.sample_continuous(u);
+        let p = Point2f::new(
+            lerp(p01.x, -self.radius.x, self.radius.x),
+            lerp(p01.y, -self.radius.y, self.radius.y),
+        );
+        // Density with respect to the filter's domain rather than [0, 1]^2
+        let pdf = pdf01 / (4. * self.radius.x * self.radius.y);
+        let x = ((p01.x * self.nx as f32) as usize).min(self.nx - 1);
+        let y = ((p01.y * self.ny as f32) as usize).min(self.ny - 1);
+        let weight = if pdf > 0. {
+            self.func[y * self.nx + x] / pdf
+        } else {
+            0.
+        };
+        FilterSample { p, weight }
+    }
+}
+
+/// Number of entries of the filter table along each axis.
+pub const FILTER_TABLE_WIDTH: usize = 16;
+
+/// Filter values tabulated over the positive quadrant of the support, so
+/// the film only does lookups per sample. Filters are assumed to be
+/// symmetric in x and y.
+#[derive(Debug, Clone)]
+pub struct FilterTable {
+    pub radius: Vector2f,
+    pub inv_radius: Vector2f,
+    weights: Vec<f32>,
+}
+
+impl FilterTable {
+    pub fn new(filter: &dyn Filter) -> FilterTable {
+        let radius = filter.radius();
+        let mut weights = Vec::with_capacity(FILTER_TABLE_WIDTH * FILTER_TABLE_WIDTH);
+        for y in 0..FILTER_TABLE_WIDTH {
+            for x in 0..FILTER_TABLE_WIDTH {
+                let p = Point2f::new(
+                    (x as f32 + 0.5) * radius.x / FILTER_TABLE_WIDTH as f32,
+                    (y as f32 + 0.5) * radius.y / FILTER_TABLE_WIDTH as f32,
+                );
+                weights.push(filter.evaluate(&p));
+            }
+        }
+        FilterTable {
+            radius,
+            inv_radius: Vector2f::new(1. / radius.x, 1. / radius.y),
+            weights,
+        }
+    }
+
+    /// Table index along one axis for an offset from the filter center.
+    pub fn index(&self, offset: f32, inv_radius: f32) -> usize {
+        ((offset * inv_radius * FILTER_TABLE_WIDTH as f32).abs().floor() as usize).min(FILTER_TABLE_WIDTH - 1)
+    }
+
+    pub fn weight(&self, ix: usize, iy: usize) -> f32 {
+        self.weights[iy * FILTER_TABLE_WIDTH + ix]
+    }
+}
+
+#[test]
+fn filters_match_reference_values() {
+    use self::boxfilter::BoxFilter;
+    use self::gaussian::GaussianFilter;
+    use self::lanczos::LanczosSincFilter;
+    use self::mitchell::MitchellFilter;
+    use self::triangle::TriangleFilter;
+
+    let radius = Vector2f::new(2., 2.);
+    let p = |x: f32, y: f32| Point2f::new(x, y);
+
+    let box_filter = BoxFilter::new(&Vector2f::new(0.5, 0.5));
+    assert_eq!(box_filter.evaluate(&p(0.3, -0.4)), 1.);
+    assert_eq!(box_filter.evaluate(&p(0.6, 0.)), 0.);
+
+    let triangle = TriangleFilter::new(&radius);
+    assert_eq!(triangle.evaluate(&p(0., 0.)), 4.);
+    assert_eq!(triangle.evaluate(&p(1., -1.5)), 0.5);
+    assert_eq!(triangle.evaluate(&p(2.5, 0.)), 0.);
+
+    // Offset so the filter reaches zero at the radius
+    let gaussian = GaussianFilter::new(&radius, 2.);
+    let g = |d: f32| (-2. * d * d).exp() - (-8f32).exp();
+    assert!((gaussian.evaluate(&p(0.5, 1.)) - g(0.5) * g(1.)).abs() < 1e-6);
+    assert_eq!(gaussian.evaluate(&p(2., 0.)), 0.);
+
+    // B = C = 1/3 gives 8/9 at the center and a small negative lobe
+    let mitchell = MitchellFilter::new(&radius, 1. / 3., 1. / 3.);
+    assert!((mitchell.evaluate(&p(0., 0.)) - (8f32 / 9.).powi(2)).abs() < 1e-5);
+    assert!((mitchell.evaluate(&p(1., 0.)) - 8. / 9. * 1. / 18.).abs() < 1e-5);
+    assert!(mitchell.evaluate(&p(1.5, 0.)) < 0.);
+    assert!(mitchell.evaluate(&p(2., 0.)).abs() < 1e-6);
+
+    let lanczos = LanczosSincFilter::new(&radius, 3.);
+    assert!((lanczos.evaluate(&p(0., 0.)) - 1.).abs() < 1e-6);
+    assert!(lanczos.evaluate(&p(1., 0.)).abs() < 1e-6);
+    assert!(lanczos.evaluate(&p(1.5, 0.)) < 0.);
+    assert_eq!(lanczos.evaluate(&p(2.5, 0.)), 0.);
+
+    // The table samples the quadrant at cell centers
+    let table = FilterTable::new(&triangle);
+    assert_eq!(table.weight(0, 0), triangle.evaluate(&p(1. / 16., 1. / 16.)));
+    assert_eq!(table.weight(15, 3), triangle.evaluate(&p(31. / 16., 7. / 16.)));
+    assert_eq!(table.index(-0.3, table.inv_radius.x), 2);
+    assert_eq!(table.index(2., table.inv_radius.x), FILTER_TABLE_WIDTH - 1);
+}
+
+#[test]
+fn filter_sampling_matches_evaluation() {
+    use self::boxfilter::BoxFilter;
+    use self::gaussian::GaussianFilter;
+    use self::lanczos::LanczosSincFilter;
+    use self::mitchell::MitchellFilter;
+    use self::triangle::TriangleFilter;
+
+    let radius = Vector2f::new(2., 1.5);
+    let filters: Vec<Box<dyn Filter>> = vec![
+        Box::new(BoxFilter::new(&radius)),
+        Box::new(TriangleFilter::new(&radius)),
+        Box::new(GaussianFilter::new(&radius, 2.)),
+        Box::new(MitchellFilter::new(&radius, 1. / 3., 1. / 3.)),
+        Box::new(LanczosSincFilter::new(&radius, 3.)),
+    ];
+    // Filtered value of a smooth signal around the pixel center, once by
+    // quadrature over the support and once from filter samples
+    let signal = |p: &Point2f| 1. + 0.5 * p.x + p.y * p.y;
+    let n = 256;
+    for filter in &filters {
+        let (mut sum, mut weight_sum) = (0., 0.);
+        for j in 0..n {
+            for i in 0..n {
+                let p = Point2f::new(
+                    lerp((i as f32 + 0.5) / n as f32, -radius.x, radius.x),
+                    lerp((j as f32 + 0.5) / n as f32, -radius.y, radius.y),
+                );
+                let f = filter.evaluate(&p);
+                sum += f * signal(&p);
+                weight_sum += f;
+            }
+        }
+        let expected = sum / weight_sum;
+
+        let (mut sum, mut weight_sum) = (0., 0.);
+        for j in 0..n {
+            for i in 0..n {
+                let u = Point2f::new((i as f32 + 0.5) / n as f32, (j as f32 + 0.5) / n as f32);
+                let fs = filter.sample(&u);
+                assert!(fs.p.x.abs() <= radius.x && fs.p.y.abs() <= radius.y);
+                sum += fs.weight * signal(&fs.p);
+                weight_sum += fs.weight;
+            }
+        }
+        assert!((sum / weight_sum - expected).abs() < 0.01, "{} vs {}", sum / weight_sum, expected);
+    }
+}
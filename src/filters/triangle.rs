@@ -0,0 +1,42 @@
+use geometry::point::Point2f;
+use geometry::vector::Vector2f;
+
+use super::{Filter, FilterSample};
+
+/// Weight falling off linearly from the center to the edge of the support.
+#[derive(Debug, Clone)]
+pub struct TriangleFilter {
+    radius: Vector2f,
+}
+
+impl TriangleFilter {
+    pub fn new(radius: &Vector2f) -> TriangleFilter {
+        TriangleFilter { radius: *radius }
+    }
+}
+
+impl Filter for TriangleFilter {
+    fn radius(&self) -> Vector2f {
+        self.radius
+    }
+
+    fn evaluate(&self, p: &Point2f) -> f32 {
+        (self.radius.x - p.x.abs()).max(0.) * (self.radius.y - p.y.abs()).max(0.)
+    }
+
+    fn sample(&self, u: &Point2f) -> FilterSample {
+        FilterSample {
+            p: Point2f::new(sample_tent(u.x, self.radius.x), sample_tent(u.y, self.radius.y)),
+            weight: 1.,
+        }
+    }
+}
+
+/// Inverts the CDF of the tent of half width `r` centered at zero.
+fn sample_tent(u: f32, r: f32) -> f32 {
+    if u < 0.5 {
+        -r + r * (2. * u).sqrt()
+    } else {
+        r - r * (2. - 2. * u).sqrt()
+    }
+}
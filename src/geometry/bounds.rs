@@ -1,5 +1,6 @@
 use std::convert::From;
-use std::ops::Index;
+use std::mem;
+use std::ops::{Index, IndexMut};
 use std::iter::Iterator;
 use std::cmp::{max, min};
 
@@ -14,11 +15,19 @@ use super::point::{Point2, Point3};
 
 use num::Bounded;
 
+use util::gamma;
+use super::ray::Ray_;
+use super::vector::Vector3f;
+
 use std::ops::Add;
 use std::cmp::{PartialEq, PartialOrd};
 
 // use ::geometry::{Interpolate};
 
+pub type Bounds2f = Bounds2<f32>;
+pub type Bounds2i = Bounds2<i32>;
+pub type Bounds3f = Bounds3<f32>;
+
 pub trait Bounds {
     type Scalar;
     type Point;
@@ -40,8 +49,8 @@ pub trait Bounds {
     fn bounding_sphere(&self) -> (Self::Point, f32);
 }
 
-#[derive(Copy, Clone, Debug)]
-pub struct Bounds2<S: Scalar> {
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Bounds2<S> {
     p_min: Point2<S>,
     p_max: Point2<S>,
 }
@@ -68,7 +77,7 @@ impl<'a, S: Scalar> From<&'a Point2<S>> for Bounds2<S> {
         }
     }
 }
-impl<S: Scalar> From<(Point2<S>, Point2<S>)> for Bounds2<S> {
+impl<S> From<(Point2<S>, Point2<S>)> for Bounds2<S> {
     fn from(points: (Point2<S>, Point2<S>)) -> Self {
         Bounds2 {
             p_min: points.0,
@@ -85,7 +94,7 @@ impl<'a, S: Scalar> From<(&'a Point2<S>, &'a Point2<S>)> for Bounds2<S> {
     }
 }
 
-impl<S: Scalar> Index<u8> for Bounds2<S> {
+impl<S> Index<u8> for Bounds2<S> {
     type Output = Point2<S>;
     fn index(&self, index: u8) -> &Self::Output {
         match index {
@@ -102,7 +111,7 @@ impl<S: Scalar> Bounds for Bounds2<S> {
 
     fn corner(&self, corner: u8) -> Self::Point {
         let x = self[corner & 1].x;
-        let y = self[corner & 2].y;
+        let y = self[(corner & 2) >> 1].y;
         Point2::new(x, y)
     }
     fn point_union(b: &Self, p: &Self::Point) -> Self {
@@ -194,6 +203,40 @@ impl<S: Scalar> Bounds for Bounds2<S> {
     }
 }
 
+/// Integer bounds index pixels and are half open: `p_max` lies one past the
+/// last pixel in each direction.
+impl Bounds2<i32> {
+    pub fn area(&self) -> i32 {
+        max(0, self.p_max.x - self.p_min.x) * max(0, self.p_max.y - self.p_min.y)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.p_max.x <= self.p_min.x || self.p_max.y <= self.p_min.y
+    }
+
+    pub fn intersect(b1: &Self, b2: &Self) -> Self {
+        let p_min = Point2 {
+            x: max(b1.p_min.x, b2.p_min.x),
+            y: max(b1.p_min.y, b2.p_min.y),
+        };
+        let p_max = Point2 {
+            x: min(b1.p_max.x, b2.p_max.x),
+            y: min(b1.p_max.y, b2.p_max.y),
+        };
+        Bounds2 { p_min, p_max }
+    }
+
+    pub fn inside_exclusive(p: &Point2<i32>, b: &Self) -> bool {
+        p.x >= b.p_min.x && p.x < b.p_max.x && p.y >= b.p_min.y && p.y < b.p_max.y
+    }
+
+    /// Pixels inside the bounds in scanline order.
+    pub fn pixels(&self) -> impl Iterator<Item = Point2<i32>> {
+        let (x0, x1) = (self.p_min.x, max(self.p_min.x, self.p_max.x));
+        (self.p_min.y..self.p_max.y).flat_map(move |y| (x0..x1).map(move |x| Point2 { x, y }))
+    }
+}
+
 #[derive(Copy, Clone, Debug)]
 pub struct Bounds3<S: Scalar> {
     p_min: Point3<S>,
@@ -249,6 +292,15 @@ impl<S: Scalar> Index<u8> for Bounds3<S> {
         }
     }
 }
+impl<S: Scalar> IndexMut<u8> for Bounds3<S> {
+    fn index_mut(&mut self, index: u8) -> &mut Self::Output {
+        match index {
+            0 => &mut self.p_min,
+            1 => &mut self.p_max,
+            _ => panic!("Bounds3 Index (b[{}]) out of range", index),
+        }
+    }
+}
 impl<S: Scalar> Bounds for Bounds3<S> {
     type Scalar = S;
     type Point = Point3<S>;
@@ -256,8 +308,8 @@ impl<S: Scalar> Bounds for Bounds3<S> {
 
     fn corner(&self, corner: u8) -> Self::Point {
         let x = self[corner & 1].x;
-        let y = self[corner & 2].y;
-        let z = self[corner & 4].z;
+        let y = self[(corner & 2) >> 1].y;
+        let z = self[(corner & 4) >> 2].z;
         Point3::new(x, y, z)
     }
     fn point_union(b: &Self, p: &Self::Point) -> Self {
@@ -312,7 +364,7 @@ impl<S: Scalar> Bounds for Bounds3<S> {
     }
     fn surface_area(&self) -> Self::Scalar {
         let d = self.diagonal();
-        S::one() + S::one() * (d.x * d.y + d.x * d.z + d.y * d.z)
+        (S::one() + S::one()) * (d.x * d.y + d.x * d.z + d.y * d.z)
     }
     fn volume(&self) -> Self::Scalar {
         let d = self.diagonal();
@@ -343,6 +395,9 @@ impl<S: Scalar> Bounds for Bounds3<S> {
         if self.p_max.y > self.p_min.y {
             offset.y = offset.y / (self.p_max.y - self.p_min.y);
         }
+        if self.p_max.z > self.p_min.z {
+            offset.z = offset.z / (self.p_max.z - self.p_min.z);
+        }
         offset
     }
     fn bounding_sphere(&self) -> (Self::Point, f32) {
@@ -359,3 +414,61 @@ impl<S: Scalar> Bounds for Bounds3<S> {
         (center, radius)
     }
 }
+
+impl Bounds3<f32> {
+    /// Slab test returning the parametric range `(t0, t1)` of the ray inside the box.
+    pub fn intersect_p(&self, ray: &Ray_) -> Option<(f32, f32)> {
+        let mut t0 = 0.;
+        let mut t1 = ray.tmax.get();
+        for i in 0..3usize {
+            let inv_ray_dir = 1. / ray.d[i];
+            let mut t_near = (self.p_min[i] - ray.o[i]) * inv_ray_dir;
+            let mut t_far = (self.p_max[i] - ray.o[i]) * inv_ray_dir;
+            if t_near > t_far {
+                mem::swap(&mut t_near, &mut t_far);
+            }
+            // Make the test conservative with respect to floating point error
+            t_far *= 1. + 2. * gamma(3);
+            t0 = if t_near > t0 { t_near } else { t0 };
+            t1 = if t_far < t1 { t_far } else { t1 };
+            if t0 > t1 {
+                return None;
+            }
+        }
+        Some((t0, t1))
+    }
+
+    /// Slab test with precomputed reciprocal direction, as used during BVH traversal.
+    pub fn intersect_p_inv(&self, ray: &Ray_, inv_dir: &Vector3f, dir_is_neg: &[u8; 3]) -> bool {
+        let mut t_min = (self[dir_is_neg[0]].x - ray.o.x) * inv_dir.x;
+        let mut t_max = (self[1 - dir_is_neg[0]].x - ray.o.x) * inv_dir.x;
+        let ty_min = (self[dir_is_neg[1]].y - ray.o.y) * inv_dir.y;
+        let mut ty_max = (self[1 - dir_is_neg[1]].y - ray.o.y) * inv_dir.y;
+
+        t_max *= 1. + 2. * gamma(3);
+        ty_max *= 1. + 2. * gamma(3);
+        if t_min > ty_max || ty_min > t_max {
+            return false;
+        }
+        if ty_min > t_min {
+            t_min = ty_min;
+        }
+        if ty_max < t_max {
+            t_max = ty_max;
+        }
+
+        let tz_min = (self[dir_is_neg[2]].z - ray.o.z) * inv_dir.z;
+        let mut tz_max = (self[1 - dir_is_neg[2]].z - ray.o.z) * inv_dir.z;
+        tz_max *= 1. + 2. * gamma(3);
+        if t_min > tz_max || tz_min > t_max {
+            return false;
+        }
+        if tz_min > t_min {
+            t_min = tz_min;
+        }
+        if tz_max < t_max {
+            t_max = tz_max;
+        }
+        t_min < ray.tmax.get() && t_max > 0.
+    }
+}
@@ -1,17 +1,163 @@
+use std::f32;
+
 use super::Scalar;
 use super::{Metric, Vector, VectorSpace};
 use super::vector::Vector3f;
-use super::point::Point3f;
+use super::point::{Point2f, Point3f};
 use super::normal::Normal3f;
+use super::ray::Ray_;
 use super::{Medium, MediumInterface};
+use util::{next_float_down, next_float_up};
 
 //TODO Create Interaction Trait and implement SurfaceInteraction
+#[derive(Debug, Clone)]
 pub struct Interaction {
-    p: Point3f,
-    time: f32,
-    p_error: Vector3f,
-    wo: Vector3f,
-    n: Normal3f,
+    pub p: Point3f,
+    pub time: f32,
+    pub p_error: Vector3f,
+    // Nothing scatters light or traces media yet
+    #[allow(dead_code)]
+    pub wo: Vector3f,
+    pub n: Normal3f,
+    #[allow(dead_code)]
     medium_interface: MediumInterface,
 }
 
+impl Interaction {
+    pub fn new(p: &Point3f, n: &Normal3f, p_error: &Vector3f, wo: &Vector3f, time: f32) -> Interaction {
+        Interaction {
+            p: *p,
+            time,
+            p_error: *p_error,
+            wo: *wo,
+            n: *n,
+            medium_interface: MediumInterface {
+                inside: None,
+                outside: None,
+            },
+        }
+    }
+
+    pub fn spawn_ray(&self, d: &Vector3f) -> Ray_ {
+        let o = offset_ray_origin(&self.p, &self.p_error, &self.n, d);
+        Ray_::new(&o, d, f32::INFINITY, self.time, None)
+    }
+}
+
+impl<'a> From<&'a SurfaceInteraction> for Interaction {
+    fn from(si: &'a SurfaceInteraction) -> Self {
+        Interaction::new(&si.p, &si.n, &si.p_error, &si.wo, si.time)
+    }
+}
+
+/// Moves `p` just outside its error bounds along the normal, on the side `w` points to.
+pub fn offset_ray_origin(p: &Point3f, p_error: &Vector3f, n: &Normal3f, w: &Vector3f) -> Point3f {
+    let d = n.x.abs() * p_error.x + n.y.abs() * p_error.y + n.z.abs() * p_error.z;
+    let mut offset = Vector3f::from(n) * d;
+    if n.x * w.x + n.y * w.y + n.z * w.z < 0. {
+        offset = -offset;
+    }
+    let mut po = *p + offset;
+    for i in 0..3usize {
+        if offset[i] > 0. {
+            po[i] = next_float_up(po[i]);
+        } else if offset[i] < 0. {
+            po[i] = next_float_down(po[i]);
+        }
+    }
+    po
+}
+
+#[derive(Debug, Clone)]
+pub struct Shading {
+    pub n: Normal3f,
+    pub dpdu: Vector3f,
+    pub dpdv: Vector3f,
+    pub dndu: Normal3f,
+    pub dndv: Normal3f,
+}
+
+#[derive(Debug, Clone)]
+pub struct SurfaceInteraction {
+    pub p: Point3f,
+    pub time: f32,
+    pub p_error: Vector3f,
+    pub wo: Vector3f,
+    pub n: Normal3f,
+    pub uv: Point2f,
+    pub dpdu: Vector3f,
+    pub dpdv: Vector3f,
+    pub dndu: Normal3f,
+    pub dndv: Normal3f,
+    pub shading: Shading,
+}
+
+impl SurfaceInteraction {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        p: &Point3f,
+        p_error: &Vector3f,
+        uv: &Point2f,
+        wo: &Vector3f,
+        dpdu: &Vector3f,
+        dpdv: &Vector3f,
+        dndu: &Normal3f,
+        dndv: &Normal3f,
+        time: f32,
+        flip_normal: bool,
+    ) -> SurfaceInteraction {
+        let mut n = Normal3f::from(Vector::normalize(&dpdu.cross(dpdv)));
+        // Flip the normal for left handed transforms and reversed orientation
+        if flip_normal {
+            n = -n;
+        }
+        SurfaceInteraction {
+            p: *p,
+            time,
+            p_error: *p_error,
+            wo: *wo,
+            n,
+            uv: *uv,
+            dpdu: *dpdu,
+            dpdv: *dpdv,
+            dndu: *dndu,
+            dndv: *dndv,
+            shading: Shading {
+                n,
+                dpdu: *dpdu,
+                dpdv: *dpdv,
+                dndu: *dndu,
+                dndv: *dndv,
+            },
+        }
+    }
+
+    pub fn set_shading_geometry(
+        &mut self,
+        dpdus: &Vector3f,
+        dpdvs: &Vector3f,
+        dndus: &Normal3f,
+        dndvs: &Normal3f,
+        orientation_is_authoritative: bool,
+    ) {
+        self.shading.n = Normal3f::from(Vector::normalize(&dpdus.cross(dpdvs)));
+        if orientation_is_authoritative {
+            self.n = face_forward(&self.n, &self.shading.n);
+        } else {
+            self.shading.n = face_forward(&self.shading.n, &self.n);
+        }
+        self.shading.dpdu = *dpdus;
+        self.shading.dpdv = *dpdvs;
+        self.shading.dndu = *dndus;
+        self.shading.dndv = *dndvs;
+    }
+}
+
+/// Flips `n` so that it lies in the same hemisphere as `v`.
+pub fn face_forward(n: &Normal3f, v: &Normal3f) -> Normal3f {
+    if n.dot(v) < 0. {
+        -*n
+    } else {
+        *n
+    }
+}
@@ -4,6 +4,7 @@ use std::rc::Rc;
 pub mod bounds;
 pub mod ray;
 pub mod transform;
+pub mod quaternion;
 pub mod normal;
 pub mod vector;
 pub mod point;
@@ -80,6 +81,7 @@ pub fn radians<S: Scalar>(deg: S) -> S {
 #[derive(Debug, Clone)]
 pub struct Medium {}
 
+#[derive(Debug, Clone)]
 pub struct MediumInterface {
     inside: Option<Rc<Medium>>,
     outside: Option<Rc<Medium>>,
@@ -12,8 +12,8 @@ pub type Point2i = Point2<i32>;
 pub type Point3f = Point3<f32>;
 pub type Point3i = Point3<i32>;
 
-#[derive(Debug, Copy, Clone)]
-pub struct Point2<S: Scalar> {
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Point2<S> {
     pub x: S,
     pub y: S,
 }
@@ -249,7 +249,7 @@ impl<'a, S: Scalar> Sub<S> for &'a Point3<S> {
 impl<S: Scalar> Sub<Vector3<S>> for Point3<S> {
     type Output = Point3<S>;
     fn sub(self, other: Vector3<S>) -> Self::Output {
-        Point3::new(self.x - other.x, self.y - other.x, self.z - other.z)
+        Point3::new(self.x - other.x, self.y - other.y, self.z - other.z)
     }
 }
 
@@ -0,0 +1,145 @@
+use std::ops::{Add, Mul, Neg, Sub};
+
+use super::VectorSpace;
+use super::transform::{Matrix4, Transform};
+use super::vector::Vector3f;
+
+#[derive(Debug, Copy, Clone)]
+pub struct Quaternion {
+    pub v: Vector3f,
+    pub w: f32,
+}
+
+impl Quaternion {
+    pub fn dot(&self, other: &Quaternion) -> f32 {
+        self.v.dot(other.v) + self.w * other.w
+    }
+
+    pub fn normalize(&self) -> Quaternion {
+        *self * (1. / self.dot(self).sqrt())
+    }
+
+    /// Rotation matrix of a unit quaternion.
+    pub fn to_transform(self) -> Transform<f32> {
+        let (x, y, z, w) = (self.v.x, self.v.y, self.v.z, self.w);
+        let (xx, yy, zz) = (x * x, y * y, z * z);
+        let (xy, xz, yz) = (x * y, x * z, y * z);
+        let (wx, wy, wz) = (x * w, y * w, z * w);
+        let m = Matrix4::from_values(
+            1. - 2. * (yy + zz),
+            2. * (xy - wz),
+            2. * (xz + wy),
+            0.,
+            2. * (xy + wz),
+            1. - 2. * (xx + zz),
+            2. * (yz - wx),
+            0.,
+            2. * (xz - wy),
+            2. * (yz + wx),
+            1. - 2. * (xx + yy),
+            0.,
+            0.,
+            0.,
+            0.,
+            1.,
+        );
+        let m_inv = m.transpose();
+        Transform::from(&m, &m_inv)
+    }
+
+    /// Spherical linear interpolation between two unit quaternions.
+    pub fn slerp(t: f32, q1: &Quaternion, q2: &Quaternion) -> Quaternion {
+        let cos_theta = q1.dot(q2);
+        if cos_theta > 0.9995 {
+            return (*q1 * (1. - t) + *q2 * t).normalize();
+        }
+        let theta = cos_theta.clamp(-1., 1.).acos();
+        let thetap = theta * t;
+        let q_perp = (*q2 - *q1 * cos_theta).normalize();
+        *q1 * thetap.cos() + q_perp * thetap.sin()
+    }
+}
+
+impl<'a> From<&'a Matrix4<f32>> for Quaternion {
+    /// Extracts the rotation of a pure rotation matrix.
+    fn from(m: &'a Matrix4<f32>) -> Quaternion {
+        let trace = m[0][0] + m[1][1] + m[2][2];
+        if trace > 0. {
+            let s = (trace + 1.).sqrt();
+            let w = s / 2.;
+            let s = 0.5 / s;
+            Quaternion {
+                v: Vector3f::new(
+                    (m[2][1] - m[1][2]) * s,
+                    (m[0][2] - m[2][0]) * s,
+                    (m[1][0] - m[0][1]) * s,
+                ),
+                w,
+            }
+        } else {
+            // Pick the largest diagonal element for numerical stability
+            let next = [1usize, 2, 0];
+            let mut i = 0;
+            if m[1][1] > m[0][0] {
+                i = 1;
+            }
+            if m[2][2] > m[i][i] {
+                i = 2;
+            }
+            let j = next[i];
+            let k = next[j];
+            let mut s = (m[i][i] - (m[j][j] + m[k][k]) + 1.).sqrt();
+            let mut q = [0f32; 3];
+            q[i] = s * 0.5;
+            if s != 0. {
+                s = 0.5 / s;
+            }
+            q[j] = (m[j][i] + m[i][j]) * s;
+            q[k] = (m[k][i] + m[i][k]) * s;
+            Quaternion {
+                v: Vector3f::new(q[0], q[1], q[2]),
+                w: (m[k][j] - m[j][k]) * s,
+            }
+        }
+    }
+}
+
+impl Add for Quaternion {
+    type Output = Quaternion;
+    fn add(self, rhs: Quaternion) -> Quaternion {
+        Quaternion {
+            v: self.v + rhs.v,
+            w: self.w + rhs.w,
+        }
+    }
+}
+
+impl Sub for Quaternion {
+    type Output = Quaternion;
+    fn sub(self, rhs: Quaternion) -> Quaternion {
+        Quaternion {
+            v: self.v - rhs.v,
+            w: self.w - rhs.w,
+        }
+    }
+}
+
+impl Mul<f32> for Quaternion {
+    type Output = Quaternion;
+    fn mul(self, rhs: f32) -> Quaternion {
+        Quaternion {
+            v: self.v * rhs,
+            w: self.w * rhs,
+        }
+    }
+}
+
+impl Neg for Quaternion {
+    type Output = Quaternion;
+    fn neg(self) -> Quaternion {
+        Quaternion {
+            v: -self.v,
+            w: -self.w,
+        }
+    }
+}
@@ -19,7 +19,7 @@ pub trait Ray {
 }
 
 #[derive(Debug, Clone)]
-struct Ray_ {
+pub struct Ray_ {
     pub o: Point3f,
     pub d: Vector3f,
     pub tmax: Cell<f32>,
@@ -28,7 +28,7 @@ struct Ray_ {
 }
 
 impl Ray_ {
-    fn default() -> Self {
+    pub fn default() -> Self {
         Ray_ {
             o: Point3f::zero(),
             d: Vector3f::zero(),
@@ -37,8 +37,7 @@ impl Ray_ {
             medium: None,
         }
     }
-    fn new(
-        &self,
+    pub fn new(
         o: &Point3f,
         d: &Vector3f,
         tmax: f32,
@@ -61,7 +60,10 @@ impl Ray for Ray_ {
     }
 }
 
-struct RayDifferential {
+/// Ray with the origins and directions of rays offset by one pixel in x and
+/// y on the film, used to estimate texture footprints.
+#[derive(Debug, Clone)]
+pub struct RayDifferential {
     pub ray: Ray_,
     pub has_differential: bool,
     pub rx_origin: Point3f,
@@ -71,7 +73,7 @@ struct RayDifferential {
 }
 
 impl RayDifferential {
-    fn default() -> Self {
+    pub fn default() -> Self {
         RayDifferential {
             ray: Ray_::default(),
             has_differential: false,
@@ -81,7 +83,7 @@ impl RayDifferential {
             ry_dir: Vector3f::zero(),
         }
     }
-    fn new(ray: &Ray_) -> RayDifferential {
+    pub fn new(ray: &Ray_) -> RayDifferential {
         RayDifferential {
             ray: ray.clone(),
             has_differential: false,
@@ -92,7 +94,8 @@ impl RayDifferential {
         }
     }
 
-    fn scale_differentials(&mut self, s: f32) {
+    /// Rescales the offsets for a sample spacing of `s` pixels.
+    pub fn scale_differentials(&mut self, s: f32) {
         self.rx_origin = self.ray.o + (self.rx_origin - self.ray.o) * s;
         self.ry_origin = self.ray.o + (self.ry_origin - self.ray.o) * s;
         self.rx_dir = self.ray.d + (self.rx_dir - self.ray.d) * s;
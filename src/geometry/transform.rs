@@ -10,6 +10,11 @@ use super::normal::{Normal2, Normal3};
 use super::{radians, Metric, Vector, VectorSpace};
 use super::vector::{Vector2, Vector3};
 use super::point::{Point2, Point3};
+use super::bounds::{Bounds, Bounds3};
+use super::ray::{RayDifferential, Ray_};
+use super::interaction::{Shading, SurfaceInteraction};
+use super::quaternion::Quaternion;
+use util::gamma;
 
 #[derive(Debug)]
 pub struct InvError;
@@ -68,7 +73,7 @@ where
         ];
         Matrix4 { mat }
     }
-    fn transpose(&self) -> Self {
+    pub fn transpose(&self) -> Self {
         Matrix4::from_values(
             self.mat[0][0],
             self.mat[1][0],
@@ -88,7 +93,7 @@ where
             self.mat[3][3],
         )
     }
-    fn inverse(&self) -> Result<Self, InvError> {
+    pub fn inverse(&self) -> Result<Self, InvError> {
         let mut indxc = [0usize; 4];
         let mut indxr = [0usize; 4];
         let mut ipiv = [0usize; 4];
@@ -124,7 +129,7 @@ where
             }
             indxr[i] = irow;
             indxc[i] = icol;
-            if minv[icol][irow] == S::zero() {
+            if minv[icol][icol] == S::zero() {
                 return Err(InvError);
             }
 
@@ -144,12 +149,28 @@ where
                 }
             }
         }
-        for j in 0usize..4 {
-            for k in 0usize..4 {}
+        // Undo the column swaps in reverse order
+        for j in (0usize..4).rev() {
+            if indxr[j] != indxc[j] {
+                for row in minv.iter_mut() {
+                    row.swap(indxr[j], indxc[j]);
+                }
+            }
         }
         return Ok(Matrix4 { mat: minv });
     }
 }
+impl<S: Scalar> Index<usize> for Matrix4<S> {
+    type Output = [S; 4];
+    fn index(&self, index: usize) -> &[S; 4] {
+        &self.mat[index]
+    }
+}
+impl<S: Scalar> IndexMut<usize> for Matrix4<S> {
+    fn index_mut(&mut self, index: usize) -> &mut [S; 4] {
+        &mut self.mat[index]
+    }
+}
 impl<S: Scalar> Mul<Matrix4<S>> for Matrix4<S> {
     type Output = Self;
     fn mul(self, rhs: Matrix4<S>) -> Self {
@@ -182,6 +203,19 @@ impl<S: Scalar> Transform<S> {
         Ok(Transform { m, m_inv })
     }
 
+    /// Like `new`, but keeps a singular `m` and fills the inverse with NaN
+    /// instead of failing. Check `is_invertible` before using the inverse.
+    pub fn new_possibly_singular(m: Matrix4<S>) -> Self {
+        let m_inv = m.inverse().unwrap_or(Matrix4 {
+            mat: [[S::nan(); 4]; 4],
+        });
+        Transform { m, m_inv }
+    }
+
+    pub fn is_invertible(&self) -> bool {
+        !self.m_inv.mat.iter().flatten().any(|x| x.is_nan())
+    }
+
     pub fn from(m: &Matrix4<S>, m_inv: &Matrix4<S>) -> Self {
         Transform {
             m: m.clone(),
@@ -196,28 +230,44 @@ impl<S: Scalar> Transform<S> {
         }
     }
 
+    pub fn matrix(&self) -> &Matrix4<S> {
+        &self.m
+    }
+
+    pub fn inverse_matrix(&self) -> &Matrix4<S> {
+        &self.m_inv
+    }
+
+    pub fn swaps_handedness(&self) -> bool {
+        let m = &self.m.mat;
+        let det = m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1])
+            - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+            + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0]);
+        det < S::zero()
+    }
+
     pub fn translate(delta: &Vector3<S>) -> Self {
         let mut m = Matrix4::new();
         m.mat[0][3] = delta.x;
         m.mat[1][3] = delta.y;
         m.mat[2][3] = delta.z;
         let mut m_inv = Matrix4::new();
-        m.mat[0][3] = -delta.x;
-        m.mat[1][3] = -delta.y;
-        m.mat[2][3] = -delta.z;
+        m_inv.mat[0][3] = -delta.x;
+        m_inv.mat[1][3] = -delta.y;
+        m_inv.mat[2][3] = -delta.z;
 
         Transform { m, m_inv }
     }
 
     pub fn scale(x: S, y: S, z: S) -> Self {
         let mut m = Matrix4::new();
-        m.mat[1][1] = x;
-        m.mat[2][2] = y;
-        m.mat[3][3] = z;
+        m.mat[0][0] = x;
+        m.mat[1][1] = y;
+        m.mat[2][2] = z;
         let mut m_inv = Matrix4::new();
-        m.mat[1][1] = x.recip();
-        m.mat[2][2] = y.recip();
-        m.mat[3][3] = z.recip();
+        m_inv.mat[0][0] = x.recip();
+        m_inv.mat[1][1] = y.recip();
+        m_inv.mat[2][2] = z.recip();
 
         Transform { m, m_inv }
     }
@@ -229,7 +279,7 @@ impl<S: Scalar> Transform<S> {
         m.mat[1][1] = cos_theta;
         m.mat[1][2] = -sin_theta;
         m.mat[2][1] = sin_theta;
-        m.mat[1][1] = cos_theta;
+        m.mat[2][2] = cos_theta;
 
         let m_inv = m.transpose();
         Transform { m, m_inv }
@@ -270,8 +320,8 @@ impl<S: Scalar> Transform<S> {
 
         // Compute rotations of first basis vectors
         m.mat[0][0] = a.x * a.x + (S::one() - a.x * a.x) * cos_theta;
-        m.mat[0][1] = a.x * a.y + (S::one() - cos_theta) - a.z * sin_theta;
-        m.mat[0][2] = a.x * a.z + (S::one() - cos_theta) - a.y * sin_theta;
+        m.mat[0][1] = a.x * a.y * (S::one() - cos_theta) - a.z * sin_theta;
+        m.mat[0][2] = a.x * a.z * (S::one() - cos_theta) + a.y * sin_theta;
         m.mat[0][3] = S::zero();
 
         // Compute rotations of second basis vectors
@@ -319,6 +369,39 @@ impl<S: Scalar> Transform<S> {
             m_inv: c_t_w,
         })
     }
+
+    /// Keeps x and y and maps depths between the near plane `n` and the far
+    /// plane `f` to [0, 1].
+    pub fn orthographic(n: S, f: S) -> Self {
+        Transform::scale(S::one(), S::one(), (f - n).recip()) * Transform::translate(&Vector3::new(S::zero(), S::zero(), -n))
+    }
+
+    /// Projects camera space points onto the z = 1 plane with the field of
+    /// view `fov` in degrees mapped to [-1, 1], and depths between the near
+    /// plane `n` and the far plane `f` to [0, 1].
+    pub fn perspective(fov: S, n: S, f: S) -> Result<Self, InvError> {
+        let (zero, one) = (S::zero(), S::one());
+        let persp = Matrix4::from_values(
+            one,
+            zero,
+            zero,
+            zero,
+            zero,
+            one,
+            zero,
+            zero,
+            zero,
+            zero,
+            f / (f - n),
+            -f * n / (f - n),
+            zero,
+            zero,
+            one,
+            zero,
+        );
+        let inv_tan_ang = (radians(fov) / (one + one)).tan().recip();
+        Ok(Transform::scale(inv_tan_ang, inv_tan_ang, one) * Transform::new(persp)?)
+    }
 }
 impl<S: Scalar> Mul<Transform<S>> for Transform<S> {
     type Output = Transform<S>;
@@ -336,7 +419,7 @@ impl<'a, S: Scalar> Mul<&'a Transform<S>> for &'a Transform<S> {
     type Output = Transform<S>;
     fn mul(self, rhs: &'a Transform<S>) -> Self::Output {
         let m = &self.m * &rhs.m;
-        let m_inv = &self.m_inv * &rhs.m_inv;
+        let m_inv = &rhs.m_inv * &self.m_inv;
         Transform { m, m_inv }
     }
 }
@@ -436,3 +519,248 @@ impl<'a, S: Scalar> Mul<&'a Normal3<S>> for &'a Transform<S> {
         )
     }
 }
+impl<'a> Mul<&'a Ray_> for &'a Transform<f32> {
+    type Output = Ray_;
+    fn mul(self, rhs: &'a Ray_) -> Self::Output {
+        let o = self * rhs.o;
+        let d = self * rhs.d;
+        Ray_::new(&o, &d, rhs.tmax.get(), rhs.time, rhs.medium.clone())
+    }
+}
+impl<'a> Mul<&'a RayDifferential> for &'a Transform<f32> {
+    type Output = RayDifferential;
+    fn mul(self, rhs: &'a RayDifferential) -> Self::Output {
+        RayDifferential {
+            ray: self * &rhs.ray,
+            has_differential: rhs.has_differential,
+            rx_origin: self * rhs.rx_origin,
+            ry_origin: self * rhs.ry_origin,
+            rx_dir: self * rhs.rx_dir,
+            ry_dir: self * rhs.ry_dir,
+        }
+    }
+}
+impl<'a, S: Scalar> Mul<&'a Bounds3<S>> for &'a Transform<S> {
+    type Output = Bounds3<S>;
+    fn mul(self, rhs: &'a Bounds3<S>) -> Self::Output {
+        let mut ret = Bounds3::from(&(self * rhs.corner(0)));
+        for i in 1..8 {
+            ret = Bounds::point_union(&ret, &(self * rhs.corner(i)));
+        }
+        ret
+    }
+}
+impl<'a> Mul<&'a SurfaceInteraction> for &'a Transform<f32> {
+    type Output = SurfaceInteraction;
+    fn mul(self, si: &'a SurfaceInteraction) -> Self::Output {
+        let p = self * si.p;
+        // Bound the error introduced by the transformation itself
+        let m = &self.m.mat;
+        let mut p_error = Vector3::zero();
+        for i in 0..3usize {
+            let prev = (m[i][0] * si.p_error.x).abs() + (m[i][1] * si.p_error.y).abs()
+                + (m[i][2] * si.p_error.z).abs();
+            let abs_sum = (m[i][0] * si.p.x).abs() + (m[i][1] * si.p.y).abs()
+                + (m[i][2] * si.p.z).abs() + m[i][3].abs();
+            p_error[i] = (gamma(3) + 1.) * prev + gamma(3) * abs_sum;
+        }
+        let n = Vector::normalize(&(self * si.n));
+        let mut shading_n = Vector::normalize(&(self * si.shading.n));
+        // Keep the shading normal in the hemisphere of the geometric normal
+        if n.dot(shading_n) < 0. {
+            shading_n = -shading_n;
+        }
+        let wo = if si.wo.length_squared() > 0. {
+            Vector::normalize(&(self * si.wo))
+        } else {
+            si.wo
+        };
+        SurfaceInteraction {
+            p,
+            time: si.time,
+            p_error,
+            wo,
+            n,
+            uv: si.uv,
+            dpdu: self * si.dpdu,
+            dpdv: self * si.dpdv,
+            dndu: self * si.dndu,
+            dndv: self * si.dndv,
+            shading: Shading {
+                n: shading_n,
+                dpdu: self * si.shading.dpdu,
+                dpdv: self * si.shading.dpdv,
+                dndu: self * si.shading.dndu,
+                dndv: self * si.shading.dndv,
+            },
+        }
+    }
+}
+
+/// Keyframed transformation between two times, interpolating translation,
+/// rotation and scale separately.
+#[derive(Debug, Clone)]
+pub struct AnimatedTransform {
+    start_transform: Transform<f32>,
+    end_transform: Transform<f32>,
+    start_time: f32,
+    end_time: f32,
+    actually_animated: bool,
+    t: [Vector3<f32>; 2],
+    r: [Quaternion; 2],
+    s: [Matrix4<f32>; 2],
+}
+
+impl AnimatedTransform {
+    pub fn new(
+        start_transform: &Transform<f32>,
+        start_time: f32,
+        end_transform: &Transform<f32>,
+        end_time: f32,
+    ) -> AnimatedTransform {
+        let (t0, mut r0, s0) = decompose(&start_transform.m);
+        let (t1, mut r1, s1) = decompose(&end_transform.m);
+        // Take the shorter path for the rotation
+        if r0.dot(&r1) < 0. {
+            r1 = -r1;
+        }
+        r0 = r0.normalize();
+        AnimatedTransform {
+            start_transform: start_transform.clone(),
+            end_transform: end_transform.clone(),
+            start_time,
+            end_time,
+            actually_animated: start_transform.m.mat != end_transform.m.mat,
+            t: [t0, t1],
+            r: [r0, r1.normalize()],
+            s: [s0, s1],
+        }
+    }
+
+    pub fn is_animated(&self) -> bool {
+        self.actually_animated
+    }
+
+    /// Transform at `time`. A scale interpolated through zero is kept as the
+    /// singular matrix it is, so the result may not be invertible.
+    pub fn interpolate(&self, time: f32) -> Transform<f32> {
+        if !self.actually_animated || time <= self.start_time {
+            return self.start_transform.clone();
+        }
+        if time >= self.end_time {
+            return self.end_transform.clone();
+        }
+        let dt = (time - self.start_time) / (self.end_time - self.start_time);
+        let trans = self.t[0] * (1. - dt) + self.t[1] * dt;
+        let rotate = Quaternion::slerp(dt, &self.r[0], &self.r[1]);
+        let mut scale = Matrix4::new();
+        for i in 0..3 {
+            for j in 0..3 {
+                scale.mat[i][j] = (1. - dt) * self.s[0].mat[i][j] + dt * self.s[1].mat[i][j];
+            }
+        }
+        let scale = Transform::new_possibly_singular(scale);
+        Transform::translate(&trans) * rotate.to_transform() * scale
+    }
+
+    pub fn transform_ray(&self, r: &Ray_) -> Ray_ {
+        &self.interpolate(r.time) * r
+    }
+
+    pub fn transform_ray_differential(&self, r: &RayDifferential) -> RayDifferential {
+        &self.interpolate(r.ray.time) * r
+    }
+
+    /// Bounds of `b` over the whole time range. Without rotation the motion
+    /// is linear and the two key frames suffice. Otherwise the transformed
+    /// box is sampled at fixed time steps, and grown by the furthest a corner
+    /// can stray from the chord between two steps: `h^2 / 8` times a bound
+    /// on its acceleration.
+    pub fn motion_bounds(&self, b: &Bounds3<f32>) -> Bounds3<f32> {
+        if !self.actually_animated {
+            return &self.start_transform * b;
+        }
+        let start = &self.start_transform * b;
+        let end = &self.end_transform * b;
+        let mut bounds = Bounds::bounds_union(&start, end);
+        let cos_half_angle = self.r[0].dot(&self.r[1]).min(1.);
+        if cos_half_angle == 1. {
+            return bounds;
+        }
+        let n_steps = 128;
+        for i in 1..n_steps {
+            let t = i as f32 / n_steps as f32;
+            let time = self.start_time * (1. - t) + self.end_time * t;
+            bounds = Bounds::bounds_union(&bounds, &self.interpolate(time) * b);
+        }
+
+        // A corner moves as T(t) + R(t) S(t) p with T and S linear in t, so
+        // its acceleration is at most (w^2 + w') |S p| + 2 w |(S1 - S0) p|
+        // for the angular speed w and acceleration w' of R. These bound both
+        // slerp and the normalized lerp it uses for small angles.
+        let half_angle = cos_half_angle.acos();
+        let w = 4. * (half_angle / 2.).tan();
+        let dw = 16. * half_angle.sin() * (1. - cos_half_angle) / (1. + cos_half_angle).powi(2);
+        let mut max_acceleration = 0f32;
+        for corner in 0..8 {
+            let p = b.corner(corner);
+            let scaled = |s: &Matrix4<f32>| {
+                let m = &s.mat;
+                Vector3::new(
+                    m[0][0] * p.x + m[0][1] * p.y + m[0][2] * p.z,
+                    m[1][0] * p.x + m[1][1] * p.y + m[1][2] * p.z,
+                    m[2][0] * p.x + m[2][1] * p.y + m[2][2] * p.z,
+                )
+            };
+            let (q0, q1) = (scaled(&self.s[0]), scaled(&self.s[1]));
+            let q_max = q0.norm().max(q1.norm());
+            let acceleration = (w * w + dw) * q_max + 2. * w * (q1 - q0).norm();
+            max_acceleration = max_acceleration.max(acceleration);
+        }
+        let h = 1. / n_steps as f32;
+        Bounds::expand(&bounds, max_acceleration * h * h / 8.)
+    }
+}
+
+/// Splits `m` into translation, rotation and scale `T R S` using polar
+/// decomposition of the upper 3x3 part.
+fn decompose(m: &Matrix4<f32>) -> (Vector3<f32>, Quaternion, Matrix4<f32>) {
+    let t = Vector3::new(m.mat[0][3], m.mat[1][3], m.mat[2][3]);
+
+    let mut mm = m.clone();
+    for i in 0..3 {
+        mm.mat[i][3] = 0.;
+        mm.mat[3][i] = 0.;
+    }
+    mm.mat[3][3] = 1.;
+
+    // Average R with its inverse transpose until it converges to a rotation
+    let mut r = mm.clone();
+    for _ in 0..100 {
+        let r_it = match r.transpose().inverse() {
+            Ok(r_it) => r_it,
+            Err(_) => break,
+        };
+        let mut r_next = Matrix4::new();
+        for i in 0..4 {
+            for j in 0..4 {
+                r_next.mat[i][j] = 0.5 * (r.mat[i][j] + r_it.mat[i][j]);
+            }
+        }
+        let mut norm = 0f32;
+        for i in 0..3 {
+            let n = (r.mat[i][0] - r_next.mat[i][0]).abs() + (r.mat[i][1] - r_next.mat[i][1]).abs()
+                + (r.mat[i][2] - r_next.mat[i][2]).abs();
+            norm = norm.max(n);
+        }
+        r = r_next;
+        if norm <= 0.0001 {
+            break;
+        }
+    }
+    // A singular matrix has no unique rotation, keep all of it in the scale
+    match r.inverse() {
+        Ok(r_inv) => (t, Quaternion::from(&r), &r_inv * &mm),
+        Err(_) => (t, Quaternion::from(&Matrix4::new()), mm),
+    }
+}
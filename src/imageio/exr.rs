@@ -0,0 +1,540 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use film::Film;
+use geometry::bounds::Bounds2i;
+use geometry::point::{Point2, Point2i};
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum PixelType {
+    Half,
+    Float,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Compression {
+    None,
+    /// Byte-wise run length encoding, lossless and cheap; worthwhile for
+    /// flat regions such as mattes.
+    Rle,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Layout {
+    Scanline,
+    /// Single resolution tiles of the given size in pixels, neither of
+    /// which may be zero.
+    Tiled { width: u32, height: u32 },
+}
+
+#[derive(Debug, Clone)]
+pub struct Channel {
+    pub name: String,
+    pub pixel_type: PixelType,
+    /// Values over the data window in scanline order.
+    pub data: Vec<f32>,
+}
+
+/// OpenEXR image with any number of channels covering the data window,
+/// written as a single part file. Channels named `layer.R` and so on form
+/// the layers compositing packages show for render passes.
+#[derive(Debug, Clone)]
+pub struct ExrImage {
+    pub display_window: Bounds2i,
+    pub data_window: Bounds2i,
+    pub layout: Layout,
+    pub compression: Compression,
+    channels: Vec<Channel>,
+}
+
+impl ExrImage {
+    pub fn new(display_window: &Bounds2i, data_window: &Bounds2i, layout: Layout, compression: Compression) -> ExrImage {
+        ExrImage {
+            display_window: *display_window,
+            data_window: *data_window,
+            layout,
+            compression,
+            channels: Vec::new(),
+        }
+    }
+
+    /// Image for the pixels stored by `film`: the display window is the full
+    /// resolution and the data window the crop.
+    pub fn for_film(film: &Film, layout: Layout, compression: Compression) -> ExrImage {
+        let display_window = Bounds2i::from((Point2 { x: 0, y: 0 }, film.full_resolution));
+        ExrImage::new(&display_window, &film.cropped_pixel_bounds, layout, compression)
+    }
+
+    pub fn channels(&self) -> &[Channel] {
+        &self.channels
+    }
+
+    pub fn add_channel(&mut self, name: &str, pixel_type: PixelType, data: Vec<f32>) -> io::Result<()> {
+        if data.len() != self.data_window.area() as usize {
+            return Err(invalid_input(format!(
+                "channel {} has {} values for a data window of {} pixels",
+                name,
+                data.len(),
+                self.data_window.area()
+            )));
+        }
+        if name.is_empty() || self.channels.iter().any(|c| c.name == name) {
+            return Err(invalid_input(format!("channel name {:?} is empty or already used", name)));
+        }
+        // Even long name files store at most 255 bytes per name
+        if name.len() > 255 {
+            return Err(invalid_input(format!("channel name of {} bytes is too long", name.len())));
+        }
+        self.channels.push(Channel {
+            name: name.to_string(),
+            pixel_type,
+            data,
+        });
+        Ok(())
+    }
+
+    /// Adds R, G and B channels, prefixed by `layer` unless it is empty.
+    pub fn add_rgb_layer(&mut self, layer: &str, pixel_type: PixelType, rgb: &[[f32; 3]]) -> io::Result<()> {
+        for (c, suffix) in ["R", "G", "B"].iter().enumerate() {
+            let name = if layer.is_empty() {
+                suffix.to_string()
+            } else {
+                format!("{}.{}", layer, suffix)
+            };
+            self.add_channel(&name, pixel_type, rgb.iter().map(|p| p[c]).collect())?;
+        }
+        Ok(())
+    }
+
+    pub fn write_file(&self, path: &Path) -> io::Result<()> {
+        let mut w = BufWriter::new(File::create(path)?);
+        self.write(&mut w)?;
+        w.flush()
+    }
+
+    pub fn write<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        if self.channels.is_empty() || self.data_window.is_empty() {
+            return Err(invalid_input("nothing to write".to_string()));
+        }
+        if let Layout::Tiled { width, height } = self.layout {
+            if width == 0 || height == 0 || width > i32::MAX as u32 || height > i32::MAX as u32 {
+                return Err(invalid_input(format!("invalid tile size {} x {}", width, height)));
+            }
+        }
+        // Channels are stored in alphabetical order
+        let mut channels: Vec<&Channel> = self.channels.iter().collect();
+        channels.sort_by(|a, b| a.name.as_bytes().cmp(b.name.as_bytes()));
+
+        let long_names = channels.iter().any(|c| c.name.len() > 31);
+        let mut version = 2u32;
+        if let Layout::Tiled { .. } = self.layout {
+            version |= 0x200;
+        }
+        if long_names {
+            version |= 0x400;
+        }
+        let mut header = Vec::new();
+        header.extend_from_slice(&[0x76, 0x2f, 0x31, 0x01]);
+        header.extend_from_slice(&version.to_le_bytes());
+        self.write_header(&mut header, &channels);
+
+        let chunks = self.chunks(&channels);
+        // The offset table follows the header, then the chunks
+        let mut offset = (header.len() + 8 * chunks.len()) as u64;
+        w.write_all(&header)?;
+        for chunk in &chunks {
+            w.write_all(&offset.to_le_bytes())?;
+            offset += chunk.len() as u64;
+        }
+        for chunk in &chunks {
+            w.write_all(chunk)?;
+        }
+        Ok(())
+    }
+
+    fn write_header(&self, out: &mut Vec<u8>, channels: &[&Channel]) {
+        let mut chlist = Vec::new();
+        for c in channels {
+            chlist.extend_from_slice(c.name.as_bytes());
+            chlist.push(0);
+            let pixel_type: i32 = match c.pixel_type {
+                PixelType::Half => 1,
+                PixelType::Float => 2,
+            };
+            chlist.extend_from_slice(&pixel_type.to_le_bytes());
+            // pLinear and reserved bytes, then x and y sampling
+            chlist.extend_from_slice(&[0, 0, 0, 0]);
+            chlist.extend_from_slice(&1i32.to_le_bytes());
+            chlist.extend_from_slice(&1i32.to_le_bytes());
+        }
+        chlist.push(0);
+        write_attribute(out, "channels", "chlist", &chlist);
+
+        let compression = match self.compression {
+            Compression::None => 0u8,
+            Compression::Rle => 1u8,
+        };
+        write_attribute(out, "compression", "compression", &[compression]);
+        write_attribute(out, "dataWindow", "box2i", &box2i(&self.data_window));
+        write_attribute(out, "displayWindow", "box2i", &box2i(&self.display_window));
+        write_attribute(out, "lineOrder", "lineOrder", &[0]);
+        write_attribute(out, "pixelAspectRatio", "float", &1f32.to_le_bytes());
+        let mut center = 0f32.to_le_bytes().to_vec();
+        center.extend_from_slice(&0f32.to_le_bytes());
+        write_attribute(out, "screenWindowCenter", "v2f", &center);
+        write_attribute(out, "screenWindowWidth", "float", &1f32.to_le_bytes());
+        if let Layout::Tiled { width, height } = self.layout {
+            let mut tiles = width.to_le_bytes().to_vec();
+            tiles.extend_from_slice(&height.to_le_bytes());
+            // One level, rounding down
+            tiles.push(0);
+            write_attribute(out, "tiles", "tiledesc", &tiles);
+        }
+        out.push(0);
+    }
+
+    /// Chunks in file order, each with its coordinates and data size.
+    fn chunks(&self, channels: &[&Channel]) -> Vec<Vec<u8>> {
+        let (d_min, d_max) = (self.data_window[0], self.data_window[1]);
+        let width = d_max.x - d_min.x;
+        let mut chunks = Vec::new();
+        match self.layout {
+            Layout::Scanline => {
+                // Both supported compressions store one scanline per chunk
+                for y in d_min.y..d_max.y {
+                    let bounds = Bounds2i::from((Point2 { x: d_min.x, y }, Point2 { x: d_max.x, y: y + 1 }));
+                    let mut chunk = y.to_le_bytes().to_vec();
+                    self.append_pixel_data(&mut chunk, channels, &bounds);
+                    chunks.push(chunk);
+                }
+            }
+            Layout::Tiled { width: tile_width, height: tile_height } => {
+                let (tw, th) = (tile_width as i32, tile_height as i32);
+                let height = d_max.y - d_min.y;
+                for ty in 0..(height + th - 1) / th {
+                    for tx in 0..(width + tw - 1) / tw {
+                        let p0 = Point2 {
+                            x: d_min.x + tx * tw,
+                            y: d_min.y + ty * th,
+                        };
+                        let p1 = Point2 {
+                            x: (p0.x + tw).min(d_max.x),
+                            y: (p0.y + th).min(d_max.y),
+                        };
+                        let mut chunk = Vec::new();
+                        for v in &[tx, ty, 0, 0] {
+                            chunk.extend_from_slice(&v.to_le_bytes());
+                        }
+                        self.append_pixel_data(&mut chunk, channels, &Bounds2i::from((p0, p1)));
+                        chunks.push(chunk);
+                    }
+                }
+            }
+        }
+        chunks
+    }
+
+    /// Appends the data size and the channel values of `bounds`, row by row
+    /// and channel by channel within each row.
+    fn append_pixel_data(&self, chunk: &mut Vec<u8>, channels: &[&Channel], bounds: &Bounds2i) {
+        let (d_min, d_max) = (self.data_window[0], self.data_window[1]);
+        let width = (d_max.x - d_min.x) as usize;
+        let (b_min, b_max) = (bounds[0], bounds[1]);
+        let mut raw = Vec::new();
+        for y in b_min.y..b_max.y {
+            let row = (y - d_min.y) as usize * width;
+            let (x0, x1) = (row + (b_min.x - d_min.x) as usize, row + (b_max.x - d_min.x) as usize);
+            for c in channels {
+                for &v in &c.data[x0..x1] {
+                    match c.pixel_type {
+                        PixelType::Half => raw.extend_from_slice(&f32_to_half(v).to_le_bytes()),
+                        PixelType::Float => raw.extend_from_slice(&v.to_le_bytes()),
+                    }
+                }
+            }
+        }
+        let data = match self.compression {
+            Compression::None => raw,
+            Compression::Rle => {
+                let compressed = rle_compress(&raw);
+                // Readers take data of the uncompressed size as stored raw
+                if compressed.len() < raw.len() {
+                    compressed
+                } else {
+                    raw
+                }
+            }
+        };
+        chunk.extend_from_slice(&(data.len() as i32).to_le_bytes());
+        chunk.extend_from_slice(&data);
+    }
+}
+
+fn invalid_input(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+fn write_attribute(out: &mut Vec<u8>, name: &str, type_name: &str, value: &[u8]) {
+    out.extend_from_slice(name.as_bytes());
+    out.push(0);
+    out.extend_from_slice(type_name.as_bytes());
+    out.push(0);
+    out.extend_from_slice(&(value.len() as i32).to_le_bytes());
+    out.extend_from_slice(value);
+}
+
+/// EXR boxes include their maximum, pixel bounds exclude it.
+fn box2i(b: &Bounds2i) -> Vec<u8> {
+    let (p_min, p_max): (Point2i, Point2i) = (b[0], b[1]);
+    let mut out = Vec::with_capacity(16);
+    for v in &[p_min.x, p_min.y, p_max.x - 1, p_max.y - 1] {
+        out.extend_from_slice(&v.to_le_bytes());
+    }
+    out
+}
+
+/// Nearest half precision value, rounding ties to even. Values beyond the
+/// half range become infinite.
+pub fn f32_to_half(f: f32) -> u16 {
+    let bits = f.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let exponent = ((bits >> 23) & 0xff) as i32;
+    let mantissa = bits & 0x7f_ffff;
+    if exponent == 0xff {
+        // Keep NaNs quiet and non-zero
+        let nan = if mantissa != 0 { 0x200 } else { 0 };
+        return sign | 0x7c00 | nan;
+    }
+    let e = exponent - 127 + 15;
+    if e >= 0x1f {
+        return sign | 0x7c00;
+    }
+    if e <= 0 {
+        if e < -10 {
+            return sign;
+        }
+        // Denormal: shift in the implicit bit and round
+        let m = mantissa | 0x80_0000;
+        let shift = (14 - e) as u32;
+        let half = 1 << (shift - 1);
+        let rounded = (m + half - 1 + ((m >> shift) & 1)) >> shift;
+        return sign | rounded as u16;
+    }
+    let rounded = mantissa + 0xfff + ((mantissa >> 13) & 1);
+    // A carry out of the mantissa correctly bumps the exponent
+    let value = (((e as u32) << 10) + (rounded >> 13)) as u16;
+    if value >= 0x7c00 {
+        sign | 0x7c00
+    } else {
+        sign | value
+    }
+}
+
+pub fn half_to_f32(h: u16) -> f32 {
+    let sign = ((h & 0x8000) as u32) << 16;
+    let exponent = ((h >> 10) & 0x1f) as u32;
+    let mantissa = (h & 0x3ff) as u32;
+    let bits = if exponent == 0 {
+        if mantissa == 0 {
+            sign
+        } else {
+            // Normalize the denormal
+            let mut e = 127 - 15 + 1;
+            let mut m = mantissa;
+            while m & 0x400 == 0 {
+                m <<= 1;
+                e -= 1;
+            }
+            sign | (e << 23) | ((m & 0x3ff) << 13)
+        }
+    } else if exponent == 0x1f {
+        sign | 0x7f80_0000 | (mantissa << 13)
+    } else {
+        sign | ((exponent + 127 - 15) << 23) | (mantissa << 13)
+    };
+    f32::from_bits(bits)
+}
+
+const MIN_RUN_LENGTH: usize = 3;
+const MAX_RUN_LENGTH: usize = 127;
+
+/// OpenEXR's RLE scheme: bytes are split into even and odd halves and delta
+/// encoded before runs of equal bytes are collapsed. Runs are stored as a
+/// count minus one and the byte, literal sequences as their negated length
+/// and the bytes.
+fn rle_compress(raw: &[u8]) -> Vec<u8> {
+    let mut t: Vec<u8> = Vec::with_capacity(raw.len());
+    t.extend(raw.iter().step_by(2));
+    t.extend(raw.iter().skip(1).step_by(2));
+    let mut p = t[0];
+    for v in t.iter_mut().skip(1) {
+        let d = v.wrapping_sub(p).wrapping_add(128);
+        p = *v;
+        *v = d;
+    }
+
+    let mut out = Vec::new();
+    let n = t.len();
+    let mut run_start = 0;
+    while run_start < n {
+        let mut run_end = run_start + 1;
+        while run_end < n && t[run_end] == t[run_start] && run_end - run_start - 1 < MAX_RUN_LENGTH {
+            run_end += 1;
+        }
+        if run_end - run_start >= MIN_RUN_LENGTH {
+            out.push((run_end - run_start - 1) as u8);
+            out.push(t[run_start]);
+            run_start = run_end;
+        } else {
+            // Extend the literal until three equal bytes start a run
+            while run_end < n
+                && (run_end + 1 >= n || t[run_end] != t[run_end + 1] || run_end + 2 >= n || t[run_end + 1] != t[run_end + 2])
+                && run_end - run_start < MAX_RUN_LENGTH
+            {
+                run_end += 1;
+            }
+            out.push((-((run_end - run_start) as i32)) as u8);
+            out.extend_from_slice(&t[run_start..run_end]);
+            run_start = run_end;
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+fn rle_decompress(data: &[u8], raw_len: usize) -> Vec<u8> {
+    let mut t = Vec::with_capacity(raw_len);
+    let mut i = 0;
+    while i < data.len() {
+        let count = data[i] as i8;
+        if count < 0 {
+            let n = (-(count as i32)) as usize;
+            t.extend_from_slice(&data[i + 1..i + 1 + n]);
+            i += 1 + n;
+        } else {
+            t.extend(std::iter::repeat_n(data[i + 1], count as usize + 1));
+            i += 2;
+        }
+    }
+    for j in 1..t.len() {
+        t[j] = t[j - 1].wrapping_add(t[j]).wrapping_sub(128);
+    }
+    let (even, odd) = t.split_at(raw_len.div_ceil(2));
+    let mut raw = Vec::with_capacity(raw_len);
+    for j in 0..raw_len {
+        raw.push(if j % 2 == 0 { even[j / 2] } else { odd[j / 2] });
+    }
+    raw
+}
+
+#[test]
+fn exr_files_round_trip() {
+    use std::collections::HashMap;
+    use std::convert::TryInto;
+
+    // Half conversion, including rounding, denormals and overflow
+    for &v in [0., 1., -2.5, 0.1, 65504., 6.1e-5, 3e-7, 1e-3].iter() {
+        let h = half_to_f32(f32_to_half(v));
+        assert!((h - v).abs() <= v.abs() * 1e-3 + 6e-8, "{} became {}", v, h);
+    }
+    assert_eq!(f32_to_half(1.), 0x3c00);
+    assert_eq!(f32_to_half(1. + 1. / 2048.), 0x3c00);
+    assert_eq!(f32_to_half(1e6), 0x7c00);
+    assert!(half_to_f32(f32_to_half(f32::NAN)).is_nan());
+
+    let raw: Vec<u8> = (0..300u32).map(|i| if i < 200 { 7 } else { (i * 37 % 251) as u8 }).collect();
+    let compressed = rle_compress(&raw);
+    assert!(compressed.len() < raw.len() / 2);
+    assert_eq!(rle_decompress(&compressed, raw.len()), raw);
+
+    // Minimal reader: header attributes, offsets and chunk payloads
+    let read_i32 = |b: &[u8], i: usize| i32::from_le_bytes([b[i], b[i + 1], b[i + 2], b[i + 3]]);
+    let parse = |file: &[u8]| {
+        assert_eq!(&file[..4], &[0x76, 0x2f, 0x31, 0x01]);
+        let mut attributes = HashMap::new();
+        let mut i = 8;
+        while file[i] != 0 {
+            let name_end = i + file[i..].iter().position(|&b| b == 0).unwrap();
+            let type_end = name_end + 1 + file[name_end + 1..].iter().position(|&b| b == 0).unwrap();
+            let size = read_i32(file, type_end + 1) as usize;
+            let name = String::from_utf8(file[i..name_end].to_vec()).unwrap();
+            attributes.insert(name, file[type_end + 5..type_end + 5 + size].to_vec());
+            i = type_end + 5 + size;
+        }
+        (attributes, i + 1)
+    };
+
+    let display = Bounds2i::from((Point2 { x: 0, y: 0 }, Point2 { x: 8, y: 6 }));
+    let data_window = Bounds2i::from((Point2 { x: 2, y: 1 }, Point2 { x: 7, y: 5 }));
+    let rgb: Vec<[f32; 3]> = (0..20).map(|i| [i as f32, 0.5, -1.]).collect();
+    let depth: Vec<f32> = (0..20).map(|i| 100. + i as f32 * 0.25).collect();
+
+    let mut scanline = ExrImage::new(&display, &data_window, Layout::Scanline, Compression::None);
+    scanline.add_rgb_layer("", PixelType::Half, &rgb).unwrap();
+    scanline.add_channel("depth.Z", PixelType::Float, depth.clone()).unwrap();
+    assert!(scanline.add_channel("R", PixelType::Half, depth.clone()).is_err());
+    assert!(scanline.add_channel("short", PixelType::Half, vec![0.; 3]).is_err());
+    let mut file = Vec::new();
+    scanline.write(&mut file).unwrap();
+    let (attributes, header_end) = parse(&file);
+    assert_eq!(attributes["dataWindow"], box2i(&data_window));
+    assert_eq!(attributes["displayWindow"], [0, 0, 0, 0, 0, 0, 0, 0, 7, 0, 0, 0, 5, 0, 0, 0]);
+    assert!(!attributes.contains_key("tiles"));
+    let chlist = &attributes["channels"];
+    assert!(chlist.starts_with(b"B\0") && chlist.windows(8).any(|w| w == b"depth.Z\0"));
+
+    // Second scanline, y = 2: B, G, R as halves, then depth as floats
+    let offset = u64::from_le_bytes(file[header_end + 8..header_end + 16].try_into().unwrap()) as usize;
+    assert_eq!(read_i32(&file, offset), 2);
+    assert_eq!(read_i32(&file, offset + 4), 5 * 2 * 3 + 5 * 4);
+    let data = &file[offset + 8..];
+    let half_at = |i: usize| half_to_f32(u16::from_le_bytes([data[2 * i], data[2 * i + 1]]));
+    assert_eq!(half_at(0), -1.);
+    assert_eq!(half_at(5), 0.5);
+    assert_eq!(half_at(10 + 3), 8.);
+    let z = f32::from_le_bytes(data[30 + 4..30 + 8].try_into().unwrap());
+    assert_eq!(z, depth[6]);
+
+    // Tiled RLE: 3 x 2 tiles of at most 2 x 2 pixels
+    let mut tiled = ExrImage::new(&display, &data_window, Layout::Tiled { width: 2, height: 2 }, Compression::Rle);
+    tiled.add_rgb_layer("diffuse", PixelType::Float, &rgb).unwrap();
+    let mut file = Vec::new();
+    tiled.write(&mut file).unwrap();
+    assert_eq!(u32::from_le_bytes(file[4..8].try_into().unwrap()), 0x202);
+    let (attributes, header_end) = parse(&file);
+    assert_eq!(attributes["tiles"], [2, 0, 0, 0, 2, 0, 0, 0, 0]);
+    assert_eq!(attributes["compression"], [1]);
+    let offsets: Vec<usize> = (0..6)
+        .map(|i| u64::from_le_bytes(file[header_end + 8 * i..header_end + 8 * i + 8].try_into().unwrap()) as usize)
+        .collect();
+    assert_eq!(offsets[0], header_end + 48);
+    // Last tile, (2, 1), holds the single column x = 6 of rows 3 and 4
+    let o = offsets[5];
+    assert_eq!((read_i32(&file, o), read_i32(&file, o + 4)), (2, 1));
+    let size = read_i32(&file, o + 16) as usize;
+    let raw_len = 2 * 3 * 4;
+    let payload = &file[o + 20..o + 20 + size];
+    let raw = if size < raw_len {
+        rle_decompress(payload, raw_len)
+    } else {
+        payload.to_vec()
+    };
+    let value = |i: usize| f32::from_le_bytes(raw[4 * i..4 * i + 4].try_into().unwrap());
+    // Row y = 4: diffuse.B, diffuse.G, diffuse.R
+    assert_eq!((value(3), value(4), value(5)), (-1., 0.5, 19.));
+    assert_eq!(o + 20 + size, file.len());
+
+    let invalid = |result: io::Result<()>| result.unwrap_err().kind() == io::ErrorKind::InvalidInput;
+    for &(width, height) in [(0, 2), (2, 0)].iter() {
+        let mut image = ExrImage::new(&display, &data_window, Layout::Tiled { width, height }, Compression::None);
+        image.add_rgb_layer("", PixelType::Half, &rgb).unwrap();
+        assert!(invalid(image.write(&mut Vec::new())));
+    }
+    let long_name = "a".repeat(256);
+    assert!(invalid(scanline.add_channel(&long_name, PixelType::Half, depth.clone())));
+    scanline.add_channel(&long_name[..255], PixelType::Half, depth.clone()).unwrap();
+    let mut file = Vec::new();
+    scanline.write(&mut file).unwrap();
+    assert_eq!(u32::from_le_bytes(file[4..8].try_into().unwrap()), 0x402);
+}
@@ -0,0 +1,4 @@
+pub mod exr;
+pub mod pfm;
+pub mod png;
+pub mod tonemap;
@@ -0,0 +1,45 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+/// Writes linear RGB values, given in scanline order from the top, as a
+/// little endian Portable Float Map, which stores rows bottom to top.
+pub fn write_pfm<W: Write>(w: &mut W, rgb: &[[f32; 3]], width: usize, height: usize) -> io::Result<()> {
+    if rgb.len() != width * height {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "pixel count does not match the size"));
+    }
+    // A negative scale marks little endian data
+    write!(w, "PF\n{} {}\n-1.0\n", width, height)?;
+    for row in rgb.chunks(width.max(1)).rev() {
+        for pixel in row {
+            for v in pixel {
+                w.write_all(&v.to_le_bytes())?;
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn write_pfm_file(path: &Path, rgb: &[[f32; 3]], width: usize, height: usize) -> io::Result<()> {
+    let mut w = BufWriter::new(File::create(path)?);
+    write_pfm(&mut w, rgb, width, height)?;
+    w.flush()
+}
+
+#[test]
+fn pfm_rows_are_stored_bottom_up() {
+    let rgb: Vec<[f32; 3]> = (0..6).map(|i| [i as f32, 0.5, -2.]).collect();
+    let mut file = Vec::new();
+    write_pfm(&mut file, &rgb, 3, 2).unwrap();
+    let header = b"PF\n3 2\n-1.0\n";
+    assert!(file.starts_with(header));
+    assert_eq!(file.len(), header.len() + 6 * 12);
+    let value = |i: usize| {
+        let o = header.len() + 4 * i;
+        f32::from_le_bytes([file[o], file[o + 1], file[o + 2], file[o + 3]])
+    };
+    // The first stored pixel is the bottom left one
+    assert_eq!((value(0), value(1), value(2)), (3., 0.5, -2.));
+    assert_eq!(value(15), 2.);
+    assert!(write_pfm(&mut Vec::new(), &rgb, 4, 2).is_err());
+}
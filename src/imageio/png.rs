@@ -0,0 +1,199 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use super::tonemap::ToneMapping;
+
+/// Largest payload of a stored deflate block.
+const MAX_STORED_BLOCK: usize = 65535;
+
+/// Writes an 8-bit RGB PNG from linear values in scanline order, tone
+/// mapped with `tone_mapping`. The image data is stored without deflate
+/// compression, which keeps the writer small at the cost of file size.
+pub fn write_png<W: Write>(
+    w: &mut W,
+    rgb: &[[f32; 3]],
+    width: usize,
+    height: usize,
+    tone_mapping: &ToneMapping,
+) -> io::Result<()> {
+    if rgb.len() != width * height || width == 0 || height == 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "pixel count does not match the size"));
+    }
+    let pixels = tone_mapping.encode_srgb8(rgb, width);
+    // Every row starts with its filter type, none
+    let mut raw = Vec::with_capacity(height * (3 * width + 1));
+    for row in pixels.chunks(3 * width) {
+        raw.push(0);
+        raw.extend_from_slice(row);
+    }
+
+    w.write_all(&[0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a])?;
+    let mut ihdr = Vec::with_capacity(13);
+    ihdr.extend_from_slice(&(width as u32).to_be_bytes());
+    ihdr.extend_from_slice(&(height as u32).to_be_bytes());
+    // Bit depth 8, truecolor, deflate, adaptive filtering, no interlacing
+    ihdr.extend_from_slice(&[8, 2, 0, 0, 0]);
+    write_chunk(w, b"IHDR", &ihdr)?;
+    write_chunk(w, b"IDAT", &zlib_stored(&raw))?;
+    write_chunk(w, b"IEND", &[])
+}
+
+pub fn write_png_file(
+    path: &Path,
+    rgb: &[[f32; 3]],
+    width: usize,
+    height: usize,
+    tone_mapping: &ToneMapping,
+) -> io::Result<()> {
+    let mut w = BufWriter::new(File::create(path)?);
+    write_png(&mut w, rgb, width, height, tone_mapping)?;
+    w.flush()
+}
+
+fn write_chunk<W: Write>(w: &mut W, chunk_type: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    w.write_all(&(data.len() as u32).to_be_bytes())?;
+    w.write_all(chunk_type)?;
+    w.write_all(data)?;
+    let mut crc = Crc32::new();
+    crc.update(chunk_type);
+    crc.update(data);
+    w.write_all(&crc.finish().to_be_bytes())
+}
+
+/// zlib stream holding `data` in stored deflate blocks.
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let n_blocks = data.len().div_ceil(MAX_STORED_BLOCK).max(1);
+    let mut out = Vec::with_capacity(data.len() + 5 * n_blocks + 6);
+    // Deflate with a 32K window, no preset dictionary; the check bits make
+    // the header a multiple of 31
+    out.extend_from_slice(&[0x78, 0x01]);
+    let mut blocks = data.chunks(MAX_STORED_BLOCK).peekable();
+    if blocks.peek().is_none() {
+        out.extend_from_slice(&[1, 0, 0, 0xff, 0xff]);
+    }
+    while let Some(block) = blocks.next() {
+        let last = blocks.peek().is_none();
+        out.push(last as u8);
+        let len = block.len() as u16;
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(&(!len).to_le_bytes());
+        out.extend_from_slice(block);
+    }
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    const MOD_ADLER: u32 = 65521;
+    let (mut a, mut b) = (1u32, 0u32);
+    // Sums stay below 2^32 for chunks of this size before reducing
+    for chunk in data.chunks(5552) {
+        for &byte in chunk {
+            a += byte as u32;
+            b += a;
+        }
+        a %= MOD_ADLER;
+        b %= MOD_ADLER;
+    }
+    (b << 16) | a
+}
+
+/// CRC-32 with the polynomial used by PNG and zlib.
+struct Crc32 {
+    table: [u32; 256],
+    crc: u32,
+}
+
+impl Crc32 {
+    fn new() -> Crc32 {
+        let mut table = [0u32; 256];
+        for (n, entry) in table.iter_mut().enumerate() {
+            let mut c = n as u32;
+            for _ in 0..8 {
+                c = if c & 1 != 0 { 0xedb8_8320 ^ (c >> 1) } else { c >> 1 };
+            }
+            *entry = c;
+        }
+        Crc32 { table, crc: 0xffff_ffff }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.crc = self.table[((self.crc ^ byte as u32) & 0xff) as usize] ^ (self.crc >> 8);
+        }
+    }
+
+    fn finish(&self) -> u32 {
+        self.crc ^ 0xffff_ffff
+    }
+}
+
+#[test]
+fn png_chunks_and_checksums_are_valid() {
+    use super::tonemap::ToneOperator;
+
+    let mut crc = Crc32::new();
+    crc.update(b"IEND");
+    assert_eq!(crc.finish(), 0xae42_6082);
+    assert_eq!(adler32(b"Wikipedia"), 0x11e6_0398);
+    assert_eq!(adler32(&vec![255; 100_000]), {
+        let (mut a, mut b) = (1u64, 0u64);
+        for _ in 0..100_000 {
+            a = (a + 255) % 65521;
+            b = (b + a) % 65521;
+        }
+        ((b << 16) | a) as u32
+    });
+
+    // Large enough for several stored blocks
+    let (width, height) = (200, 120);
+    let rgb: Vec<[f32; 3]> = (0..width * height).map(|i| [(i % width) as f32 / width as f32, 1., 0.]).collect();
+    let tone_mapping = ToneMapping::new(ToneOperator::Clamp, 0., false);
+    let mut file = Vec::new();
+    write_png(&mut file, &rgb, width, height, &tone_mapping).unwrap();
+    assert_eq!(&file[..8], &[0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a]);
+
+    // Walk the chunks, checking their CRCs and collecting the image data
+    let mut i = 8;
+    let mut types = Vec::new();
+    let mut idat = Vec::new();
+    while i < file.len() {
+        let len = u32::from_be_bytes([file[i], file[i + 1], file[i + 2], file[i + 3]]) as usize;
+        let body = &file[i + 4..i + 8 + len];
+        let mut crc = Crc32::new();
+        crc.update(body);
+        let stored = &file[i + 8 + len..i + 12 + len];
+        assert_eq!(crc.finish().to_be_bytes(), [stored[0], stored[1], stored[2], stored[3]]);
+        types.push(body[..4].to_vec());
+        if &body[..4] == b"IDAT" {
+            idat.extend_from_slice(&body[4..]);
+        }
+        if &body[..4] == b"IHDR" {
+            assert_eq!(&body[4..12], &[0, 0, 0, 200, 0, 0, 0, 120]);
+        }
+        i += 12 + len;
+    }
+    assert_eq!(types, vec![b"IHDR".to_vec(), b"IDAT".to_vec(), b"IEND".to_vec()]);
+
+    // Unpack the stored blocks
+    assert_eq!(((idat[0] as u32) << 8 | idat[1] as u32) % 31, 0);
+    let mut raw = Vec::new();
+    let mut j = 2;
+    loop {
+        let last = idat[j] & 1 == 1;
+        let len = u16::from_le_bytes([idat[j + 1], idat[j + 2]]);
+        assert_eq!(!len, u16::from_le_bytes([idat[j + 3], idat[j + 4]]));
+        raw.extend_from_slice(&idat[j + 5..j + 5 + len as usize]);
+        j += 5 + len as usize;
+        if last {
+            break;
+        }
+    }
+    assert_eq!(raw.len(), height * (3 * width + 1));
+    assert_eq!(u32::from_be_bytes([idat[j], idat[j + 1], idat[j + 2], idat[j + 3]]), adler32(&raw));
+    let row = 7 * (3 * width + 1);
+    assert_eq!(raw[row], 0);
+    assert_eq!(&raw[row + 1..row + 4], &[0, 255, 0]);
+    assert_eq!(raw[row + 1 + 3 * 100], 188);
+}
@@ -0,0 +1,125 @@
+/// Curve mapping scene radiance to display values in [0, 1].
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ToneOperator {
+    /// Values above one are clipped.
+    Clamp,
+    /// `v / (1 + v)` per channel, compressing highlights smoothly.
+    Reinhard,
+    /// Narkowicz's fit of the ACES filmic reference rendering transform.
+    AcesFilmic,
+}
+
+/// How linear film values become 8-bit display values: scaled by the
+/// exposure in stops, tone mapped, sRGB encoded and optionally dithered
+/// before quantization to hide banding in smooth gradients.
+#[derive(Debug, Copy, Clone)]
+pub struct ToneMapping {
+    pub operator: ToneOperator,
+    pub exposure: f32,
+    pub dither: bool,
+}
+
+impl ToneMapping {
+    pub fn new(operator: ToneOperator, exposure: f32, dither: bool) -> ToneMapping {
+        ToneMapping {
+            operator,
+            exposure,
+            dither,
+        }
+    }
+
+    /// Linear display value of a linear scene value.
+    pub fn map(&self, v: f32) -> f32 {
+        let v = (v * self.exposure.exp2()).max(0.);
+        let mapped = match self.operator {
+            ToneOperator::Clamp => v,
+            ToneOperator::Reinhard => v / (1. + v),
+            ToneOperator::AcesFilmic => (v * (2.51 * v + 0.03)) / (v * (2.43 * v + 0.59) + 0.14),
+        };
+        if mapped.is_nan() {
+            0.
+        } else {
+            mapped.clamp(0., 1.)
+        }
+    }
+
+    /// 8-bit sRGB values for an image `width` pixels wide, three bytes per
+    /// pixel in scanline order.
+    pub fn encode_srgb8(&self, rgb: &[[f32; 3]], width: usize) -> Vec<u8> {
+        let mut out = Vec::with_capacity(3 * rgb.len());
+        for (i, pixel) in rgb.iter().enumerate() {
+            for (c, &v) in pixel.iter().enumerate() {
+                let encoded = srgb_encode(self.map(v)) * 255.;
+                let dither = if self.dither {
+                    dither_noise((i % width.max(1)) as u32, (i / width.max(1)) as u32, c as u32) - 0.5
+                } else {
+                    0.
+                };
+                out.push((encoded + 0.5 + dither).floor().clamp(0., 255.) as u8);
+            }
+        }
+        out
+    }
+}
+
+/// sRGB transfer function for a linear value in [0, 1].
+pub fn srgb_encode(v: f32) -> f32 {
+    if v <= 0.003_130_8 {
+        12.92 * v
+    } else {
+        1.055 * v.powf(1. / 2.4) - 0.055
+    }
+}
+
+/// Uniform value in [0, 1) from hashing the pixel and channel, so dithering
+/// is deterministic and uncorrelated between neighbours.
+fn dither_noise(x: u32, y: u32, c: u32) -> f32 {
+    let mut h = x.wrapping_mul(0x8da6_b343) ^ y.wrapping_mul(0xd816_3841) ^ c.wrapping_mul(0xcb1a_b31f);
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x7feb_352d);
+    h ^= h >> 15;
+    h = h.wrapping_mul(0x846c_a68b);
+    h ^= h >> 16;
+    (h >> 8) as f32 / (1 << 24) as f32
+}
+
+#[test]
+fn tone_operators_map_to_display_range() {
+    let clamp = ToneMapping::new(ToneOperator::Clamp, 0., false);
+    assert_eq!(clamp.map(0.25), 0.25);
+    assert_eq!(clamp.map(4.), 1.);
+    assert_eq!(clamp.map(-1.), 0.);
+    assert_eq!(clamp.map(f32::NAN), 0.);
+    // One stop up doubles the value
+    assert_eq!(ToneMapping::new(ToneOperator::Clamp, 1., false).map(0.25), 0.5);
+
+    let reinhard = ToneMapping::new(ToneOperator::Reinhard, 0., false);
+    assert_eq!(reinhard.map(1.), 0.5);
+    assert!(reinhard.map(1000.) < 1. && reinhard.map(1000.) > 0.99);
+
+    let aces = ToneMapping::new(ToneOperator::AcesFilmic, 0., false);
+    assert_eq!(aces.map(0.), 0.);
+    assert!((aces.map(0.18) - 0.2670).abs() < 1e-3);
+    assert_eq!(aces.map(100.), 1.);
+    let mut last = 0.;
+    for i in 1..100 {
+        let v = aces.map(i as f32 * 0.1);
+        assert!(v >= last);
+        last = v;
+    }
+
+    assert_eq!(srgb_encode(0.), 0.);
+    assert!((srgb_encode(1.) - 1.).abs() < 1e-6);
+    assert!((srgb_encode(0.5) - 0.7354).abs() < 1e-3);
+    assert_eq!(clamp.encode_srgb8(&[[0., 0.5, 1.]], 1), vec![0, 188, 255]);
+
+    // Dithering keeps the average of a flat area between two levels
+    let flat = vec![[0.2, 0.2, 0.2]; 64 * 64];
+    let target = srgb_encode(0.2) * 255.;
+    let plain = clamp.encode_srgb8(&flat, 64);
+    assert!(plain.iter().all(|&v| v == plain[0]));
+    let dithered = ToneMapping::new(ToneOperator::Clamp, 0., true).encode_srgb8(&flat, 64);
+    let mean = dithered.iter().map(|&v| v as f32).sum::<f32>() / dithered.len() as f32;
+    assert!(dithered.iter().any(|&v| v != dithered[0]));
+    assert!((mean - target).abs() < 0.05, "{} vs {}", mean, target);
+}
@@ -7,6 +7,25 @@ use std::default::Default;
 // use cg::prelude::*;
 mod util;
 mod geometry;
+// Modules below are not reachable from main yet
+#[allow(dead_code)]
+mod shapes;
+#[allow(dead_code)]
+mod textures;
+#[allow(dead_code)]
+mod sampling;
+#[allow(dead_code)]
+mod primitives;
+#[allow(dead_code)]
+mod accelerators;
+#[allow(dead_code)]
+mod cameras;
+#[allow(dead_code)]
+mod film;
+#[allow(dead_code)]
+mod filters;
+#[allow(dead_code)]
+mod imageio;
 
 use geometry::{Vector, VectorSpace, Metric, Point, Scalar};
 use geometry::point::Point3;
@@ -0,0 +1,248 @@
+use std::borrow::Cow;
+use std::rc::Rc;
+
+use geometry::bounds::Bounds3f;
+use geometry::interaction::SurfaceInteraction;
+use geometry::ray::Ray_;
+use geometry::transform::{AnimatedTransform, Transform};
+use shapes::Shape;
+
+/// Anything a ray can be traced against. `intersect` shortens `ray.tmax` to
+/// the closest hit found so far.
+pub trait Primitive {
+    fn world_bound(&self) -> Bounds3f;
+    fn intersect(&self, ray: &Ray_) -> Option<SurfaceInteraction>;
+    fn intersect_p(&self, ray: &Ray_) -> bool;
+}
+
+pub struct GeometricPrimitive {
+    shape: Rc<dyn Shape>,
+}
+
+impl GeometricPrimitive {
+    pub fn new(shape: Rc<dyn Shape>) -> GeometricPrimitive {
+        GeometricPrimitive { shape }
+    }
+}
+
+impl Primitive for GeometricPrimitive {
+    fn world_bound(&self) -> Bounds3f {
+        self.shape.world_bound()
+    }
+
+    fn intersect(&self, ray: &Ray_) -> Option<SurfaceInteraction> {
+        let (t_hit, isect) = self.shape.intersect(ray)?;
+        ray.tmax.set(t_hit);
+        Some(isect)
+    }
+
+    fn intersect_p(&self, ray: &Ray_) -> bool {
+        self.shape.intersect_p(ray)
+    }
+}
+
+/// Instance of a shared primitive, usually an aggregate, placed in the scene
+/// by a possibly animated transformation.
+pub struct TransformedPrimitive {
+    primitive: Rc<dyn Primitive>,
+    primitive_to_world: AnimatedTransform,
+    /// Primitive to world and world to primitive transforms of a placement
+    /// that does not move, so rays skip the interpolation.
+    fixed: Option<(Transform<f32>, Transform<f32>)>,
+}
+
+impl TransformedPrimitive {
+    pub fn new(primitive: Rc<dyn Primitive>, primitive_to_world: &Transform<f32>) -> TransformedPrimitive {
+        TransformedPrimitive::animated(
+            primitive,
+            AnimatedTransform::new(primitive_to_world, 0., primitive_to_world, 1.),
+        )
+    }
+
+    pub fn animated(primitive: Rc<dyn Primitive>, primitive_to_world: AnimatedTransform) -> TransformedPrimitive {
+        let fixed = if primitive_to_world.is_animated() {
+            None
+        } else {
+            let to_world = primitive_to_world.interpolate(0.);
+            let to_primitive = to_world.inverse();
+            Some((to_world, to_primitive))
+        };
+        TransformedPrimitive {
+            primitive,
+            primitive_to_world,
+            fixed,
+        }
+    }
+
+    fn transforms(&self, time: f32) -> Cow<'_, (Transform<f32>, Transform<f32>)> {
+        match self.fixed {
+            Some(ref fixed) => Cow::Borrowed(fixed),
+            None => {
+                let to_world = self.primitive_to_world.interpolate(time);
+                let to_primitive = to_world.inverse();
+                Cow::Owned((to_world, to_primitive))
+            }
+        }
+    }
+}
+
+impl Primitive for TransformedPrimitive {
+    fn world_bound(&self) -> Bounds3f {
+        self.primitive_to_world
+            .motion_bounds(&self.primitive.world_bound())
+    }
+
+    fn intersect(&self, r: &Ray_) -> Option<SurfaceInteraction> {
+        let transforms = self.transforms(r.time);
+        let (ref to_world, ref to_primitive) = *transforms;
+        // A scale passing through zero flattens the primitive for a moment
+        if !to_world.is_invertible() {
+            return None;
+        }
+        let ray = to_primitive * r;
+        let isect = self.primitive.intersect(&ray)?;
+        r.tmax.set(ray.tmax.get());
+        Some(to_world * &isect)
+    }
+
+    fn intersect_p(&self, r: &Ray_) -> bool {
+        let transforms = self.transforms(r.time);
+        transforms.0.is_invertible() && self.primitive.intersect_p(&(&transforms.1 * r))
+    }
+}
+
+#[test]
+fn instances_match_flattened_geometry() {
+    use accelerators::bvh::{BVHAccel, SplitMethod};
+    use accelerators::test_util::mesh_primitives as to_prims;
+    use geometry::VectorSpace;
+    use geometry::bounds::Bounds;
+    use geometry::point::Point3f;
+    use geometry::transform::Matrix4;
+    use geometry::vector::Vector3f;
+    use shapes::triangle::TriangleMesh;
+
+    let p = vec![
+        Point3f::new(-1., -1., 0.),
+        Point3f::new(1., -1., 0.),
+        Point3f::new(0., 1., 0.5),
+    ];
+    let identity = Transform::new(Matrix4::new()).unwrap();
+    let shared: Rc<dyn Primitive> = Rc::new(BVHAccel::new(
+        to_prims(TriangleMesh::new(&identity, false, vec![0, 1, 2], p.clone(), None, None)),
+        1,
+        SplitMethod::SAH,
+    ));
+
+    let placements = [
+        Transform::translate(&Vector3f::new(0., 0., 5.)),
+        Transform::translate(&Vector3f::new(3., 0., 8.)) * Transform::rotate(30., &Vector3f::new(0., 1., 1.)),
+        Transform::translate(&Vector3f::new(-3., 1., 6.)) * Transform::scale(2., 0.5, 1.),
+    ];
+    let mut instances: Vec<Rc<dyn Primitive>> = Vec::new();
+    let mut flattened: Vec<Rc<dyn Primitive>> = Vec::new();
+    for placement in &placements {
+        instances.push(Rc::new(TransformedPrimitive::new(shared.clone(), placement)));
+        flattened.extend(to_prims(TriangleMesh::new(placement, false, vec![0, 1, 2], p.clone(), None, None)));
+    }
+    let top = BVHAccel::new(instances, 1, SplitMethod::SAH);
+    let reference = BVHAccel::new(flattened, 1, SplitMethod::SAH);
+
+    for i in 0..400 {
+        let o = Point3f::new(0., 0., -2.);
+        let d = Vector3f::new((i % 20) as f32 * 0.06 - 0.6, (i / 20) as f32 * 0.04 - 0.4, 1.);
+        let ray = Ray_::new(&o, &d, f32::INFINITY, 0., None);
+        let reference_ray = Ray_::new(&o, &d, f32::INFINITY, 0., None);
+        match (top.intersect(&ray), reference.intersect(&reference_ray)) {
+            (Some(a), Some(b)) => {
+                assert!((ray.tmax.get() - reference_ray.tmax.get()).abs() < 1e-4);
+                assert!((a.p.x - b.p.x).abs() < 1e-4 && (a.p.y - b.p.y).abs() < 1e-4);
+                assert!(a.n.dot(b.n) > 0.999);
+            }
+            (None, None) => {}
+            _ => panic!("instanced and flattened hits differ"),
+        }
+    }
+
+    // Halfway through the motion the instance sits halfway along its path
+    let motion = AnimatedTransform::new(&placements[0], 0., &placements[1], 1.);
+    let moving = TransformedPrimitive::animated(shared.clone(), motion.clone());
+    let mid = &motion.interpolate(0.5) * Point3f::new(0., 0., 0.);
+    assert!((mid.x - 1.5).abs() < 1e-4 && (mid.z - 6.5).abs() < 1e-4);
+    let bound = moving.world_bound();
+    for &time in &[0., 0.25, 0.5, 0.75, 1.] {
+        let b = &motion.interpolate(time) * &shared.world_bound();
+        assert!(Bounds::inside(&b[0], &bound) && Bounds::inside(&b[1], &bound));
+    }
+}
+
+#[test]
+fn rotating_instance_bounds_cover_the_whole_motion() {
+    use geometry::bounds::{Bounds, Bounds3};
+    use geometry::point::Point3f;
+    use geometry::vector::Vector3f;
+
+    struct Speck(Bounds3f);
+    impl Primitive for Speck {
+        fn world_bound(&self) -> Bounds3f {
+            self.0
+        }
+        fn intersect(&self, _: &Ray_) -> Option<SurfaceInteraction> {
+            None
+        }
+        fn intersect_p(&self, _: &Ray_) -> bool {
+            false
+        }
+    }
+
+    // The speck sweeps a quarter circle and crosses the x axis, where it
+    // is furthest along x, halfway between the first two time samples
+    let p = Point3f::new(10., 0., 0.);
+    let axis = Vector3f::new(0., 0., 1.);
+    let offset = 90. / 256.;
+    let motion = AnimatedTransform::new(
+        &Transform::rotate(-offset, &axis),
+        0.,
+        &Transform::rotate(90. - offset, &axis),
+        1.,
+    );
+    let speck: Rc<dyn Primitive> = Rc::new(Speck(Bounds3::from(&p)));
+    let bound = TransformedPrimitive::animated(speck, motion.clone()).world_bound();
+    for i in 0..=1024 {
+        let time = i as f32 / 1024.;
+        assert!(Bounds::inside(&(&motion.interpolate(time) * p), &bound));
+    }
+    assert!(bound[1].x >= 10. && bound[1].x < 10.01);
+}
+
+#[test]
+fn flattened_instances_keep_their_singular_scale() {
+    use accelerators::bvh::{BVHAccel, SplitMethod};
+    use accelerators::test_util::random_triangles;
+    use geometry::point::Point3f;
+    use geometry::transform::Matrix4;
+    use geometry::vector::Vector3f;
+
+    // Projects onto the z = 0 plane while moving along x
+    let mut flatten = Matrix4::new();
+    flatten[0][0] = 2.;
+    flatten[2][2] = 0.;
+    let start = Transform::new_possibly_singular(flatten);
+    assert!(!start.is_invertible());
+    let end = Transform::translate(&Vector3f::new(1., 0., 0.)) * start.clone();
+    let motion = AnimatedTransform::new(&start, 0., &end, 1.);
+
+    let p = &motion.interpolate(0.5) * Point3f::new(1., 1., 5.);
+    assert_eq!((p.x, p.y, p.z), (2.5, 1., 0.));
+    assert!(!motion.interpolate(0.5).is_invertible());
+
+    // Rays hit a flattened instance with probability zero, and must not be
+    // sent through its missing inverse
+    let shared: Rc<dyn Primitive> = Rc::new(BVHAccel::new(random_triangles(20, 29), 1, SplitMethod::SAH));
+    let instance = TransformedPrimitive::animated(shared, motion);
+    for &time in [0., 0.5, 1.].iter() {
+        let ray = Ray_::new(&Point3f::new(5., 5., -1.), &Vector3f::new(0., 0., 1.), f32::INFINITY, time, None);
+        assert!(instance.intersect(&ray).is_none());
+        assert!(!instance.intersect_p(&ray));
+    }
+}
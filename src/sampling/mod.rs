@@ -0,0 +1,369 @@
+use std::f32::consts::PI;
+
+use geometry::{Metric, Vector, VectorSpace};
+use geometry::point::{Point2f, Point3f};
+use geometry::vector::Vector3f;
+use util::clamp_nan_to_low;
+
+/// Solid angles outside this range are sampled by area instead, since the
+/// spherical mappings become numerically unstable.
+pub const MIN_SPHERICAL_SAMPLE_AREA: f32 = 3e-4;
+pub const MAX_SPHERICAL_SAMPLE_AREA: f32 = 6.22;
+
+pub fn uniform_sample_sphere(u: &Point2f) -> Vector3f {
+    let z = 1. - 2. * u.x;
+    let r = (1. - z * z).max(0.).sqrt();
+    let phi = 2. * PI * u.y;
+    Vector3f::new(r * phi.cos(), r * phi.sin(), z)
+}
+
+/// Maps the unit square to the unit disk keeping adjacent samples adjacent.
+pub fn concentric_sample_disk(u: &Point2f) -> Point2f {
+    let (x, y) = (2. * u.x - 1., 2. * u.y - 1.);
+    if x == 0. && y == 0. {
+        return Point2f::new(0., 0.);
+    }
+    let (r, theta) = if x.abs() > y.abs() {
+        (x, PI / 4. * (y / x))
+    } else {
+        (y, PI / 2. - PI / 4. * (x / y))
+    };
+    Point2f::new(r * theta.cos(), r * theta.sin())
+}
+
+/// Maps the unit square to the unit sphere preserving area (Clarberg 2008):
+/// the square is folded into an octahedron whose faces are warped onto the
+/// octants of the sphere.
+pub fn equal_area_square_to_sphere(p: &Point2f) -> Vector3f {
+    let (u, v) = (2. * p.x - 1., 2. * p.y - 1.);
+    let (up, vp) = (u.abs(), v.abs());
+    let signed_distance = 1. - (up + vp);
+    let r = 1. - signed_distance.abs();
+    let phi = if r == 0. { 1. } else { (vp - up) / r + 1. } * PI / 4.;
+    let z = (1. - r * r).copysign(signed_distance);
+    let cos_phi = phi.cos().copysign(u);
+    let sin_phi = phi.sin().copysign(v);
+    let s = r * (2. - r * r).max(0.).sqrt();
+    Vector3f::new(cos_phi * s, sin_phi * s, z)
+}
+
+/// Mirrors the digits of `a` in `base` around the radix point, giving the
+/// `a`th point of the van der Corput sequence in that base.
+pub fn radical_inverse(base: u64, mut a: u64) -> f32 {
+    let inv_base = 1. / base as f64;
+    let mut reversed = 0u64;
+    let mut inv_base_n = 1.;
+    while a > 0 {
+        let next = a / base;
+        reversed = reversed * base + (a - next * base);
+        inv_base_n *= inv_base;
+        a = next;
+    }
+    ((reversed as f64 * inv_base_n) as f32).min(1. - f32::EPSILON / 2.)
+}
+
+pub fn uniform_cone_pdf(cos_theta_max: f32) -> f32 {
+    1. / (2. * PI * (1. - cos_theta_max))
+}
+
+/// Returns the first two barycentric coordinates of a uniformly sampled point.
+pub fn uniform_sample_triangle(u: &Point2f) -> Point2f {
+    let su0 = u.x.sqrt();
+    Point2f::new(1. - su0, u.y * su0)
+}
+
+pub fn spherical_direction(
+    sin_theta: f32,
+    cos_theta: f32,
+    phi: f32,
+    x: &Vector3f,
+    y: &Vector3f,
+    z: &Vector3f,
+) -> Vector3f {
+    x * (sin_theta * phi.cos()) + y * (sin_theta * phi.sin()) + z * cos_theta
+}
+
+fn angle_between(v1: &Vector3f, v2: &Vector3f) -> f32 {
+    if v1.dot(v2) < 0. {
+        PI - 2. * ((v1 + v2).norm() / 2.).min(1.).asin()
+    } else {
+        2. * ((v2 - v1).norm() / 2.).min(1.).asin()
+    }
+}
+
+fn gram_schmidt(v: &Vector3f, w: &Vector3f) -> Vector3f {
+    v - &(w * v.dot(w))
+}
+
+/// Area of the spherical triangle spanned by the unit vectors `a`, `b` and `c`.
+pub fn spherical_triangle_area(a: &Vector3f, b: &Vector3f, c: &Vector3f) -> f32 {
+    (2. * a.dot(&b.cross(c)).atan2(1. + a.dot(b) + a.dot(c) + b.dot(c))).abs()
+}
+
+/// Solid angle subtended by the triangle `v` as seen from `p`.
+pub fn triangle_solid_angle(v: &[Point3f; 3], p: &Point3f) -> f32 {
+    let a = v[0] - *p;
+    let b = v[1] - *p;
+    let c = v[2] - *p;
+    if a.length_squared() == 0. || b.length_squared() == 0. || c.length_squared() == 0. {
+        return 0.;
+    }
+    spherical_triangle_area(
+        &Vector::normalize(&a),
+        &Vector::normalize(&b),
+        &Vector::normalize(&c),
+    )
+}
+
+/// Samples a direction uniformly inside the solid angle subtended by the
+/// triangle `v` from `p` (Arvo's method) and returns the barycentrics of the
+/// corresponding point together with the solid angle pdf.
+pub fn sample_spherical_triangle(v: &[Point3f; 3], p: &Point3f, u: &Point2f) -> Option<([f32; 3], f32)> {
+    let (a, b, c) = (v[0] - *p, v[1] - *p, v[2] - *p);
+    if a.length_squared() == 0. || b.length_squared() == 0. || c.length_squared() == 0. {
+        return None;
+    }
+    let a = Vector::normalize(&a);
+    let b = Vector::normalize(&b);
+    let c = Vector::normalize(&c);
+
+    let n_ab = a.cross(&b);
+    let n_bc = b.cross(&c);
+    let n_ca = c.cross(&a);
+    if n_ab.length_squared() == 0. || n_bc.length_squared() == 0. || n_ca.length_squared() == 0. {
+        return None;
+    }
+    let n_ab = Vector::normalize(&n_ab);
+    let n_bc = Vector::normalize(&n_bc);
+    let n_ca = Vector::normalize(&n_ca);
+
+    // Angles at the spherical triangle vertices
+    let alpha = angle_between(&n_ab, &-n_ca);
+    let beta = angle_between(&n_bc, &-n_ab);
+    let gamma = angle_between(&n_ca, &-n_bc);
+
+    // Uniformly sample the sub-triangle area A'
+    let a_pi = alpha + beta + gamma;
+    let ap_pi = PI + u.x * (a_pi - PI);
+    let area = a_pi - PI;
+    if area <= 0. {
+        return None;
+    }
+    let pdf = 1. / area;
+
+    // Find cos(beta') for the point c' along the arc between a and c
+    let (sin_alpha, cos_alpha) = alpha.sin_cos();
+    let sin_phi = ap_pi.sin() * cos_alpha - ap_pi.cos() * sin_alpha;
+    let cos_phi = ap_pi.cos() * cos_alpha + ap_pi.sin() * sin_alpha;
+    let k1 = cos_phi + cos_alpha;
+    let k2 = sin_phi - sin_alpha * a.dot(b);
+    let cos_bp = clamp_nan_to_low(
+        (k2 + (k2 * cos_phi - k1 * sin_phi) * cos_alpha) / ((k2 * sin_phi + k1 * cos_phi) * sin_alpha),
+        -1.,
+        1.,
+    );
+    let sin_bp = (1. - cos_bp * cos_bp).max(0.).sqrt();
+    let cp = a * cos_bp + Vector::normalize(&gram_schmidt(&c, &a)) * sin_bp;
+
+    // Sample the arc between b and c'
+    let cos_theta = 1. - u.y * (1. - cp.dot(b));
+    let sin_theta = (1. - cos_theta * cos_theta).max(0.).sqrt();
+    let w = b * cos_theta + Vector::normalize(&gram_schmidt(&cp, &b)) * sin_theta;
+
+    // Intersect the sampled direction with the triangle for its barycentrics
+    let e1 = v[1] - v[0];
+    let e2 = v[2] - v[0];
+    let s1 = w.cross(&e2);
+    let divisor = s1.dot(e1);
+    if divisor == 0. {
+        return Some(([1. / 3., 1. / 3., 1. / 3.], pdf));
+    }
+    let inv_divisor = 1. / divisor;
+    let s = *p - v[0];
+    let mut b1 = (s.dot(s1) * inv_divisor).clamp(0., 1.);
+    let mut b2 = (w.dot(s.cross(&e1)) * inv_divisor).clamp(0., 1.);
+    let sum = b1 + b2;
+    if sum > 1. {
+        b1 /= sum;
+        b2 /= sum;
+    }
+    Some(([1. - b1 - b2, b1, b2], pdf))
+}
+
+/// Samples a point on the rectangle `s + [0, 1] * ex + [0, 1] * ey` (with
+/// perpendicular edges) uniformly by the solid angle it subtends from `p_ref`
+/// (Urena et al. 2013). Returns the point and the solid angle pdf, which is
+/// zero when the solid angle is too small for the mapping to be accurate and
+/// the caller should sample by area instead.
+pub fn sample_spherical_rectangle(
+    p_ref: &Point3f,
+    s: &Point3f,
+    ex: &Vector3f,
+    ey: &Vector3f,
+    u: &Point2f,
+) -> (Point3f, f32) {
+    let exl = ex.norm();
+    let eyl = ey.norm();
+    let rx = ex / exl;
+    let ry = ey / eyl;
+    let mut rz = rx.cross(&ry);
+
+    let d = *s - *p_ref;
+    let x0 = d.dot(rx);
+    let y0 = d.dot(ry);
+    let mut z0 = d.dot(rz);
+    // Flip z to point away from the rectangle
+    if z0 > 0. {
+        rz = -rz;
+        z0 = -z0;
+    }
+    let x1 = x0 + exl;
+    let y1 = y0 + eyl;
+    let fallback = *s + ex * u.x + ey * u.y;
+    if z0 == 0. {
+        return (fallback, 0.);
+    }
+
+    // Plane normals of the rectangle edges and the internal angles
+    let v00 = Vector3f::new(x0, y0, z0);
+    let v01 = Vector3f::new(x0, y1, z0);
+    let v10 = Vector3f::new(x1, y0, z0);
+    let v11 = Vector3f::new(x1, y1, z0);
+    let n0 = Vector::normalize(&v00.cross(&v10));
+    let n1 = Vector::normalize(&v10.cross(&v11));
+    let n2 = Vector::normalize(&v11.cross(&v01));
+    let n3 = Vector::normalize(&v01.cross(&v00));
+    let g0 = angle_between(&-n0, &n1);
+    let g1 = angle_between(&-n1, &n2);
+    let g2 = angle_between(&-n2, &n3);
+    let g3 = angle_between(&-n3, &n0);
+
+    let solid_angle = g0 + g1 + g2 + g3 - 2. * PI;
+    if solid_angle <= 0. {
+        return (fallback, 0.);
+    }
+    if solid_angle < MIN_SPHERICAL_SAMPLE_AREA {
+        return (fallback, 0.);
+    }
+    let pdf = 1. / solid_angle;
+
+    // Sample the x coordinate cu
+    let b0 = n0.z;
+    let b1 = n2.z;
+    let au = u.x * (g0 + g1 - 2. * PI) + (u.x - 1.) * (g2 + g3);
+    let fu = (au.cos() * b0 - b1) / au.sin();
+    let one_minus_epsilon = 1. - f32::EPSILON;
+    let cu = clamp_nan_to_low(
+        (1. / (fu * fu + b0 * b0).sqrt()).copysign(fu),
+        -one_minus_epsilon,
+        one_minus_epsilon,
+    );
+    let xu = clamp_nan_to_low(-(cu * z0) / (1. - cu * cu).max(0.).sqrt(), x0, x1);
+
+    // Sample the y coordinate along the chosen line
+    let dd = (xu * xu + z0 * z0).sqrt();
+    let h0 = y0 / (dd * dd + y0 * y0).sqrt();
+    let h1 = y1 / (dd * dd + y1 * y1).sqrt();
+    let hv = h0 + u.y * (h1 - h0);
+    let hvsq = hv * hv;
+    let yv = if hvsq < 1. - 1e-4 {
+        hv * dd / (1. - hvsq).sqrt()
+    } else {
+        y1
+    };
+
+    (*p_ref + (rx * xu + ry * yv + rz * z0), pdf)
+}
+
+/// Piecewise constant distribution over [0, 1] with one segment per entry
+/// of `func`.
+#[derive(Debug, Clone)]
+pub struct Distribution1D {
+    pub func: Vec<f32>,
+    pub cdf: Vec<f32>,
+    pub func_int: f32,
+}
+
+impl Distribution1D {
+    pub fn new(func: &[f32]) -> Distribution1D {
+        let n = func.len();
+        let mut cdf = vec![0.; n + 1];
+        for i in 1..=n {
+            cdf[i] = cdf[i - 1] + func[i - 1] / n as f32;
+        }
+        let func_int = cdf[n];
+        if func_int == 0. {
+            for (i, c) in cdf.iter_mut().enumerate() {
+                *c = i as f32 / n as f32;
+            }
+        } else {
+            for c in cdf.iter_mut() {
+                *c /= func_int;
+            }
+        }
+        Distribution1D {
+            func: func.to_vec(),
+            cdf,
+            func_int,
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.func.len()
+    }
+
+    /// Returns the sampled position in [0, 1), its density and the index of
+    /// the segment it lies in.
+    pub fn sample_continuous(&self, u: f32) -> (f32, f32, usize) {
+        // Last cdf entry not above u
+        let offset = (self.cdf.partition_point(|&c| c <= u).max(1) - 1).min(self.count() - 1);
+        let mut du = u - self.cdf[offset];
+        if self.cdf[offset + 1] - self.cdf[offset] > 0. {
+            du /= self.cdf[offset + 1] - self.cdf[offset];
+        }
+        let pdf = if self.func_int > 0. {
+            self.func[offset] / self.func_int
+        } else {
+            0.
+        };
+        ((offset as f32 + du) / self.count() as f32, pdf, offset)
+    }
+}
+
+/// Piecewise constant distribution over [0, 1]^2, sampled by choosing a row
+/// from the marginal distribution and a position within it from the row's
+/// conditional distribution.
+#[derive(Debug, Clone)]
+pub struct Distribution2D {
+    conditional: Vec<Distribution1D>,
+    marginal: Distribution1D,
+}
+
+impl Distribution2D {
+    /// `func` holds `nv` rows of `nu` values.
+    pub fn new(func: &[f32], nu: usize, nv: usize) -> Distribution2D {
+        let conditional: Vec<Distribution1D> = func.chunks(nu).take(nv).map(Distribution1D::new).collect();
+        let marginal_func: Vec<f32> = conditional.iter().map(|d| d.func_int).collect();
+        Distribution2D {
+            conditional,
+            marginal: Distribution1D::new(&marginal_func),
+        }
+    }
+
+    pub fn sample_continuous(&self, u: &Point2f) -> (Point2f, f32) {
+        let (d1, pdf1, v) = self.marginal.sample_continuous(u.y);
+        let (d0, pdf0, _) = self.conditional[v].sample_continuous(u.x);
+        (Point2f::new(d0, d1), pdf0 * pdf1)
+    }
+
+    pub fn pdf(&self, p: &Point2f) -> f32 {
+        let nu = self.conditional[0].count();
+        let nv = self.marginal.count();
+        let iu = ((p.x * nu as f32) as usize).min(nu - 1);
+        let iv = ((p.y * nv as f32) as usize).min(nv - 1);
+        if self.marginal.func_int == 0. {
+            return 0.;
+        }
+        self.conditional[iv].func[iu] / self.marginal.func_int
+    }
+}
@@ -0,0 +1,158 @@
+use std::rc::Rc;
+
+use geometry::bounds::Bounds3f;
+use geometry::interaction::{Interaction, SurfaceInteraction};
+use geometry::point::Point2f;
+use geometry::ray::Ray_;
+use geometry::vector::Vector3f;
+use textures::Texture;
+use util::hash_float;
+
+use super::Shape;
+
+/// Wraps a shape with an alpha texture. Hits with alpha below `threshold` are
+/// ignored, hits with fractional alpha are kept with probability alpha using a
+/// hash of the ray and the hit point, so `intersect` and `intersect_p` always
+/// agree while stacked surfaces still decide independently.
+pub struct AlphaMasked {
+    shape: Rc<dyn Shape>,
+    alpha: Rc<dyn Texture<f32>>,
+    threshold: f32,
+}
+
+impl AlphaMasked {
+    pub fn new(shape: Rc<dyn Shape>, alpha: Rc<dyn Texture<f32>>, threshold: f32) -> AlphaMasked {
+        AlphaMasked {
+            shape,
+            alpha,
+            threshold,
+        }
+    }
+
+    fn passes(&self, ray: &Ray_, si: &SurfaceInteraction) -> bool {
+        let a = self.alpha.evaluate(si);
+        if a >= 1. {
+            return true;
+        }
+        if a < self.threshold || a <= 0. {
+            return false;
+        }
+        let (o, d, p) = (&ray.o, &ray.d, &si.p);
+        hash_float(&[o.x, o.y, o.z, d.x, d.y, d.z, p.x, p.y, p.z]) < a
+    }
+}
+
+impl Shape for AlphaMasked {
+    fn object_bound(&self) -> Bounds3f {
+        self.shape.object_bound()
+    }
+
+    fn world_bound(&self) -> Bounds3f {
+        self.shape.world_bound()
+    }
+
+    fn intersect(&self, ray: &Ray_) -> Option<(f32, SurfaceInteraction)> {
+        let (t_hit, si) = self.shape.intersect(ray)?;
+        if self.passes(ray, &si) {
+            return Some((t_hit, si));
+        }
+        // Continue the ray past the cut out hit
+        let next = Interaction::from(&si).spawn_ray(&ray.d);
+        next.tmax.set(ray.tmax.get() - t_hit);
+        let (t_next, si_next) = self.intersect(&next)?;
+        Some((t_hit + t_next, si_next))
+    }
+
+    fn intersect_p(&self, ray: &Ray_) -> bool {
+        self.intersect(ray).is_some()
+    }
+
+    fn area(&self) -> f32 {
+        self.shape.area()
+    }
+
+    fn sample_area(&self, u: &Point2f) -> (Interaction, f32) {
+        self.shape.sample_area(u)
+    }
+
+    fn pdf_area(&self, it: &Interaction) -> f32 {
+        self.shape.pdf_area(it)
+    }
+
+    fn sample(&self, it: &Interaction, u: &Point2f) -> Option<(Interaction, f32)> {
+        self.shape.sample(it, u)
+    }
+
+    fn pdf(&self, it: &Interaction, wi: &Vector3f) -> f32 {
+        self.shape.pdf(it, wi)
+    }
+}
+
+#[test]
+fn fractional_alpha_is_consistent() {
+    use geometry::point::Point3f;
+    use geometry::transform::{Matrix4, Transform};
+    use textures::ConstantTexture;
+    use super::quad::Quad;
+
+    let identity = Transform::new(Matrix4::new()).unwrap();
+    let quad: Rc<dyn Shape> = Rc::new(Quad::new(
+        &identity,
+        false,
+        &Point3f::new(0., 0., 0.),
+        &Vector3f::new(1., 0., 0.),
+        &Vector3f::new(0., 1., 0.),
+    ));
+    let masked = |a: f32| AlphaMasked::new(quad.clone(), Rc::new(ConstantTexture::new(a)), 0.1);
+    let (opaque, cutout, half) = (masked(1.), masked(0.05), masked(0.5));
+
+    let mut hits = 0;
+    for i in 0..32 {
+        for j in 0..32 {
+            let o = Point3f::new((i as f32 + 0.5) / 32., (j as f32 + 0.5) / 32., 1.);
+            let ray = Ray_::new(&o, &Vector3f::new(0., 0., -1.), f32::INFINITY, 0., None);
+            assert!(opaque.intersect_p(&ray));
+            assert!(cutout.intersect(&ray).is_none());
+            let hit = half.intersect(&ray).is_some();
+            assert_eq!(hit, half.intersect_p(&ray));
+            if hit {
+                hits += 1;
+            }
+        }
+    }
+    assert!(hits > 412 && hits < 612);
+}
+
+#[test]
+fn stacked_fractional_alpha_multiplies() {
+    use geometry::point::Point3f;
+    use geometry::transform::{Matrix4, Transform};
+    use textures::ConstantTexture;
+    use super::quad::Quad;
+
+    let identity = Transform::new(Matrix4::new()).unwrap();
+    let layer = |z: f32| {
+        let quad = Rc::new(Quad::new(
+            &identity,
+            false,
+            &Point3f::new(0., 0., z),
+            &Vector3f::new(1., 0., 0.),
+            &Vector3f::new(0., 1., 0.),
+        ));
+        AlphaMasked::new(quad, Rc::new(ConstantTexture::new(0.5)), 0.1)
+    };
+    let (front, back) = (layer(0.5), layer(0.));
+
+    // Each ray gets through both layers with probability 0.5 * 0.5
+    let mut transmitted = 0;
+    for i in 0..64 {
+        for j in 0..64 {
+            let o = Point3f::new((i as f32 + 0.5) / 64., (j as f32 + 0.5) / 64., 1.);
+            let ray = Ray_::new(&o, &Vector3f::new(0., 0., -1.), f32::INFINITY, 0., None);
+            if !front.intersect_p(&ray) && !back.intersect_p(&ray) {
+                transmitted += 1;
+            }
+        }
+    }
+    assert!(transmitted > 924 && transmitted < 1124, "{}", transmitted);
+}
@@ -0,0 +1,396 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::rc::Rc;
+
+use geometry::{Metric, Point, Vector};
+use geometry::bounds::{Bounds, Bounds3, Bounds3f};
+use geometry::interaction::{face_forward, SurfaceInteraction};
+use geometry::normal::Normal3f;
+use geometry::point::{Point2f, Point3f};
+use geometry::vector::Vector3f;
+use textures::Texture;
+use util::clamp_nan_to_low;
+
+use super::triangle::TriangleMesh;
+
+/// Criterion deciding when an edge of the base mesh is split further.
+#[derive(Debug, Clone)]
+pub enum EdgeTolerance {
+    /// Maximum edge length in world space.
+    Length(f32),
+    /// Maximum projected edge length in pixels, seen from `camera_pos` with an
+    /// image resolution of `pixels_per_radian` pixels per radian of view angle.
+    Screen {
+        camera_pos: Point3f,
+        pixels_per_radian: f32,
+        max_pixels: f32,
+    },
+}
+
+impl EdgeTolerance {
+    fn needs_split(&self, p0: &Point3f, p1: &Point3f) -> bool {
+        let length = Point::distance(p0, p1);
+        match *self {
+            EdgeTolerance::Length(max_length) => length > max_length,
+            EdgeTolerance::Screen {
+                ref camera_pos,
+                pixels_per_radian,
+                max_pixels,
+            } => {
+                let mid = Point::lerp(0.5, p0, p1);
+                let dist = Point::distance(&mid, camera_pos).max(1e-4);
+                length / dist * pixels_per_radian > max_pixels
+            }
+        }
+    }
+}
+
+/// The mesh has no uv coordinates to look the displacement texture up with.
+#[derive(Debug)]
+pub struct MissingUvError;
+
+impl fmt::Display for MissingUvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Displaced mesh has no uv coordinates")
+    }
+}
+
+impl Error for MissingUvError {
+    fn description(&self) -> &str {
+        "Displacement mapping needs per vertex uv coordinates"
+    }
+}
+
+/// Displaces a triangle mesh along its normals by a scalar texture after
+/// adaptively splitting it into micro triangles.
+pub struct DisplacementMapping {
+    displacement: Rc<dyn Texture<f32>>,
+    max_displacement: f32,
+    tolerance: EdgeTolerance,
+    /// Number of times an edge of the base mesh can be bisected.
+    max_depth: u32,
+}
+
+impl DisplacementMapping {
+    /// Texture values are clamped to `[-max_displacement, max_displacement]`
+    /// so the padded bounds stay conservative.
+    pub fn new(
+        displacement: Rc<dyn Texture<f32>>,
+        max_displacement: f32,
+        tolerance: EdgeTolerance,
+        max_depth: u32,
+    ) -> DisplacementMapping {
+        DisplacementMapping {
+            displacement,
+            max_displacement: max_displacement.abs(),
+            tolerance,
+            max_depth,
+        }
+    }
+
+    /// Bounds of the displaced surface, known before tessellating. A mesh
+    /// without vertices has empty bounds, with `p_min > p_max`.
+    pub fn world_bound(&self, mesh: &TriangleMesh) -> Bounds3f {
+        let empty = Bounds3::from((
+            Point3f::new(f32::INFINITY, f32::INFINITY, f32::INFINITY),
+            Point3f::new(f32::NEG_INFINITY, f32::NEG_INFINITY, f32::NEG_INFINITY),
+        ));
+        let b = mesh.p.iter().fold(empty, |b, p| Bounds::point_union(&b, p));
+        Bounds::expand(&b, self.max_displacement)
+    }
+
+    /// Tessellates and displaces `mesh`, which needs per vertex uvs. The
+    /// default parameterization of a `Triangle` without uvs differs between
+    /// triangles sharing a vertex, which would tear the displaced surface
+    /// apart there, so such meshes are rejected.
+    pub fn displace(&self, mesh: &TriangleMesh) -> Result<TriangleMesh, MissingUvError> {
+        let base_uv = mesh.uv.clone().ok_or(MissingUvError)?;
+        let base_n = match mesh.n {
+            Some(ref n) => n.clone(),
+            None => vertex_normals(&mesh.p, &mesh.vertex_indices, None),
+        };
+        let mut tess = Tessellator {
+            p: mesh.p.clone(),
+            n: base_n,
+            uv: base_uv,
+            midpoints: HashMap::new(),
+            levels: HashMap::new(),
+            indices: Vec::with_capacity(mesh.vertex_indices.len()),
+            tolerance: &self.tolerance,
+            max_depth: self.max_depth,
+        };
+        for tri in mesh.vertex_indices.chunks(3) {
+            tess.subdivide([tri[0], tri[1], tri[2]]);
+        }
+
+        // Move every vertex along its interpolated base normal
+        let mut p = Vec::with_capacity(tess.p.len());
+        for i in 0..tess.p.len() {
+            let si = vertex_interaction(&tess.p[i], &tess.n[i], &tess.uv[i]);
+            let d = clamp_nan_to_low(self.displacement.evaluate(&si), -self.max_displacement, self.max_displacement);
+            p.push(tess.p[i] + Vector3f::from(&tess.n[i]) * d);
+        }
+        let n = vertex_normals(&p, &tess.indices, Some(&tess.n));
+
+        Ok(TriangleMesh {
+            object_to_world: mesh.object_to_world.clone(),
+            reverse_orientation: mesh.reverse_orientation,
+            transform_swaps_handedness: mesh.transform_swaps_handedness,
+            n_triangles: tess.indices.len() / 3,
+            vertex_indices: tess.indices,
+            p,
+            n: Some(n),
+            uv: Some(tess.uv),
+        })
+    }
+}
+
+struct Tessellator<'a> {
+    p: Vec<Point3f>,
+    n: Vec<Normal3f>,
+    uv: Vec<Point2f>,
+    midpoints: HashMap<(usize, usize), usize>,
+    /// Number of bisections that led to each edge created while
+    /// tessellating; edges of the base mesh are at level zero.
+    levels: HashMap<(usize, usize), u32>,
+    indices: Vec<usize>,
+    tolerance: &'a EdgeTolerance,
+    max_depth: u32,
+}
+
+fn edge_key(a: usize, b: usize) -> (usize, usize) {
+    if a < b {
+        (a, b)
+    } else {
+        (b, a)
+    }
+}
+
+impl<'a> Tessellator<'a> {
+    fn level(&self, a: usize, b: usize) -> u32 {
+        self.levels.get(&edge_key(a, b)).cloned().unwrap_or(0)
+    }
+
+    /// Whether edge `(a, b)` gets bisected. This only depends on the edge
+    /// itself, so both triangles sharing it agree, even at the depth limit.
+    fn needs_split(&self, a: usize, b: usize) -> bool {
+        self.level(a, b) < self.max_depth && self.tolerance.needs_split(&self.p[a], &self.p[b])
+    }
+
+    /// Returns the shared midpoint vertex of edge `(a, b)`, so neighbouring
+    /// triangles splitting the same edge stay crack free.
+    fn split_edge(&mut self, a: usize, b: usize) -> usize {
+        let key = edge_key(a, b);
+        if let Some(&m) = self.midpoints.get(&key) {
+            return m;
+        }
+        let m = self.p.len();
+        let p = Point::lerp(0.5, &self.p[key.0], &self.p[key.1]);
+        let n_sum = self.n[key.0] + self.n[key.1];
+        let n = if n_sum.length_squared() > 0. {
+            Vector::normalize(&n_sum)
+        } else {
+            self.n[key.0]
+        };
+        self.p.push(p);
+        self.n.push(n);
+        let uv = (self.uv[key.0] + self.uv[key.1]) * 0.5;
+        self.uv.push(uv);
+        self.midpoints.insert(key, m);
+        let level = self.level(a, b) + 1;
+        self.levels.insert(edge_key(a, m), level);
+        self.levels.insert(edge_key(m, b), level);
+        m
+    }
+
+    /// Longest edge bisection of the edges that need splitting, until none
+    /// is left.
+    fn subdivide(&mut self, v: [usize; 3]) {
+        let mut longest: Option<(usize, f32)> = None;
+        for i in 0..3 {
+            let (a, b) = (v[i], v[(i + 1) % 3]);
+            if !self.needs_split(a, b) {
+                continue;
+            }
+            let length = Point::distance(&self.p[a], &self.p[b]);
+            let is_longer = match longest {
+                Some((_, l)) => length > l,
+                None => true,
+            };
+            if is_longer {
+                longest = Some((i, length));
+            }
+        }
+        match longest {
+            Some((i, _)) => {
+                let (a, b, c) = (v[i], v[(i + 1) % 3], v[(i + 2) % 3]);
+                let m = self.split_edge(a, b);
+                // The new edge is only shared by the two halves
+                let level = (0..3).map(|j| self.level(v[j], v[(j + 1) % 3])).max().unwrap() + 1;
+                self.levels.insert(edge_key(m, c), level);
+                self.subdivide([a, m, c]);
+                self.subdivide([m, b, c]);
+            }
+            None => self.indices.extend_from_slice(&v),
+        }
+    }
+}
+
+fn vertex_interaction(p: &Point3f, n: &Normal3f, uv: &Point2f) -> SurfaceInteraction {
+    let (dpdu, dpdv) = Vector3f::from(n).coordinate_system();
+    let mut si = SurfaceInteraction::new(
+        p,
+        &Vector3f::zero(),
+        uv,
+        &Vector3f::zero(),
+        &dpdu,
+        &dpdv,
+        &Normal3f::zero(),
+        &Normal3f::zero(),
+        0.,
+        false,
+    );
+    si.n = *n;
+    si.shading.n = *n;
+    si
+}
+
+/// Area weighted vertex normals, oriented to agree with `reference` if given.
+fn vertex_normals(p: &[Point3f], indices: &[usize], reference: Option<&[Normal3f]>) -> Vec<Normal3f> {
+    let mut n = vec![Normal3f::zero(); p.len()];
+    for tri in indices.chunks(3) {
+        let face = (p[tri[1]] - p[tri[0]]).cross(&(p[tri[2]] - p[tri[0]]));
+        for &i in tri {
+            n[i] += Normal3f::from(&face);
+        }
+    }
+    for i in 0..n.len() {
+        if n[i].length_squared() > 0. {
+            n[i] = Vector::normalize(&n[i]);
+        } else if let Some(reference) = reference {
+            n[i] = reference[i];
+        }
+        if let Some(reference) = reference {
+            n[i] = face_forward(&n[i], &reference[i]);
+        }
+    }
+    n
+}
+
+#[test]
+fn displaced_quad_is_crack_free() {
+    use geometry::transform::{Matrix4, Transform};
+    use textures::ConstantTexture;
+
+    let identity = Transform::new(Matrix4::new()).unwrap();
+    let p = vec![
+        Point3f::new(0., 0., 0.),
+        Point3f::new(1., 0., 0.),
+        Point3f::new(1., 1., 0.),
+        Point3f::new(0., 1., 0.),
+    ];
+    let uv = p.iter().map(|p| Point2f::new(p.x, p.y)).collect();
+    let mesh = TriangleMesh::new(&identity, false, vec![0, 1, 2, 0, 2, 3], p, None, Some(uv));
+    let mapping = DisplacementMapping::new(
+        Rc::new(ConstantTexture::new(0.25)),
+        0.5,
+        EdgeTolerance::Length(0.2),
+        16,
+    );
+    let displaced = mapping.displace(&mesh).unwrap();
+    assert!(displaced.n_triangles > 2);
+
+    let mut edges: HashMap<(usize, usize), u32> = HashMap::new();
+    for tri in displaced.vertex_indices.chunks(3) {
+        for i in 0..3 {
+            let (a, b) = (tri[i], tri[(i + 1) % 3]);
+            assert!(Point::distance(&displaced.p[a], &displaced.p[b]) <= 0.2);
+            *edges.entry(if a < b { (a, b) } else { (b, a) }).or_insert(0) += 1;
+        }
+    }
+    // Every edge is shared by two triangles except on the quad border
+    for (&(a, b), &count) in edges.iter() {
+        let (pa, pb) = (displaced.p[a], displaced.p[b]);
+        let on_border = (pa.x == pb.x && (pa.x == 0. || pa.x == 1.))
+            || (pa.y == pb.y && (pa.y == 0. || pa.y == 1.));
+        assert_eq!(count, if on_border { 1 } else { 2 });
+    }
+    for (p, n) in displaced.p.iter().zip(displaced.n.as_ref().unwrap()) {
+        assert!((p.z - 0.25).abs() < 1e-6);
+        assert!((n.z - 1.).abs() < 1e-5);
+    }
+}
+
+#[test]
+fn shared_edges_agree_at_the_depth_limit() {
+    use geometry::transform::{Matrix4, Transform};
+    use textures::ConstantTexture;
+
+    // The big triangle runs out of depth on its long edges long before the
+    // small one stops splitting the edge they share along x == 0
+    let identity = Transform::new(Matrix4::new()).unwrap();
+    let p = vec![
+        Point3f::new(0., 0., 0.),
+        Point3f::new(0., 1., 0.),
+        Point3f::new(-0.5, 0.5, 0.),
+        Point3f::new(10., 0.5, 0.),
+    ];
+    let uv = p.iter().map(|p| Point2f::new(p.x, p.y)).collect();
+    let mesh = TriangleMesh::new(&identity, false, vec![0, 1, 2, 1, 0, 3], p, None, Some(uv));
+    let mapping = DisplacementMapping::new(Rc::new(ConstantTexture::new(0.)), 0., EdgeTolerance::Length(0.3), 4);
+    let displaced = mapping.displace(&mesh).unwrap();
+
+    let mut edges: HashMap<(usize, usize), u32> = HashMap::new();
+    for tri in displaced.vertex_indices.chunks(3) {
+        for i in 0..3 {
+            *edges.entry(edge_key(tri[i], tri[(i + 1) % 3])).or_insert(0) += 1;
+        }
+    }
+    let shared: Vec<u32> = edges
+        .iter()
+        .filter(|&(&(a, b), _)| displaced.p[a].x == 0. && displaced.p[b].x == 0.)
+        .map(|(_, &count)| count)
+        .collect();
+    assert!(shared.len() > 1);
+    assert!(shared.iter().all(|&count| count == 2));
+}
+
+#[test]
+fn empty_mesh_has_empty_bounds() {
+    use geometry::transform::{Matrix4, Transform};
+    use textures::ConstantTexture;
+
+    let identity = Transform::new(Matrix4::new()).unwrap();
+    let mesh = TriangleMesh::new(&identity, false, vec![], vec![], None, Some(vec![]));
+    let mapping = DisplacementMapping::new(Rc::new(ConstantTexture::new(0.)), 0.1, EdgeTolerance::Length(0.3), 4);
+    let b = mapping.world_bound(&mesh);
+    assert!(b.diagonal().x < 0.);
+    assert!(!Bounds::inside(&Point3f::new(0., 0., 0.), &b));
+    assert_eq!(mapping.displace(&mesh).unwrap().n_triangles, 0);
+}
+
+#[test]
+fn displacement_needs_uvs() {
+    use geometry::transform::{Matrix4, Transform};
+    use textures::ConstantTexture;
+
+    let identity = Transform::new(Matrix4::new()).unwrap();
+    let p = vec![
+        Point3f::new(0., 0., 0.),
+        Point3f::new(1., 0., 0.),
+        Point3f::new(0., 1., 0.),
+    ];
+    let uv = vec![Point2f::new(0., 0.), Point2f::new(1., 0.), Point2f::new(0., 1.)];
+    let mapping = DisplacementMapping::new(Rc::new(ConstantTexture::new(0.1)), 0.1, EdgeTolerance::Length(0.3), 4);
+
+    let without_uvs = TriangleMesh::new(&identity, false, vec![0, 1, 2], p.clone(), None, None);
+    assert!(mapping.displace(&without_uvs).is_err());
+
+    // Midpoints interpolate the uvs of their edge
+    let with_uvs = TriangleMesh::new(&identity, false, vec![0, 1, 2], p, None, Some(uv));
+    let displaced = mapping.displace(&with_uvs).unwrap();
+    for (p, uv) in displaced.p.iter().zip(displaced.uv.as_ref().unwrap()) {
+        assert!((p.x - uv.x).abs() < 1e-6 && (p.y - uv.y).abs() < 1e-6);
+    }
+}
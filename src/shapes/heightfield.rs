@@ -0,0 +1,245 @@
+use std::rc::Rc;
+
+use geometry::Vector;
+use geometry::bounds::{Bounds3, Bounds3f};
+use geometry::interaction::{Interaction, SurfaceInteraction};
+use geometry::normal::Normal3f;
+use geometry::point::{Point2f, Point3f};
+use geometry::ray::{Ray, Ray_};
+use geometry::transform::Transform;
+use util::clamp_nan_to_low;
+
+use super::Shape;
+use super::triangle::{create_triangle_mesh, Triangle, TriangleMesh};
+
+/// Height field over the unit square in object space, with `z[v * nu + u]`
+/// giving the elevation of the grid vertex `(u, v)`.
+pub struct Heightfield {
+    object_to_world: Transform<f32>,
+    world_to_object: Transform<f32>,
+    nu: usize,
+    nv: usize,
+    z_min: f32,
+    z_max: f32,
+    mesh: Rc<TriangleMesh>,
+    area_cdf: Vec<f32>,
+}
+
+impl Heightfield {
+    pub fn new(
+        object_to_world: &Transform<f32>,
+        reverse_orientation: bool,
+        nu: usize,
+        nv: usize,
+        z: &[f32],
+    ) -> Heightfield {
+        assert!(nu >= 2 && nv >= 2);
+        assert_eq!(z.len(), nu * nv);
+
+        let du = 1. / (nu - 1) as f32;
+        let dv = 1. / (nv - 1) as f32;
+        let mut p = Vec::with_capacity(nu * nv);
+        let mut n = Vec::with_capacity(nu * nv);
+        let mut uv = Vec::with_capacity(nu * nv);
+        for y in 0..nv {
+            for x in 0..nu {
+                let u = x as f32 * du;
+                let v = y as f32 * dv;
+                p.push(Point3f::new(u, v, z[y * nu + x]));
+                uv.push(Point2f::new(u, v));
+
+                // Smooth normal from central differences of the elevation grid
+                let (x0, x1) = (x.saturating_sub(1), (x + 1).min(nu - 1));
+                let (y0, y1) = (y.saturating_sub(1), (y + 1).min(nv - 1));
+                let dzdu = (z[y * nu + x1] - z[y * nu + x0]) / ((x1 - x0) as f32 * du);
+                let dzdv = (z[y1 * nu + x] - z[y0 * nu + x]) / ((y1 - y0) as f32 * dv);
+                n.push(Vector::normalize(&Normal3f::new(-dzdu, -dzdv, 1.)));
+            }
+        }
+
+        let mut indices = Vec::with_capacity(6 * (nu - 1) * (nv - 1));
+        let vert = |x: usize, y: usize| x + y * nu;
+        for y in 0..nv - 1 {
+            for x in 0..nu - 1 {
+                indices.extend_from_slice(&[vert(x, y), vert(x + 1, y), vert(x + 1, y + 1)]);
+                indices.extend_from_slice(&[vert(x, y), vert(x + 1, y + 1), vert(x, y + 1)]);
+            }
+        }
+
+        let z_min = z.iter().cloned().fold(f32::INFINITY, f32::min);
+        let z_max = z.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+        let mesh = TriangleMesh::new(
+            object_to_world,
+            reverse_orientation,
+            indices,
+            p,
+            Some(n),
+            Some(uv),
+        );
+        let mesh = Rc::new(mesh);
+        let mut area_cdf = Vec::with_capacity(mesh.n_triangles);
+        let mut area = 0.;
+        for i in 0..mesh.n_triangles {
+            area += Triangle::new(mesh.clone(), i).area();
+            area_cdf.push(area);
+        }
+        Heightfield {
+            object_to_world: object_to_world.clone(),
+            world_to_object: object_to_world.inverse(),
+            nu,
+            nv,
+            z_min,
+            z_max,
+            mesh,
+            area_cdf,
+        }
+    }
+
+    pub fn mesh(&self) -> Rc<TriangleMesh> {
+        self.mesh.clone()
+    }
+
+    /// Tessellates the height field into individual triangles sharing its mesh.
+    pub fn triangles(&self) -> Vec<Rc<dyn Shape>> {
+        create_triangle_mesh(self.mesh.clone())
+    }
+
+    fn intersect_cell(&self, ray: &Ray_, x: usize, y: usize) -> Option<(f32, SurfaceInteraction)> {
+        let first = 2 * (y * (self.nu - 1) + x);
+        let mut hit: Option<(f32, SurfaceInteraction)> = None;
+        for tri in first..first + 2 {
+            if let Some((t, isect)) = Triangle::new(self.mesh.clone(), tri).intersect(ray) {
+                let closer = match hit {
+                    Some((t_hit, _)) => t < t_hit,
+                    None => true,
+                };
+                if closer {
+                    hit = Some((t, isect));
+                }
+            }
+        }
+        hit
+    }
+}
+
+impl Shape for Heightfield {
+    fn object_bound(&self) -> Bounds3f {
+        Bounds3::from((
+            Point3f::new(0., 0., self.z_min),
+            Point3f::new(1., 1., self.z_max),
+        ))
+    }
+
+    fn world_bound(&self) -> Bounds3f {
+        &self.object_to_world * &self.object_bound()
+    }
+
+    /// Marches the ray through the grid cells in object space and only tests
+    /// the two triangles of each visited cell, front to back.
+    fn intersect(&self, r: &Ray_) -> Option<(f32, SurfaceInteraction)> {
+        let ray = &self.world_to_object * r;
+        let (t0, t1) = self.object_bound().intersect_p(&ray)?;
+
+        let res = [self.nu - 1, self.nv - 1];
+        let p_entry = ray.point(t0);
+        let p_grid = [p_entry.x * res[0] as f32, p_entry.y * res[1] as f32];
+        let mut cell = [0isize; 2];
+        let mut next_crossing_t = [0f32; 2];
+        let mut delta_t = [0f32; 2];
+        let mut step = [0isize; 2];
+        let mut out = [0isize; 2];
+        for axis in 0..2 {
+            let max_cell = res[axis] as isize - 1;
+            cell[axis] = (p_grid[axis].floor() as isize).max(0).min(max_cell);
+            let d_grid = ray.d[axis] * res[axis] as f32;
+            if d_grid == 0. {
+                next_crossing_t[axis] = f32::INFINITY;
+                delta_t[axis] = f32::INFINITY;
+                out[axis] = max_cell + 1;
+            } else if d_grid > 0. {
+                next_crossing_t[axis] = t0 + ((cell[axis] + 1) as f32 - p_grid[axis]) / d_grid;
+                delta_t[axis] = 1. / d_grid;
+                step[axis] = 1;
+                out[axis] = max_cell + 1;
+            } else {
+                next_crossing_t[axis] = t0 + (cell[axis] as f32 - p_grid[axis]) / d_grid;
+                delta_t[axis] = -1. / d_grid;
+                step[axis] = -1;
+                out[axis] = -1;
+            }
+        }
+
+        loop {
+            // Cells are visited in ray order, so the first hit is the closest
+            let hit = self.intersect_cell(r, cell[0] as usize, cell[1] as usize);
+            if hit.is_some() {
+                return hit;
+            }
+            let axis = if next_crossing_t[0] < next_crossing_t[1] { 0 } else { 1 };
+            if next_crossing_t[axis] > t1 {
+                return None;
+            }
+            cell[axis] += step[axis];
+            if cell[axis] == out[axis] {
+                return None;
+            }
+            next_crossing_t[axis] += delta_t[axis];
+        }
+    }
+
+    fn area(&self) -> f32 {
+        self.area_cdf[self.area_cdf.len() - 1]
+    }
+
+    /// Picks a triangle proportional to its area and samples it uniformly.
+    fn sample_area(&self, u: &Point2f) -> (Interaction, f32) {
+        let area = self.area();
+        let target = u.x * area;
+        let tri = match self.area_cdf
+            .binary_search_by(|a| a.partial_cmp(&target).unwrap())
+        {
+            Ok(i) => i,
+            Err(i) => i.min(self.area_cdf.len() - 1),
+        };
+        let start = if tri == 0 { 0. } else { self.area_cdf[tri - 1] };
+        let u_remapped = clamp_nan_to_low((target - start) / (self.area_cdf[tri] - start), 0., 1.);
+        let (it, _) = Triangle::new(self.mesh.clone(), tri).sample_area(&Point2f::new(u_remapped, u.y));
+        (it, 1. / area)
+    }
+}
+
+#[test]
+fn grid_march_matches_tessellation() {
+    use geometry::transform::Matrix4;
+    use geometry::vector::Vector3f;
+
+    let (nu, nv) = (9, 7);
+    let z: Vec<f32> = (0..nu * nv)
+        .map(|i| ((i % nu) as f32 * 0.7).sin() * 0.2 + ((i / nu) as f32 * 0.4).cos() * 0.1)
+        .collect();
+    let identity = Transform::new(Matrix4::new()).unwrap();
+    let hf = Heightfield::new(&identity, false, nu, nv, &z);
+    let triangles = hf.triangles();
+
+    let mut seed = 17u32;
+    let mut rand = || {
+        seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+        (seed >> 8) as f32 / (1 << 24) as f32
+    };
+    for _ in 0..500 {
+        let o = Point3f::new(rand() * 1.6 - 0.3, rand() * 1.6 - 0.3, 1.);
+        let d = Vector3f::new(rand() - 0.5, rand() - 0.5, -1.);
+        let ray = Ray_::new(&o, &d, f32::INFINITY, 0., None);
+
+        let marched = hf.intersect(&ray).map(|(t, _)| t);
+        let brute = triangles
+            .iter()
+            .filter_map(|tri| tri.intersect(&ray).map(|(t, _)| t))
+            .fold(None, |best: Option<f32>, t| Some(best.map_or(t, |b| b.min(t))));
+        match (marched, brute) {
+            (Some(a), Some(b)) => assert!((a - b).abs() < 1e-5),
+            (None, None) => {}
+            _ => panic!("grid march and tessellation disagree"),
+        }
+    }
+}
@@ -1,3 +1,128 @@
-trait Shape {
+pub mod triangle;
+pub mod heightfield;
+pub mod displacement;
+pub mod sphere;
+pub mod quad;
+pub mod alpha;
 
-}
\ No newline at end of file
+use geometry::{Metric, Point, Vector, VectorSpace};
+use geometry::bounds::Bounds3f;
+use geometry::interaction::{Interaction, SurfaceInteraction};
+use geometry::point::Point2f;
+use geometry::ray::Ray_;
+use geometry::vector::Vector3f;
+
+pub trait Shape {
+    fn object_bound(&self) -> Bounds3f;
+    fn world_bound(&self) -> Bounds3f;
+    fn intersect(&self, ray: &Ray_) -> Option<(f32, SurfaceInteraction)>;
+    fn intersect_p(&self, ray: &Ray_) -> bool {
+        self.intersect(ray).is_some()
+    }
+    fn area(&self) -> f32;
+
+    /// Samples a point uniformly by area and returns it with its area density.
+    fn sample_area(&self, u: &Point2f) -> (Interaction, f32);
+    fn pdf_area(&self, _it: &Interaction) -> f32 {
+        1. / self.area()
+    }
+
+    /// Samples a point on the shape as seen from `it` and returns it with its
+    /// density with respect to solid angle at `it`.
+    fn sample(&self, it: &Interaction, u: &Point2f) -> Option<(Interaction, f32)> {
+        sample_by_area(self, it, u)
+    }
+    /// Solid angle density of sampling direction `wi` from `it` with `sample`.
+    fn pdf(&self, it: &Interaction, wi: &Vector3f) -> f32 {
+        pdf_by_area(self, it, wi)
+    }
+}
+
+/// Area sampling converted to a solid angle density at the reference point.
+pub fn sample_by_area<T: Shape + ?Sized>(
+    shape: &T,
+    it: &Interaction,
+    u: &Point2f,
+) -> Option<(Interaction, f32)> {
+    let (intr, pdf) = shape.sample_area(u);
+    let wi = intr.p - it.p;
+    let dist2 = wi.length_squared();
+    if dist2 == 0. {
+        return None;
+    }
+    let wi = Vector::normalize(&wi);
+    let cos_theta = Vector3f::from(&intr.n).abs_dot(-wi);
+    if cos_theta == 0. {
+        return None;
+    }
+    Some((intr, pdf * dist2 / cos_theta))
+}
+
+/// Finds the point seen along `wi` by casting a ray against the shape and
+/// converts its area density to solid angle.
+pub fn pdf_by_area<T: Shape + ?Sized>(shape: &T, it: &Interaction, wi: &Vector3f) -> f32 {
+    let ray = it.spawn_ray(wi);
+    match shape.intersect(&ray) {
+        Some((_, isect)) => {
+            let cos_theta = Vector3f::from(&isect.n).abs_dot(-*wi);
+            if cos_theta == 0. {
+                return 0.;
+            }
+            Point::distance_squared(&it.p, &isect.p) / (cos_theta * shape.area())
+        }
+        None => 0.,
+    }
+}
+
+#[test]
+fn solid_angle_sampling_matches_pdf() {
+    use std::rc::Rc;
+    use geometry::normal::Normal3f;
+    use geometry::point::Point3f;
+    use geometry::transform::Transform;
+
+    let to_world = Transform::translate(&Vector3f::new(0., 0., 2.));
+    let mesh = triangle::TriangleMesh::new(
+        &to_world,
+        false,
+        vec![0, 1, 2],
+        vec![
+            Point3f::new(-1., -1., 0.),
+            Point3f::new(1., -0.5, 0.),
+            Point3f::new(0., 1., 0.3),
+        ],
+        None,
+        None,
+    );
+    let shapes: Vec<Box<dyn Shape>> = vec![
+        Box::new(sphere::Sphere::new(&to_world, false, 0.5)),
+        Box::new(triangle::Triangle::new(Rc::new(mesh), 0)),
+        Box::new(quad::Quad::new(
+            &to_world,
+            false,
+            &Point3f::new(-1., -0.5, 0.),
+            &Vector3f::new(2., 0., 0.),
+            &Vector3f::new(0., 1., 0.),
+        )),
+    ];
+    let reference = Interaction::new(
+        &Point3f::new(0.2, 0.1, 0.),
+        &Normal3f::new(0., 0., 1.),
+        &Vector3f::zero(),
+        &Vector3f::zero(),
+        0.,
+    );
+
+    for shape in shapes.iter() {
+        for i in 0..8 {
+            for j in 0..8 {
+                let u = Point2f::new((i as f32 + 0.5) / 8., (j as f32 + 0.5) / 8.);
+                let (intr, pdf) = shape.sample(&reference, &u).unwrap();
+                let wi = Vector::normalize(&(intr.p - reference.p));
+                let pdf_wi = shape.pdf(&reference, &wi);
+                assert!(pdf > 0.);
+                assert!((pdf - pdf_wi).abs() <= 1e-2 * pdf);
+            }
+        }
+    }
+}
@@ -0,0 +1,210 @@
+use geometry::{Metric, Point, Vector, VectorSpace};
+use geometry::bounds::{Bounds, Bounds3, Bounds3f};
+use geometry::interaction::{Interaction, SurfaceInteraction};
+use geometry::normal::Normal3f;
+use geometry::point::{Point2f, Point3f};
+use geometry::ray::{Ray, Ray_};
+use geometry::transform::Transform;
+use geometry::vector::Vector3f;
+use sampling::{sample_spherical_rectangle, triangle_solid_angle, MAX_SPHERICAL_SAMPLE_AREA,
+               MIN_SPHERICAL_SAMPLE_AREA};
+use util::gamma;
+
+use super::{pdf_by_area, sample_by_area, Shape};
+
+/// Planar parallelogram `p + u * s + v * t` with `u, v` in `[0, 1]`, stored in
+/// world space.
+pub struct Quad {
+    object_to_world: Transform<f32>,
+    p: Point3f,
+    s: Vector3f,
+    t: Vector3f,
+    n: Normal3f,
+    is_rectangle: bool,
+    flip_normal: bool,
+}
+
+impl Quad {
+    pub fn new(
+        object_to_world: &Transform<f32>,
+        reverse_orientation: bool,
+        p: &Point3f,
+        s: &Vector3f,
+        t: &Vector3f,
+    ) -> Quad {
+        let p = object_to_world * p;
+        let s = object_to_world * s;
+        let t = object_to_world * t;
+        let flip_normal = reverse_orientation ^ object_to_world.swaps_handedness();
+        let mut n = Normal3f::from(Vector::normalize(&s.cross(&t)));
+        if flip_normal {
+            n = -n;
+        }
+        let is_rectangle = s.dot(t).abs() <= 1e-4 * s.norm() * t.norm();
+        Quad {
+            object_to_world: object_to_world.clone(),
+            p,
+            s,
+            t,
+            n,
+            is_rectangle,
+            flip_normal,
+        }
+    }
+
+    fn corners(&self) -> [Point3f; 4] {
+        [
+            self.p,
+            self.p + self.s,
+            self.p + self.s + self.t,
+            self.p + self.t,
+        ]
+    }
+
+    /// Solid angle used for spherical rectangle sampling, if applicable.
+    fn spherical_solid_angle(&self, p_ref: &Point3f) -> Option<f32> {
+        if !self.is_rectangle {
+            return None;
+        }
+        let c = self.corners();
+        let solid_angle =
+            triangle_solid_angle(&[c[0], c[1], c[2]], p_ref) + triangle_solid_angle(&[c[0], c[2], c[3]], p_ref);
+        if !(MIN_SPHERICAL_SAMPLE_AREA..=MAX_SPHERICAL_SAMPLE_AREA).contains(&solid_angle) {
+            None
+        } else {
+            Some(solid_angle)
+        }
+    }
+
+    fn interaction_at(&self, p: &Point3f) -> Interaction {
+        let p_abs = p.abs();
+        let p_error = Vector3f::new(p_abs.x, p_abs.y, p_abs.z) * gamma(6);
+        Interaction::new(p, &self.n, &p_error, &Vector3f::zero(), 0.)
+    }
+}
+
+impl Shape for Quad {
+    fn object_bound(&self) -> Bounds3f {
+        let world_to_object = self.object_to_world.inverse();
+        &world_to_object * &self.world_bound()
+    }
+
+    fn world_bound(&self) -> Bounds3f {
+        let c = self.corners();
+        let mut b = Bounds3::from(&c[0]);
+        for corner in c.iter().skip(1) {
+            b = Bounds::point_union(&b, corner);
+        }
+        b
+    }
+
+    fn intersect(&self, ray: &Ray_) -> Option<(f32, SurfaceInteraction)> {
+        let ng = self.s.cross(&self.t);
+        let denom = ng.dot(ray.d);
+        if denom == 0. {
+            return None;
+        }
+        let t_hit = ng.dot(self.p - ray.o) / denom;
+        if t_hit <= 0. || t_hit >= ray.tmax.get() {
+            return None;
+        }
+
+        // Express the hit point in terms of the two edges
+        let p_hit = ray.point(t_hit);
+        let w = p_hit - self.p;
+        let inv_len2 = 1. / ng.length_squared();
+        let u = w.cross(&self.t).dot(ng) * inv_len2;
+        let v = self.s.cross(&w).dot(ng) * inv_len2;
+        if !(0. ..=1.).contains(&u) || !(0. ..=1.).contains(&v) {
+            return None;
+        }
+
+        let p_abs = p_hit.abs();
+        let p_error = Vector3f::new(p_abs.x, p_abs.y, p_abs.z) * gamma(6);
+        let isect = SurfaceInteraction::new(
+            &p_hit,
+            &p_error,
+            &Point2f::new(u, v),
+            &-ray.d,
+            &self.s,
+            &self.t,
+            &Normal3f::zero(),
+            &Normal3f::zero(),
+            ray.time,
+            self.flip_normal,
+        );
+        Some((t_hit, isect))
+    }
+
+    fn area(&self) -> f32 {
+        self.s.cross(&self.t).norm()
+    }
+
+    fn sample_area(&self, u: &Point2f) -> (Interaction, f32) {
+        let p = self.p + self.s * u.x + self.t * u.y;
+        (self.interaction_at(&p), 1. / self.area())
+    }
+
+    fn sample(&self, it: &Interaction, u: &Point2f) -> Option<(Interaction, f32)> {
+        if self.spherical_solid_angle(&it.p).is_none() {
+            return sample_by_area(self, it, u);
+        }
+        let (p, pdf) = sample_spherical_rectangle(&it.p, &self.p, &self.s, &self.t, u);
+        if pdf == 0. {
+            return sample_by_area(self, it, u);
+        }
+        Some((self.interaction_at(&p), pdf))
+    }
+
+    fn pdf(&self, it: &Interaction, wi: &Vector3f) -> f32 {
+        match self.spherical_solid_angle(&it.p) {
+            Some(solid_angle) => {
+                if self.intersect_p(&it.spawn_ray(wi)) {
+                    1. / solid_angle
+                } else {
+                    0.
+                }
+            }
+            None => pdf_by_area(self, it, wi),
+        }
+    }
+}
+
+#[test]
+fn small_solid_angle_is_sampled_in_the_measure_of_its_pdf() {
+    // A thin strip seen at a grazing angle, where sampling by area and by
+    // solid angle give very different densities
+    let quad = Quad::new(
+        &Transform::translate(&Vector3f::new(0., 0., 0.)),
+        false,
+        &Point3f::new(1., -0.0025, 0.2),
+        &Vector3f::new(2., 0., 0.),
+        &Vector3f::new(0., 0.005, 0.),
+    );
+    let p_ref = Point3f::new(0., 0., 0.);
+    let reference = Interaction::new(&p_ref, &Normal3f::new(0., 0., 1.), &Vector3f::zero(), &Vector3f::zero(), 0.);
+    let solid_angle = quad.spherical_solid_angle(&p_ref).unwrap();
+    assert!(solid_angle > 4e-4 && solid_angle < 6e-4);
+
+    // Estimate the solid angle of the nearer half of the strip
+    let near_half = |c: &[Point3f; 4]| {
+        let mid = [c[0] + (c[1] - c[0]) * 0.5, c[3] + (c[2] - c[3]) * 0.5];
+        triangle_solid_angle(&[c[0], mid[0], mid[1]], &p_ref) + triangle_solid_angle(&[c[0], mid[1], c[3]], &p_ref)
+    };
+    let n = 64;
+    let mut estimate = 0.;
+    for i in 0..n {
+        for j in 0..n {
+            let u = Point2f::new((i as f32 + 0.5) / n as f32, (j as f32 + 0.5) / n as f32);
+            let (intr, pdf) = quad.sample(&reference, &u).unwrap();
+            let wi = Vector::normalize(&(intr.p - p_ref));
+            assert!((pdf - quad.pdf(&reference, &wi)).abs() <= 1e-2 * pdf);
+            if intr.p.x < 2. {
+                estimate += 1. / pdf;
+            }
+        }
+    }
+    estimate /= (n * n) as f32;
+    let expected = near_half(&quad.corners());
+    assert!((estimate - expected).abs() < 0.02 * expected);
+}
@@ -0,0 +1,231 @@
+use std::f32::consts::PI;
+
+use geometry::{Point, Vector, VectorSpace};
+use geometry::bounds::{Bounds3, Bounds3f};
+use geometry::interaction::{Interaction, SurfaceInteraction};
+use geometry::normal::Normal3f;
+use geometry::point::{Point2f, Point3f};
+use geometry::ray::{Ray, Ray_};
+use geometry::transform::Transform;
+use geometry::vector::Vector3f;
+use sampling::{spherical_direction, uniform_cone_pdf, uniform_sample_sphere};
+use util::gamma;
+
+use super::{pdf_by_area, sample_by_area, Shape};
+
+pub struct Sphere {
+    object_to_world: Transform<f32>,
+    world_to_object: Transform<f32>,
+    reverse_orientation: bool,
+    transform_swaps_handedness: bool,
+    radius: f32,
+}
+
+impl Sphere {
+    pub fn new(object_to_world: &Transform<f32>, reverse_orientation: bool, radius: f32) -> Sphere {
+        Sphere {
+            object_to_world: object_to_world.clone(),
+            world_to_object: object_to_world.inverse(),
+            reverse_orientation,
+            transform_swaps_handedness: object_to_world.swaps_handedness(),
+            radius,
+        }
+    }
+
+    fn center(&self) -> Point3f {
+        &self.object_to_world * Point3f::zero()
+    }
+}
+
+impl Shape for Sphere {
+    fn object_bound(&self) -> Bounds3f {
+        Bounds3::from((
+            Point3f::new(-self.radius, -self.radius, -self.radius),
+            Point3f::new(self.radius, self.radius, self.radius),
+        ))
+    }
+
+    fn world_bound(&self) -> Bounds3f {
+        &self.object_to_world * &self.object_bound()
+    }
+
+    fn intersect(&self, r: &Ray_) -> Option<(f32, SurfaceInteraction)> {
+        let ray = &self.world_to_object * r;
+
+        // Solve the quadratic for the ray parameter in object space
+        let (o, d) = (ray.o, ray.d);
+        let a = d.x * d.x + d.y * d.y + d.z * d.z;
+        let b = 2. * (d.x * o.x + d.y * o.y + d.z * o.z);
+        let c = o.x * o.x + o.y * o.y + o.z * o.z - self.radius * self.radius;
+        let discrim = b as f64 * b as f64 - 4. * a as f64 * c as f64;
+        if discrim < 0. {
+            return None;
+        }
+        let root_discrim = discrim.sqrt() as f32;
+        let q = if b < 0. {
+            -0.5 * (b - root_discrim)
+        } else {
+            -0.5 * (b + root_discrim)
+        };
+        let (mut t0, mut t1) = (q / a, c / q);
+        if t0 > t1 {
+            ::std::mem::swap(&mut t0, &mut t1);
+        }
+        let t_max = ray.tmax.get();
+        if t0 > t_max || t1 <= 0. {
+            return None;
+        }
+        let mut t_hit = t0;
+        if t_hit <= 0. {
+            t_hit = t1;
+            if t_hit > t_max {
+                return None;
+            }
+        }
+
+        // Refine the hit point and compute its spherical coordinates
+        let mut p_hit = ray.point(t_hit);
+        p_hit = p_hit * (self.radius / Point::distance(&p_hit, &Point3f::zero()));
+        if p_hit.x == 0. && p_hit.y == 0. {
+            p_hit.x = 1e-5 * self.radius;
+        }
+        let mut phi = p_hit.y.atan2(p_hit.x);
+        if phi < 0. {
+            phi += 2. * PI;
+        }
+        let phi_max = 2. * PI;
+        let (theta_min, theta_max) = (PI, 0.);
+        let cos_theta = (p_hit.z / self.radius).clamp(-1., 1.);
+        let theta = cos_theta.acos();
+        let u = phi / phi_max;
+        let v = (theta - theta_min) / (theta_max - theta_min);
+
+        // Partial derivatives of the position and the normal
+        let z_radius = (p_hit.x * p_hit.x + p_hit.y * p_hit.y).sqrt();
+        let cos_phi = p_hit.x / z_radius;
+        let sin_phi = p_hit.y / z_radius;
+        let sin_theta = (1. - cos_theta * cos_theta).max(0.).sqrt();
+        let dtheta = theta_max - theta_min;
+        let dpdu = Vector3f::new(-phi_max * p_hit.y, phi_max * p_hit.x, 0.);
+        let dpdv = Vector3f::new(p_hit.z * cos_phi, p_hit.z * sin_phi, -self.radius * sin_theta)
+            * dtheta;
+        let d2pduu = Vector3f::new(p_hit.x, p_hit.y, 0.) * (-phi_max * phi_max);
+        let d2pduv = Vector3f::new(-sin_phi, cos_phi, 0.) * (dtheta * p_hit.z * phi_max);
+        let d2pdvv = Vector3f::new(p_hit.x, p_hit.y, p_hit.z) * (-dtheta * dtheta);
+
+        let e1 = dpdu.dot(dpdu);
+        let f1 = dpdu.dot(dpdv);
+        let g1 = dpdv.dot(dpdv);
+        let n = Vector::normalize(&dpdu.cross(&dpdv));
+        let e2 = n.dot(d2pduu);
+        let f2 = n.dot(d2pduv);
+        let g2 = n.dot(d2pdvv);
+        let inv_egf2 = 1. / (e1 * g1 - f1 * f1);
+        let dndu = Normal3f::from(
+            dpdu * ((f2 * f1 - e2 * g1) * inv_egf2) + dpdv * ((e2 * f1 - f2 * e1) * inv_egf2),
+        );
+        let dndv = Normal3f::from(
+            dpdu * ((g2 * f1 - f2 * g1) * inv_egf2) + dpdv * ((f2 * f1 - g2 * e1) * inv_egf2),
+        );
+
+        let p_abs = p_hit.abs();
+        let p_error = Vector3f::new(p_abs.x, p_abs.y, p_abs.z) * gamma(5);
+        let isect = SurfaceInteraction::new(
+            &p_hit,
+            &p_error,
+            &Point2f::new(u, v),
+            &-ray.d,
+            &dpdu,
+            &dpdv,
+            &dndu,
+            &dndv,
+            ray.time,
+            self.reverse_orientation ^ self.transform_swaps_handedness,
+        );
+        Some((t_hit, &self.object_to_world * &isect))
+    }
+
+    fn area(&self) -> f32 {
+        4. * PI * self.radius * self.radius
+    }
+
+    fn sample_area(&self, u: &Point2f) -> (Interaction, f32) {
+        let d = uniform_sample_sphere(u);
+        let mut n = Vector::normalize(&(&self.object_to_world * Normal3f::from(&d)));
+        if self.reverse_orientation {
+            n = -n;
+        }
+        let p_obj = Point3f::new(d.x, d.y, d.z) * self.radius;
+        let p_abs = p_obj.abs();
+        let p_error = Vector3f::new(p_abs.x, p_abs.y, p_abs.z) * gamma(5);
+        let it = Interaction::new(
+            &(&self.object_to_world * p_obj),
+            &n,
+            &(&self.object_to_world * p_error),
+            &Vector3f::zero(),
+            0.,
+        );
+        (it, 1. / self.area())
+    }
+
+    /// Samples the cone of directions subtended by the sphere from outside,
+    /// falling back to area sampling when the reference point is inside.
+    fn sample(&self, it: &Interaction, u: &Point2f) -> Option<(Interaction, f32)> {
+        let p_center = self.center();
+        let dc2 = Point::distance_squared(&it.p, &p_center);
+        if dc2 <= self.radius * self.radius {
+            return sample_by_area(self, it, u);
+        }
+
+        let dc = dc2.sqrt();
+        let wc = (p_center - it.p) / dc;
+        let (wc_x, wc_y) = wc.coordinate_system();
+
+        // Sample the cone and compute the angle alpha from the sphere center
+        let sin_theta_max = self.radius / dc;
+        let sin_theta_max2 = sin_theta_max * sin_theta_max;
+        let inv_sin_theta_max = 1. / sin_theta_max;
+        let cos_theta_max = (1. - sin_theta_max2).max(0.).sqrt();
+        let mut cos_theta = (cos_theta_max - 1.) * u.x + 1.;
+        let mut sin_theta2 = 1. - cos_theta * cos_theta;
+        if sin_theta_max2 < 0.00068523 {
+            // Taylor expansion for small cones, sin^2(1.5 deg)
+            sin_theta2 = sin_theta_max2 * u.x;
+            cos_theta = (1. - sin_theta2).sqrt();
+        }
+        let cos_alpha = sin_theta2 * inv_sin_theta_max
+            + cos_theta
+                * (1. - sin_theta2 * inv_sin_theta_max * inv_sin_theta_max)
+                    .max(0.)
+                    .sqrt();
+        let sin_alpha = (1. - cos_alpha * cos_alpha).max(0.).sqrt();
+        let phi = u.y * 2. * PI;
+
+        let n_world = spherical_direction(sin_alpha, cos_alpha, phi, &-wc_x, &-wc_y, &-wc);
+        let p_world = p_center + n_world * self.radius;
+        let p_abs = p_world.abs();
+        let mut n = Normal3f::from(&n_world);
+        if self.reverse_orientation {
+            n = -n;
+        }
+        let intr = Interaction::new(
+            &p_world,
+            &n,
+            &(Vector3f::new(p_abs.x, p_abs.y, p_abs.z) * gamma(5)),
+            &Vector3f::zero(),
+            0.,
+        );
+        Some((intr, uniform_cone_pdf(cos_theta_max)))
+    }
+
+    fn pdf(&self, it: &Interaction, wi: &Vector3f) -> f32 {
+        let p_center = self.center();
+        let dc2 = Point::distance_squared(&it.p, &p_center);
+        if dc2 <= self.radius * self.radius {
+            return pdf_by_area(self, it, wi);
+        }
+        let sin_theta_max2 = self.radius * self.radius / dc2;
+        let cos_theta_max = (1. - sin_theta_max2).max(0.).sqrt();
+        uniform_cone_pdf(cos_theta_max)
+    }
+}
@@ -0,0 +1,371 @@
+use std::rc::Rc;
+
+use geometry::{Metric, Point, Vector};
+use geometry::bounds::{Bounds, Bounds3, Bounds3f};
+use geometry::interaction::{face_forward, Interaction, SurfaceInteraction};
+use geometry::normal::Normal3f;
+use geometry::point::{Point2f, Point3f};
+use geometry::ray::Ray_;
+use geometry::transform::Transform;
+use geometry::vector::Vector3f;
+use sampling::{sample_spherical_triangle, triangle_solid_angle, uniform_sample_triangle,
+               MAX_SPHERICAL_SAMPLE_AREA, MIN_SPHERICAL_SAMPLE_AREA};
+use util::gamma;
+
+use super::{pdf_by_area, sample_by_area, Shape};
+
+/// Vertex data shared by all triangles of a mesh. Positions and normals are
+/// stored in world space.
+pub struct TriangleMesh {
+    pub object_to_world: Transform<f32>,
+    pub reverse_orientation: bool,
+    pub transform_swaps_handedness: bool,
+    pub n_triangles: usize,
+    pub vertex_indices: Vec<usize>,
+    pub p: Vec<Point3f>,
+    pub n: Option<Vec<Normal3f>>,
+    pub uv: Option<Vec<Point2f>>,
+}
+
+impl TriangleMesh {
+    pub fn new(
+        object_to_world: &Transform<f32>,
+        reverse_orientation: bool,
+        vertex_indices: Vec<usize>,
+        p: Vec<Point3f>,
+        n: Option<Vec<Normal3f>>,
+        uv: Option<Vec<Point2f>>,
+    ) -> TriangleMesh {
+        assert!(vertex_indices.len().is_multiple_of(3));
+        let p = p.iter().map(|p| object_to_world * p).collect();
+        let n = n.map(|n| n.iter().map(|n| object_to_world * n).collect());
+        TriangleMesh {
+            object_to_world: object_to_world.clone(),
+            reverse_orientation,
+            transform_swaps_handedness: object_to_world.swaps_handedness(),
+            n_triangles: vertex_indices.len() / 3,
+            vertex_indices,
+            p,
+            n,
+            uv,
+        }
+    }
+}
+
+pub struct Triangle {
+    mesh: Rc<TriangleMesh>,
+    v: usize,
+}
+
+pub fn create_triangle_mesh(mesh: Rc<TriangleMesh>) -> Vec<Rc<dyn Shape>> {
+    (0..mesh.n_triangles)
+        .map(|i| Rc::new(Triangle::new(mesh.clone(), i)) as Rc<dyn Shape>)
+        .collect()
+}
+
+impl Triangle {
+    pub fn new(mesh: Rc<TriangleMesh>, tri_number: usize) -> Triangle {
+        Triangle {
+            mesh,
+            v: 3 * tri_number,
+        }
+    }
+
+    pub fn vertices(&self) -> (Point3f, Point3f, Point3f) {
+        let idx = &self.mesh.vertex_indices;
+        (
+            self.mesh.p[idx[self.v]],
+            self.mesh.p[idx[self.v + 1]],
+            self.mesh.p[idx[self.v + 2]],
+        )
+    }
+
+    /// Builds the interaction at barycentric coordinates `b` with the
+    /// geometric normal oriented like the shading normals.
+    fn interaction_at(&self, b: &[f32; 3]) -> Interaction {
+        let (p0, p1, p2) = self.vertices();
+        let p = p0 * b[0] + p1 * b[1] + p2 * b[2];
+        let mut n = Normal3f::from(Vector::normalize(&(p1 - p0).cross(&(p2 - p0))));
+        if let Some(ref normals) = self.mesh.n {
+            let idx = &self.mesh.vertex_indices;
+            let ns = normals[idx[self.v]] * b[0] + normals[idx[self.v + 1]] * b[1]
+                + normals[idx[self.v + 2]] * b[2];
+            n = face_forward(&n, &ns);
+        } else if self.mesh.reverse_orientation ^ self.mesh.transform_swaps_handedness {
+            n = -n;
+        }
+        let p_abs_sum = (p0 * b[0]).abs() + (p1 * b[1]).abs() + (p2 * b[2]).abs();
+        let p_error = Vector3f::new(p_abs_sum.x, p_abs_sum.y, p_abs_sum.z) * gamma(6);
+        Interaction::new(&p, &n, &p_error, &Vector3f::zero(), 0.)
+    }
+
+    /// Spherical sampling is only used when the subtended solid angle is
+    /// neither tiny nor close to a hemisphere.
+    fn spherical_solid_angle(&self, p: &Point3f) -> Option<f32> {
+        let (p0, p1, p2) = self.vertices();
+        let solid_angle = triangle_solid_angle(&[p0, p1, p2], p);
+        if !(MIN_SPHERICAL_SAMPLE_AREA..=MAX_SPHERICAL_SAMPLE_AREA).contains(&solid_angle) {
+            None
+        } else {
+            Some(solid_angle)
+        }
+    }
+
+    fn uvs(&self) -> [Point2f; 3] {
+        match self.mesh.uv {
+            Some(ref uv) => {
+                let idx = &self.mesh.vertex_indices;
+                [uv[idx[self.v]], uv[idx[self.v + 1]], uv[idx[self.v + 2]]]
+            }
+            None => [
+                Point2f::new(0., 0.),
+                Point2f::new(1., 0.),
+                Point2f::new(1., 1.),
+            ],
+        }
+    }
+}
+
+fn max_dimension(v: &[f32; 3]) -> usize {
+    if v[0] > v[1] {
+        if v[0] > v[2] {
+            0
+        } else {
+            2
+        }
+    } else if v[1] > v[2] {
+        1
+    } else {
+        2
+    }
+}
+
+impl Shape for Triangle {
+    fn object_bound(&self) -> Bounds3f {
+        let world_to_object = self.mesh.object_to_world.inverse();
+        let (p0, p1, p2) = self.vertices();
+        let b = Bounds3::from((&world_to_object * p0, &world_to_object * p1));
+        Bounds::point_union(&b, &(&world_to_object * p2))
+    }
+
+    fn world_bound(&self) -> Bounds3f {
+        let (p0, p1, p2) = self.vertices();
+        let b = Bounds3::from((Point::min(&p0, &p1), Point::max(&p0, &p1)));
+        Bounds::point_union(&b, &p2)
+    }
+
+    fn intersect(&self, ray: &Ray_) -> Option<(f32, SurfaceInteraction)> {
+        let (p0, p1, p2) = self.vertices();
+
+        // Transform triangle vertices to ray coordinate space
+        let mut p0t = [p0.x - ray.o.x, p0.y - ray.o.y, p0.z - ray.o.z];
+        let mut p1t = [p1.x - ray.o.x, p1.y - ray.o.y, p1.z - ray.o.z];
+        let mut p2t = [p2.x - ray.o.x, p2.y - ray.o.y, p2.z - ray.o.z];
+
+        let kz = max_dimension(&[ray.d.x.abs(), ray.d.y.abs(), ray.d.z.abs()]);
+        let kx = if kz + 1 == 3 { 0 } else { kz + 1 };
+        let ky = if kx + 1 == 3 { 0 } else { kx + 1 };
+        let d = [ray.d[kx], ray.d[ky], ray.d[kz]];
+        p0t = [p0t[kx], p0t[ky], p0t[kz]];
+        p1t = [p1t[kx], p1t[ky], p1t[kz]];
+        p2t = [p2t[kx], p2t[ky], p2t[kz]];
+
+        // Apply shear transformation to translated vertex positions
+        let sx = -d[0] / d[2];
+        let sy = -d[1] / d[2];
+        let sz = 1. / d[2];
+        for pt in [&mut p0t, &mut p1t, &mut p2t].iter_mut() {
+            pt[0] += sx * pt[2];
+            pt[1] += sy * pt[2];
+        }
+
+        // Compute edge function coefficients
+        let mut e0 = p1t[0] * p2t[1] - p1t[1] * p2t[0];
+        let mut e1 = p2t[0] * p0t[1] - p2t[1] * p0t[0];
+        let mut e2 = p0t[0] * p1t[1] - p0t[1] * p1t[0];
+
+        // Fall back to double precision test at triangle edges
+        if e0 == 0. || e1 == 0. || e2 == 0. {
+            let (p0t, p1t, p2t) = (
+                [p0t[0] as f64, p0t[1] as f64],
+                [p1t[0] as f64, p1t[1] as f64],
+                [p2t[0] as f64, p2t[1] as f64],
+            );
+            e0 = (p1t[0] * p2t[1] - p1t[1] * p2t[0]) as f32;
+            e1 = (p2t[0] * p0t[1] - p2t[1] * p0t[0]) as f32;
+            e2 = (p0t[0] * p1t[1] - p0t[1] * p1t[0]) as f32;
+        }
+
+        if (e0 < 0. || e1 < 0. || e2 < 0.) && (e0 > 0. || e1 > 0. || e2 > 0.) {
+            return None;
+        }
+        let det = e0 + e1 + e2;
+        if det == 0. {
+            return None;
+        }
+
+        // Compute scaled hit distance and test against ray t range
+        p0t[2] *= sz;
+        p1t[2] *= sz;
+        p2t[2] *= sz;
+        let t_scaled = e0 * p0t[2] + e1 * p1t[2] + e2 * p2t[2];
+        let t_max = ray.tmax.get();
+        if (det < 0. && (t_scaled >= 0. || t_scaled < t_max * det))
+            || (det > 0. && (t_scaled <= 0. || t_scaled > t_max * det))
+        {
+            return None;
+        }
+
+        let inv_det = 1. / det;
+        let b0 = e0 * inv_det;
+        let b1 = e1 * inv_det;
+        let b2 = e2 * inv_det;
+        let t = t_scaled * inv_det;
+
+        // Ensure that computed triangle t is conservatively greater than zero
+        let max_zt = p0t[2].abs().max(p1t[2].abs()).max(p2t[2].abs());
+        let delta_z = gamma(3) * max_zt;
+        let max_xt = p0t[0].abs().max(p1t[0].abs()).max(p2t[0].abs());
+        let max_yt = p0t[1].abs().max(p1t[1].abs()).max(p2t[1].abs());
+        let delta_x = gamma(5) * (max_xt + max_zt);
+        let delta_y = gamma(5) * (max_yt + max_zt);
+        let delta_e = 2. * (gamma(2) * max_xt * max_yt + delta_y * max_xt + delta_x * max_yt);
+        let max_e = e0.abs().max(e1.abs()).max(e2.abs());
+        let delta_t = 3. * (gamma(3) * max_e * max_zt + delta_e * max_zt + delta_z * max_e)
+            * inv_det.abs();
+        if t <= delta_t {
+            return None;
+        }
+
+        // Compute triangle partial derivatives
+        let uv = self.uvs();
+        let duv02 = uv[0] - uv[2];
+        let duv12 = uv[1] - uv[2];
+        let dp02 = p0 - p2;
+        let dp12 = p1 - p2;
+        let determinant = duv02.x * duv12.y - duv02.y * duv12.x;
+        let degenerate_uv = determinant.abs() < 1e-8;
+        let mut dpdu = Vector3f::zero();
+        let mut dpdv = Vector3f::zero();
+        if !degenerate_uv {
+            let invdet = 1. / determinant;
+            dpdu = (dp02 * duv12.y - dp12 * duv02.y) * invdet;
+            dpdv = (dp12 * duv02.x - dp02 * duv12.x) * invdet;
+        }
+        if degenerate_uv || dpdu.cross(&dpdv).length_squared() == 0. {
+            let ng = (p2 - p0).cross(&(p1 - p0));
+            if ng.length_squared() == 0. {
+                return None;
+            }
+            let (u, v) = Vector::normalize(&ng).coordinate_system();
+            dpdu = u;
+            dpdv = v;
+        }
+
+        // Compute error bounds and interpolated values at the hit point
+        let x_abs_sum = (b0 * p0.x).abs() + (b1 * p1.x).abs() + (b2 * p2.x).abs();
+        let y_abs_sum = (b0 * p0.y).abs() + (b1 * p1.y).abs() + (b2 * p2.y).abs();
+        let z_abs_sum = (b0 * p0.z).abs() + (b1 * p1.z).abs() + (b2 * p2.z).abs();
+        let p_error = Vector3f::new(x_abs_sum, y_abs_sum, z_abs_sum) * gamma(7);
+        let p_hit = p0 * b0 + p1 * b1 + p2 * b2;
+        let uv_hit = uv[0] * b0 + uv[1] * b1 + uv[2] * b2;
+
+        let flip = self.mesh.reverse_orientation ^ self.mesh.transform_swaps_handedness;
+        let mut isect = SurfaceInteraction::new(
+            &p_hit,
+            &p_error,
+            &uv_hit,
+            &-ray.d,
+            &dpdu,
+            &dpdv,
+            &Normal3f::zero(),
+            &Normal3f::zero(),
+            ray.time,
+            false,
+        );
+
+        // Override surface normal with the true geometric normal
+        let mut n = Normal3f::from(Vector::normalize(&dp02.cross(&dp12)));
+        if flip {
+            n = -n;
+        }
+        isect.n = n;
+        isect.shading.n = n;
+
+        if let Some(ref normals) = self.mesh.n {
+            let idx = &self.mesh.vertex_indices;
+            let n0 = normals[idx[self.v]];
+            let n1 = normals[idx[self.v + 1]];
+            let n2 = normals[idx[self.v + 2]];
+
+            // Compute shading normal and tangents for the interpolated normal
+            let ns_sum = n0 * b0 + n1 * b1 + n2 * b2;
+            let ns = if ns_sum.length_squared() > 0. {
+                Vector::normalize(&ns_sum)
+            } else {
+                isect.n
+            };
+            let nsv = Vector3f::from(&ns);
+            let mut ss = Vector::normalize(&isect.shading.dpdu);
+            let mut ts = ss.cross(&nsv);
+            if ts.length_squared() > 0. {
+                ts = Vector::normalize(&ts);
+                ss = ts.cross(&nsv);
+            } else {
+                let (u, v) = nsv.coordinate_system();
+                ss = u;
+                ts = v;
+            }
+
+            // Compute partial derivatives of the shading normal
+            let dn1 = n0 - n2;
+            let dn2 = n1 - n2;
+            let (dndu, dndv) = if degenerate_uv {
+                (Normal3f::zero(), Normal3f::zero())
+            } else {
+                let invdet = 1. / determinant;
+                (
+                    (dn1 * duv12.y - dn2 * duv02.y) * invdet,
+                    (dn2 * duv02.x - dn1 * duv12.x) * invdet,
+                )
+            };
+            isect.set_shading_geometry(&ss, &ts, &dndu, &dndv, true);
+        }
+        Some((t, isect))
+    }
+
+    fn area(&self) -> f32 {
+        let (p0, p1, p2) = self.vertices();
+        (p1 - p0).cross(&(p2 - p0)).norm() * 0.5
+    }
+
+    fn sample_area(&self, u: &Point2f) -> (Interaction, f32) {
+        let b = uniform_sample_triangle(u);
+        (
+            self.interaction_at(&[b.x, b.y, 1. - b.x - b.y]),
+            1. / self.area(),
+        )
+    }
+
+    fn sample(&self, it: &Interaction, u: &Point2f) -> Option<(Interaction, f32)> {
+        if self.spherical_solid_angle(&it.p).is_none() {
+            return sample_by_area(self, it, u);
+        }
+        let (p0, p1, p2) = self.vertices();
+        let (b, pdf) = sample_spherical_triangle(&[p0, p1, p2], &it.p, u)?;
+        Some((self.interaction_at(&b), pdf))
+    }
+
+    fn pdf(&self, it: &Interaction, wi: &Vector3f) -> f32 {
+        match self.spherical_solid_angle(&it.p) {
+            Some(solid_angle) => {
+                if self.intersect_p(&it.spawn_ray(wi)) {
+                    1. / solid_angle
+                } else {
+                    0.
+                }
+            }
+            None => pdf_by_area(self, it, wi),
+        }
+    }
+}
@@ -0,0 +1,21 @@
+use geometry::interaction::SurfaceInteraction;
+
+pub trait Texture<T> {
+    fn evaluate(&self, si: &SurfaceInteraction) -> T;
+}
+
+pub struct ConstantTexture<T> {
+    value: T,
+}
+
+impl<T: Copy> ConstantTexture<T> {
+    pub fn new(value: T) -> ConstantTexture<T> {
+        ConstantTexture { value }
+    }
+}
+
+impl<T: Copy> Texture<T> for ConstantTexture<T> {
+    fn evaluate(&self, _si: &SurfaceInteraction) -> T {
+        self.value
+    }
+}
@@ -30,6 +30,57 @@ pub fn maxf<T: cmp::PartialOrd>(v1: T, v2: T) -> T {
     }
 }
 
+/// Clamps `v` to `[low, high]`, mapping NaN to `low`. `f32::clamp` keeps NaN,
+/// which a ratio that can degenerate to 0 / 0 would then pass on.
+#[allow(clippy::manual_clamp)]
+pub fn clamp_nan_to_low(v: f32, low: f32, high: f32) -> f32 {
+    v.max(low).min(high)
+}
+
+pub const MACHINE_EPSILON: f32 = f32::EPSILON * 0.5;
+
+pub fn gamma(n: i32) -> f32 {
+    (n as f32 * MACHINE_EPSILON) / (1. - n as f32 * MACHINE_EPSILON)
+}
+
+pub fn next_float_up(v: f32) -> f32 {
+    if v.is_infinite() && v > 0. {
+        return v;
+    }
+    let v = if v == -0. { 0. } else { v };
+    let bits = v.to_bits();
+    let bits = if v >= 0. { bits + 1 } else { bits - 1 };
+    f32::from_bits(bits)
+}
+
+pub fn next_float_down(v: f32) -> f32 {
+    if v.is_infinite() && v < 0. {
+        return v;
+    }
+    let v = if v == 0. { -0. } else { v };
+    let bits = v.to_bits();
+    let bits = if v > 0. { bits - 1 } else { bits + 1 };
+    f32::from_bits(bits)
+}
+
+pub fn mix_bits(mut v: u64) -> u64 {
+    v ^= v >> 31;
+    v = v.wrapping_mul(0x7fb5_d329_728e_a185);
+    v ^= v >> 27;
+    v = v.wrapping_mul(0x81da_def4_bc2d_d44d);
+    v ^= v >> 33;
+    v
+}
+
+/// Deterministic pseudo random value in `[0, 1)` derived from the given floats.
+pub fn hash_float(values: &[f32]) -> f32 {
+    let mut h = 0u64;
+    for v in values {
+        h = mix_bits(h ^ v.to_bits() as u64);
+    }
+    (h >> 40) as f32 / (1u64 << 24) as f32
+}
+
 #[test]
 fn mintest() {
     let result = minf(13.3f32, 13.4f32);
@@ -47,6 +98,12 @@ fn maxtest() {
     assert_eq!(result, 25f32);
 }
 #[test]
+fn clamp_maps_nan_to_low() {
+    assert_eq!(clamp_nan_to_low(f32::NAN, -1., 1.), -1.);
+    assert_eq!(clamp_nan_to_low(2., -1., 1.), 1.);
+    assert_eq!(clamp_nan_to_low(0.5, -1., 1.), 0.5);
+}
+#[test]
 #[should_panic]
 fn mintest_panic() {
     let result = minf(25f32, f32::NAN);